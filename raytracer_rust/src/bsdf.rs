@@ -0,0 +1,443 @@
+use std::f32::consts::PI;
+
+use crate::hittable::SurfacePoint;
+use crate::microfacet::{ggx_d, smith_g1, smith_g2, sample_visible_normal, visible_normal_pdf, fresnel_dielectric, fresnel_conductor};
+use crate::vec3::{Vec3, normalize, random_f32, random_cosine_direction, reflect, refract};
+
+/// The shading frame at a hit, for turning directions into ones where z is the normal and back
+pub struct Frame {
+    tangent: Vec3,
+    bitangent: Vec3,
+    normal: Vec3
+}
+impl Frame {
+    pub fn new(surface: &SurfacePoint) -> Frame {
+        Frame {
+            tangent: surface.tangent.clone(),
+            bitangent: surface.bitangent.clone(),
+            normal: surface.normal.clone()
+        }
+    }
+
+    pub fn to_local(&self, v: &Vec3) -> Vec3 {
+        Vec3::new(v.dot(&self.tangent), v.dot(&self.bitangent), v.dot(&self.normal))
+    }
+
+    pub fn to_world(&self, v: &Vec3) -> Vec3 {
+        self.tangent.clone() * v[0] + self.bitangent.clone() * v[1] + self.normal.clone() * v[2]
+    }
+}
+
+/// How a surface that is not a perfect mirror or perfectly smooth glass scatters light, with textures already
+/// looked up. `wo` is the unit vector towards where the light goes (back along the ray) and `wi` the one
+/// towards where it comes from, both in world space.
+#[derive(Debug, Clone)]
+pub enum Bsdf {
+    Lambertian { albedo: Vec3 },
+    // rough metal, eta + ik is its complex index of refraction. `alpha` is the GGX width, roughness squared
+    Conductor { eta: Vec3, k: Vec3, alpha: f32 },
+    // rough glass, `eta` is the index of refraction on the far side of the surface over the one on the near side.
+    // like the smooth dielectric, light going through is not made brighter or darker by the change in eta
+    Dielectric { eta: f32, alpha: f32 },
+    Principled(Layers)
+}
+
+/// The lobes of a principled material at one point, with its textures looked up
+#[derive(Debug, Clone)]
+pub struct Layers {
+    pub base_color: Vec3,
+    pub metallic: f32,
+    // index of refraction of the dielectric part, like `Bsdf::Dielectric`
+    pub eta: f32,
+    pub alpha: f32,
+    pub sheen: Vec3,
+    pub clearcoat: f32,
+    pub clearcoat_alpha: f32,
+    pub transmission: f32
+}
+
+/// A direction picked by `Bsdf::sample`
+pub struct BsdfSample {
+    pub direction: Vec3,
+    // bsdf times cosine over pdf, how much of the light from `direction` makes it towards wo
+    pub weight: Vec3,
+    // probability density per solid angle
+    pub pdf: f32
+}
+
+impl Bsdf {
+    /// The bsdf times the cosine at `wi`, light arriving from `wi` times this is the light leaving towards `wo`
+    pub fn eval(&self, frame: &Frame, wo: &Vec3, wi: &Vec3) -> Vec3 {
+        self.eval_local(&frame.to_local(wo), &frame.to_local(wi))
+    }
+
+    /// Probability density per solid angle that `sample` picks `wi`
+    pub fn pdf(&self, frame: &Frame, wo: &Vec3, wi: &Vec3) -> f32 {
+        self.pdf_local(&frame.to_local(wo), &frame.to_local(wi))
+    }
+
+    /// Picks a direction for light to come from, None if the path ends here
+    pub fn sample(&self, frame: &Frame, wo: &Vec3) -> Option<BsdfSample> {
+        let (wi, weight, pdf) = self.sample_local(&frame.to_local(wo))?;
+        Some(BsdfSample { direction: frame.to_world(&wi), weight, pdf })
+    }
+
+    // the same three in the shading frame
+    fn eval_local(&self, wo: &Vec3, wi: &Vec3) -> Vec3 {
+        match self {
+            Bsdf::Lambertian { albedo } => albedo.clone() * (f32::max(0.0, wi[2]) / PI),
+            Bsdf::Conductor { eta, k, alpha } => {
+                if wo[2] <= 0.0 || wi[2] <= 0.0 {
+                    return Vec3::new(0.0, 0.0, 0.0);
+                }
+                let m = normalize(&(wo.clone() + wi.clone()));
+                fresnel_conductor(wo.dot(&m), eta, k) * (ggx_d(&m, *alpha) * smith_g2(wo, wi, *alpha) / (4.0 * wo[2]))
+            },
+            Bsdf::Dielectric { eta, alpha } => {
+                let value = match dielectric_half_vector(wo, wi, *eta) {
+                    Some((m, true)) => {
+                        let f = fresnel_dielectric(wo.dot(&m), *eta);
+                        f * ggx_d(&m, *alpha) * smith_g2(wo, wi, *alpha) / (4.0 * wo[2])
+                    },
+                    Some((m, false)) => {
+                        let f = fresnel_dielectric(wo.dot(&m), *eta);
+                        let (cos_o, cos_i) = (wo.dot(&m), wi.dot(&m));
+                        let denom = cos_o + eta * cos_i;
+                        (1.0 - f) * ggx_d(&m, *alpha) * smith_g2(wo, wi, *alpha)
+                            * cos_o * eta * eta * f32::abs(cos_i) / (wo[2] * denom * denom)
+                    },
+                    None => 0.0
+                };
+                Vec3::new(value, value, value)
+            },
+            Bsdf::Principled(layers) => layers.eval(wo, wi)
+        }
+    }
+
+    fn pdf_local(&self, wo: &Vec3, wi: &Vec3) -> f32 {
+        match self {
+            Bsdf::Lambertian { .. } => f32::max(0.0, wi[2]) / PI,
+            Bsdf::Conductor { alpha, .. } => reflection_pdf(wo, wi, *alpha),
+            Bsdf::Dielectric { eta, alpha } => match dielectric_half_vector(wo, wi, *eta) {
+                Some((m, true)) => {
+                    let f = fresnel_dielectric(wo.dot(&m), *eta);
+                    f * visible_normal_pdf(wo, &m, *alpha) / (4.0 * wo.dot(&m))
+                },
+                Some((m, false)) => {
+                    let f = fresnel_dielectric(wo.dot(&m), *eta);
+                    let (cos_o, cos_i) = (wo.dot(&m), wi.dot(&m));
+                    let denom = cos_o + eta * cos_i;
+                    (1.0 - f) * visible_normal_pdf(wo, &m, *alpha) * eta * eta * f32::abs(cos_i) / (denom * denom)
+                },
+                None => 0.0
+            },
+            Bsdf::Principled(layers) => layers.pdf(wo, wi)
+        }
+    }
+
+    // direction, weight and pdf
+    fn sample_local(&self, wo_local: &Vec3) -> Option<(Vec3, Vec3, f32)> {
+        match self {
+            Bsdf::Lambertian { albedo } => {
+                // cosine weighted directions cancel out the cosine and 1/pi of the brdf, leaving the albedo
+                let wi = random_cosine_direction(&Vec3::new(0.0, 0.0, 1.0));
+                let pdf = self.pdf_local(wo_local, &wi);
+                Some((wi, albedo.clone(), pdf))
+            },
+            Bsdf::Conductor { eta, k, alpha } => {
+                if wo_local[2] <= 0.0 {
+                    return None;
+                }
+                let m = sample_visible_normal(wo_local, *alpha, random_f32(), random_f32());
+                let wi = reflect(&(wo_local.clone() * -1.0), &m);
+                if wi[2] <= 0.0 {
+                    return None;
+                }
+                let weight = fresnel_conductor(wo_local.dot(&m), eta, k)
+                    * (smith_g2(wo_local, &wi, *alpha) / smith_g1(wo_local, *alpha));
+                Some((wi.clone(), weight, reflection_pdf(wo_local, &wi, *alpha)))
+            },
+            Bsdf::Dielectric { eta, alpha } => {
+                if wo_local[2] <= 0.0 {
+                    return None;
+                }
+                let m = sample_visible_normal(wo_local, *alpha, random_f32(), random_f32());
+                let cos_o = wo_local.dot(&m);
+                if cos_o <= 0.0 {
+                    return None;
+                }
+                // reflect or refract with the chance fresnel gives, which cancels out of the weight
+                let f = fresnel_dielectric(cos_o, *eta);
+                let visible = visible_normal_pdf(wo_local, &m, *alpha);
+                let (wi, pdf) = if random_f32() < f {
+                    let wi = reflect(&(wo_local.clone() * -1.0), &m);
+                    if wi[2] <= 0.0 {
+                        return None;
+                    }
+                    (wi, f * visible / (4.0 * cos_o))
+                } else {
+                    let wi = refract(&(wo_local.clone() * -1.0), &m, 1.0 / eta);
+                    if wi[2] >= 0.0 {
+                        return None;
+                    }
+                    let cos_i = wi.dot(&m);
+                    let denom = cos_o + eta * cos_i;
+                    (wi, (1.0 - f) * visible * eta * eta * f32::abs(cos_i) / (denom * denom))
+                };
+                let g = smith_g2(wo_local, &wi, *alpha) / smith_g1(wo_local, *alpha);
+                Some((wi, Vec3::new(g, g, g), pdf))
+            },
+            Bsdf::Principled(layers) => layers.sample(wo_local)
+        }
+    }
+}
+
+// the clearcoat is a thin varnish, like polyurethane
+const CLEARCOAT_IOR: f32 = 1.5;
+
+// how much of the light coming in from `cos_theta` a white sheen sends back out. fitted to stay just
+// above the integral of (1 - cos_d)^5 cos over the hemisphere, which is 0.088 at grazing angles
+fn sheen_albedo(cos_theta: f32) -> f32 {
+    0.09 * f32::powf(1.0 - cos_theta, 1.8)
+}
+
+impl Layers {
+    // metal and dielectric mixed by `metallic`. the dielectric reflects what fresnel says and the rest goes
+    // into the sheen, which takes what it reflects away from the diffuse and transmission lobes under it.
+    // the clearcoat covers all of it and takes away from below what it reflects itself
+    fn eval(&self, wo: &Vec3, wi: &Vec3) -> Vec3 {
+        if wo[2] <= 0.0 || wi[2] == 0.0 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        let coat_fresnel = self.clearcoat * fresnel_dielectric(wo[2], CLEARCOAT_IOR);
+        let sheen_albedo = sheen_albedo(wo[2]);
+        let under_sheen = Vec3::new(1.0, 1.0, 1.0) - self.sheen.clone() * sheen_albedo;
+        if wi[2] < 0.0 {
+            let transmitted = Bsdf::Dielectric { eta: self.eta, alpha: self.alpha }.eval_local(wo, wi);
+            return self.base_color.clone() * under_sheen
+                * (transmitted[0] * (1.0 - self.metallic) * self.transmission * (1.0 - coat_fresnel));
+        }
+
+        let m = normalize(&(wo.clone() + wi.clone()));
+        let cos_d = wo.dot(&m);
+        let specular = ggx_d(&m, self.alpha) * smith_g2(wo, wi, self.alpha) / (4.0 * wo[2]);
+        let f = fresnel_dielectric(cos_d, self.eta);
+        let refracted = 1.0 - fresnel_dielectric(wo[2], self.eta);
+        let diffuse = self.base_color.clone() * under_sheen * ((1.0 - self.transmission) * refracted * wi[2] / PI);
+        let sheen = self.sheen.clone() * (refracted * f32::powi(1.0 - cos_d, 5) * wi[2]);
+        let dielectric = diffuse + sheen + f * specular;
+        let metal = schlick(&self.base_color, cos_d) * specular;
+        let base = dielectric * (1.0 - self.metallic) + metal * self.metallic;
+
+        let coat = self.clearcoat * fresnel_dielectric(cos_d, CLEARCOAT_IOR)
+            * ggx_d(&m, self.clearcoat_alpha) * smith_g2(wo, wi, self.clearcoat_alpha) / (4.0 * wo[2]);
+        base * (1.0 - coat_fresnel) + coat
+    }
+
+    fn pdf(&self, wo: &Vec3, wi: &Vec3) -> f32 {
+        if wo[2] <= 0.0 || wi[2] == 0.0 {
+            return 0.0;
+        }
+        let [diffuse, specular, transmission, clearcoat] = self.lobe_chances(wo);
+        if wi[2] < 0.0 {
+            return transmission * refraction_pdf(wo, wi, self.eta, self.alpha);
+        }
+        diffuse * wi[2] / PI + specular * reflection_pdf(wo, wi, self.alpha)
+            + clearcoat * reflection_pdf(wo, wi, self.clearcoat_alpha)
+    }
+
+    fn sample(&self, wo: &Vec3) -> Option<(Vec3, Vec3, f32)> {
+        if wo[2] <= 0.0 {
+            return None;
+        }
+        let [diffuse, specular, transmission, _] = self.lobe_chances(wo);
+        // like the conductor, reflections off microfacets that point below the surface are lost
+        let reflection = |alpha: f32| {
+            let wi = reflect(&(wo.clone() * -1.0), &sample_visible_normal(wo, alpha, random_f32(), random_f32()));
+            if wi[2] <= 0.0 { None } else { Some(wi) }
+        };
+        let u = random_f32();
+        let wi = if u < diffuse {
+            random_cosine_direction(&Vec3::new(0.0, 0.0, 1.0))
+        } else if u < diffuse + specular {
+            reflection(self.alpha)?
+        } else if u < diffuse + specular + transmission {
+            let m = sample_visible_normal(wo, self.alpha, random_f32(), random_f32());
+            if fresnel_dielectric(wo.dot(&m), self.eta) >= 1.0 {
+                // total internal reflection, nothing gets through this microfacet
+                return None;
+            }
+            let wi = refract(&(wo.clone() * -1.0), &m, 1.0 / self.eta);
+            if wi[2] >= 0.0 {
+                return None;
+            }
+            wi
+        } else {
+            reflection(self.clearcoat_alpha)?
+        };
+
+        // every lobe could have picked wi, so the weight uses the density of all of them together
+        let pdf = self.pdf(wo, &wi);
+        if pdf <= 0.0 {
+            return None;
+        }
+        Some((wi.clone(), self.eval(wo, &wi) / pdf, pdf))
+    }
+
+    // how likely each of the diffuse, specular, transmission and clearcoat lobes is to be sampled,
+    // roughly by how much light it sends towards wo
+    fn lobe_chances(&self, wo: &Vec3) -> [f32; 4] {
+        let average = |v: &Vec3| (v[0] + v[1] + v[2]) / 3.0;
+        let f = fresnel_dielectric(wo[2], self.eta);
+        let coat = self.clearcoat * fresnel_dielectric(wo[2], CLEARCOAT_IOR);
+        let sheen = average(&self.sheen);
+        let color = average(&self.base_color) * (1.0 - sheen * sheen_albedo(wo[2]));
+        let dielectric = (1.0 - coat) * (1.0 - self.metallic);
+        let chances = [
+            dielectric * (1.0 - f) * ((1.0 - self.transmission) * color + sheen),
+            dielectric * f + (1.0 - coat) * self.metallic * average(&schlick(&self.base_color, wo[2])),
+            dielectric * self.transmission * (1.0 - f) * color,
+            coat
+        ];
+        let total: f32 = chances.iter().sum();
+        if total <= 0.0 {
+            return [0.0; 4];
+        }
+        chances.map(|chance| chance / total)
+    }
+}
+
+// Schlick's approximation of fresnel, from the reflectance `f0` when looking straight at the surface
+fn schlick(f0: &Vec3, cos: f32) -> Vec3 {
+    let t = f32::powi(1.0 - f32::clamp(cos, 0.0, 1.0), 5);
+    f0.clone() * (1.0 - t) + t
+}
+
+// density of reflecting `wo` into `wi` off a microfacet picked with `sample_visible_normal`
+fn reflection_pdf(wo: &Vec3, wi: &Vec3, alpha: f32) -> f32 {
+    if wo[2] <= 0.0 || wi[2] <= 0.0 {
+        return 0.0;
+    }
+    let m = normalize(&(wo.clone() + wi.clone()));
+    visible_normal_pdf(wo, &m, alpha) / (4.0 * wo.dot(&m))
+}
+
+// and of refracting it into `wi`
+fn refraction_pdf(wo: &Vec3, wi: &Vec3, eta: f32, alpha: f32) -> f32 {
+    match dielectric_half_vector(wo, wi, eta) {
+        Some((m, false)) => {
+            let (cos_o, cos_i) = (wo.dot(&m), wi.dot(&m));
+            let denom = cos_o + eta * cos_i;
+            visible_normal_pdf(wo, &m, alpha) * eta * eta * f32::abs(cos_i) / (denom * denom)
+        },
+        _ => 0.0
+    }
+}
+
+// microfacet normal that scatters `wo` into `wi` and whether that is a reflection, None if no microfacet can
+fn dielectric_half_vector(wo: &Vec3, wi: &Vec3, eta: f32) -> Option<(Vec3, bool)> {
+    if wo[2] <= 0.0 || wi[2] == 0.0 {
+        return None;
+    }
+    let reflected = wi[2] > 0.0;
+    let m = if reflected { wo.clone() + wi.clone() } else { (wo.clone() + wi.clone() * eta) * -1.0 };
+    let length = f32::sqrt(m.dot(&m));
+    if length == 0.0 {
+        return None;
+    }
+    let m = if m[2] < 0.0 { m / -length } else { m / length };
+    // the microfacet has to face wo, and wi has to be on the side of it the light ends up on
+    if wo.dot(&m) <= 0.0 || (reflected && wi.dot(&m) <= 0.0) || (!reflected && wi.dot(&m) >= 0.0) {
+        return None;
+    }
+    Some((m, reflected))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec3::{random_in_unit_sphere, seed_rng};
+
+    // a surface that absorbs nothing, lit equally from every direction, must not send out more light than
+    // arrives. the albedo is measured both with the bsdf's own sampling and by adding up `eval` over the
+    // sphere, which only agree if the pdfs match the sampling
+    #[test]
+    fn white_furnace() {
+        seed_rng(5);
+        let frame = Frame {
+            tangent: Vec3::new(1.0, 0.0, 0.0),
+            bitangent: Vec3::new(0.0, 1.0, 0.0),
+            normal: Vec3::new(0.0, 0.0, 1.0)
+        };
+        // eta 0 makes a conductor reflect everything
+        let mirror = |alpha| Bsdf::Conductor { eta: Vec3::new(0.0, 0.0, 0.0), k: Vec3::new(1.0, 1.0, 1.0), alpha };
+        // white, with some metal, transmission, clearcoat and sheen
+        let principled = |metallic, transmission, clearcoat, sheen, alpha| Bsdf::Principled(Layers {
+            base_color: Vec3::new(1.0, 1.0, 1.0),
+            metallic,
+            eta: 1.5,
+            alpha,
+            sheen: Vec3::new(sheen, sheen, sheen),
+            clearcoat,
+            clearcoat_alpha: 0.01,
+            transmission
+        });
+        let bsdfs = [
+            Bsdf::Lambertian { albedo: Vec3::new(1.0, 1.0, 1.0) },
+            mirror(0.3),
+            mirror(0.6),
+            mirror(1.0),
+            Bsdf::Dielectric { eta: 1.5, alpha: 0.3 },
+            Bsdf::Dielectric { eta: 1.5, alpha: 0.8 },
+            Bsdf::Dielectric { eta: 1.0 / 1.5, alpha: 0.5 },
+            principled(0.0, 0.0, 0.0, 0.0, 0.25),
+            principled(1.0, 0.0, 0.0, 0.0, 0.25),
+            principled(0.0, 1.0, 0.0, 0.0, 0.1),
+            principled(0.5, 0.5, 1.0, 0.0, 0.5),
+            // sheen on top of a nearly smooth surface, which reflects the most
+            principled(0.0, 0.0, 0.0, 1.0, 0.02)
+        ];
+
+        const SAMPLES: usize = 100_000;
+        for bsdf in &bsdfs {
+            for cos in [1.0, 0.6, 0.3] {
+                let wo = Vec3::new(f32::sqrt(1.0 - cos * cos), 0.0, cos);
+
+                let mut sampled = 0.0;
+                for _ in 0..SAMPLES {
+                    if let Some(sample) = bsdf.sample(&frame, &wo) {
+                        sampled += sample.weight[0];
+                    }
+                }
+                let sampled = sampled / SAMPLES as f32;
+
+                // half the directions uniformly over the sphere and half the bsdf's way, which keeps the noise
+                // down but only comes out right if `pdf` is the density `sample` really has
+                let mut brute_force = 0.0;
+                for _ in 0..SAMPLES {
+                    let wi = if random_f32() < 0.5 {
+                        normalize(&random_in_unit_sphere())
+                    } else {
+                        match bsdf.sample(&frame, &wo) {
+                            Some(sample) => sample.direction,
+                            None => continue
+                        }
+                    };
+                    let pdf = 0.5 / (4.0 * PI) + 0.5 * bsdf.pdf(&frame, &wo, &wi);
+                    brute_force += bsdf.eval(&frame, &wo, &wi)[0] / pdf;
+                }
+                let brute_force = brute_force / SAMPLES as f32;
+
+                assert!(sampled <= 1.0 + 1e-4, "{:?} at cos {}: sampled albedo {}", bsdf, cos, sampled);
+                assert!(brute_force <= 1.01, "{:?} at cos {}: albedo {}", bsdf, cos, brute_force);
+                assert!(
+                    f32::abs(sampled - brute_force) < 0.02,
+                    "{:?} at cos {}: sampled albedo {} but brute force {}", bsdf, cos, sampled, brute_force
+                );
+                // light that would bounce between microfacets more than once is lost, on the roughest
+                // surfaces that is about half of it, but something has to come back
+                assert!(sampled > 0.3, "{:?} at cos {}: sampled albedo {}", bsdf, cos, sampled);
+            }
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use crate::vec3::Vec3;
+use crate::ray::Ray;
+use crate::hittable::Hit;
+
+// number of centroid bins tried per axis when looking for the cheapest split
+const SAH_BINS: usize = 12;
+// cost of visiting a node relative to intersecting one primitive
+const TRAVERSAL_COST: f32 = 1.0;
+// leaves are allowed to grow this large before a split is forced
+const MAX_LEAF_SIZE: usize = 8;
+
+#[derive(Debug, Clone)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3
+}
+impl Aabb {
+    pub fn new(min: Vec3, max: Vec3) -> Aabb {
+        Aabb { min, max }
+    }
+
+    /// Box that contains nothing, growing it with anything gives that thing back
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vec3::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            max: Vec3::new(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY)
+        }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: Vec3::new(
+                f32::min(self.min[0], other.min[0]),
+                f32::min(self.min[1], other.min[1]),
+                f32::min(self.min[2], other.min[2])
+            ),
+            max: Vec3::new(
+                f32::max(self.max[0], other.max[0]),
+                f32::max(self.max[1], other.max[1]),
+                f32::max(self.max[2], other.max[2])
+            )
+        }
+    }
+
+    pub fn grow(&self, p: &Vec3) -> Aabb {
+        self.union(&Aabb::new(p.clone(), p.clone()))
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        (self.min.clone() + self.max.clone()) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self.max.clone() - self.min.clone();
+        if d[0] < 0.0 || d[1] < 0.0 || d[2] < 0.0 {
+            return 0.0;
+        }
+        2.0 * (d[0]*d[1] + d[1]*d[2] + d[2]*d[0])
+    }
+
+    /// Slab test, returns the distance where the ray enters the box if it does so before `t_max`
+    fn hit(&self, origin: &Vec3, inv_dir: &[f32; 3], t_max: f32) -> Option<f32> {
+        let mut t_enter = 0.0;
+        let mut t_exit = t_max;
+        for axis in 0..3 {
+            let t0 = (self.min[axis] - origin[axis]) * inv_dir[axis];
+            let t1 = (self.max[axis] - origin[axis]) * inv_dir[axis];
+            // f32::min/max drop NaN which shows up for rays parallel to and exactly on a slab
+            t_enter = f32::max(t_enter, f32::min(t0, t1));
+            t_exit = f32::min(t_exit, f32::max(t0, t1));
+        }
+        if t_enter <= t_exit {
+            Some(t_enter)
+        } else {
+            None
+        }
+    }
+}
+
+struct Node {
+    bounds: Aabb,
+    // leaf: first index into `Bvh::indices`, interior: index of the left child (right is next to it)
+    first: usize,
+    // number of primitives in a leaf, 0 for interior nodes
+    count: usize
+}
+
+/// Bounding volume hierarchy over anything that can be put in a box.
+/// It only knows the ids it was built with, testing the actual primitives is left to the caller.
+pub struct Bvh {
+    nodes: Vec<Node>,
+    indices: Vec<usize>
+}
+impl Bvh {
+    /// Builds the tree top down, splitting every node where the surface area heuristic says it is cheapest
+    pub fn build(primitives: &[(usize, Aabb)]) -> Bvh {
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(2 * primitives.len()),
+            indices: (0..primitives.len()).collect()
+        };
+        if primitives.is_empty() {
+            return bvh;
+        }
+
+        let centroids: Vec<Vec3> = primitives.iter().map(|(_, b)| b.centroid()).collect();
+        bvh.nodes.push(Node { bounds: Aabb::empty(), first: 0, count: primitives.len() });
+        bvh.subdivide(0, primitives, &centroids);
+
+        // from here on the indices point to the callers ids instead of into `primitives`
+        for i in bvh.indices.iter_mut() {
+            *i = primitives[*i].0;
+        }
+        bvh
+    }
+
+    fn subdivide(&mut self, node_index: usize, primitives: &[(usize, Aabb)], centroids: &[Vec3]) {
+        let first = self.nodes[node_index].first;
+        let count = self.nodes[node_index].count;
+        let range = first..first + count;
+
+        let mut bounds = Aabb::empty();
+        let mut centroid_bounds = Aabb::empty();
+        for &i in &self.indices[range.clone()] {
+            bounds = bounds.union(&primitives[i].1);
+            centroid_bounds = centroid_bounds.grow(&centroids[i]);
+        }
+        self.nodes[node_index].bounds = bounds.clone();
+
+        if count <= 1 {
+            return;
+        }
+
+        let (axis, split, split_cost) = match self.cheapest_split(&self.indices[range.clone()], primitives, centroids, &centroid_bounds) {
+            Some(split) => split,
+            // all centroids are in the same spot so there is nothing to split on
+            None => return
+        };
+
+        let leaf_cost = count as f32 * bounds.surface_area();
+        let split_cost = TRAVERSAL_COST * bounds.surface_area() + split_cost;
+        if count <= MAX_LEAF_SIZE && split_cost >= leaf_cost {
+            return;
+        }
+
+        let lo = centroid_bounds.min[axis];
+        let extent = centroid_bounds.max[axis] - lo;
+        let slice = &mut self.indices[range];
+        let mut left_count = 0;
+        for i in 0..slice.len() {
+            if bin_of(centroids[slice[i]][axis], lo, extent) <= split {
+                slice.swap(i, left_count);
+                left_count += 1;
+            }
+        }
+        if left_count == 0 || left_count == count {
+            return;
+        }
+
+        let left = self.nodes.len();
+        self.nodes.push(Node { bounds: Aabb::empty(), first, count: left_count });
+        self.nodes.push(Node { bounds: Aabb::empty(), first: first + left_count, count: count - left_count });
+        self.nodes[node_index].first = left;
+        self.nodes[node_index].count = 0;
+
+        self.subdivide(left, primitives, centroids);
+        self.subdivide(left + 1, primitives, centroids);
+    }
+
+    /// Returns (axis, last bin on the left side, cost of the children) of the best binned split
+    fn cheapest_split(&self, indices: &[usize], primitives: &[(usize, Aabb)], centroids: &[Vec3], centroid_bounds: &Aabb) -> Option<(usize, usize, f32)> {
+        let mut best: Option<(usize, usize, f32)> = None;
+
+        for axis in [0, 1, 2] {
+            let lo = centroid_bounds.min[axis];
+            let extent = centroid_bounds.max[axis] - lo;
+            if extent <= 0.0 {
+                continue;
+            }
+
+            let mut bin_bounds: Vec<Aabb> = vec![Aabb::empty(); SAH_BINS];
+            let mut bin_counts = [0usize; SAH_BINS];
+            for &i in indices {
+                let bin = bin_of(centroids[i][axis], lo, extent);
+                bin_counts[bin] += 1;
+                bin_bounds[bin] = bin_bounds[bin].union(&primitives[i].1);
+            }
+
+            // sweep from the right so that right_area[b] covers the bins after b
+            let mut right_area = [0.0; SAH_BINS];
+            let mut right_count = [0usize; SAH_BINS];
+            let mut acc = Aabb::empty();
+            let mut n = 0;
+            for b in (1..SAH_BINS).rev() {
+                acc = acc.union(&bin_bounds[b]);
+                n += bin_counts[b];
+                right_area[b - 1] = acc.surface_area();
+                right_count[b - 1] = n;
+            }
+
+            let mut acc = Aabb::empty();
+            let mut n = 0;
+            for b in 0..SAH_BINS - 1 {
+                acc = acc.union(&bin_bounds[b]);
+                n += bin_counts[b];
+                if n == 0 || right_count[b] == 0 {
+                    continue;
+                }
+                let cost = n as f32 * acc.surface_area() + right_count[b] as f32 * right_area[b];
+                if best.as_ref().is_none_or(|(_, _, c)| cost < *c) {
+                    best = Some((axis, b, cost));
+                }
+            }
+        }
+        best
+    }
+
+    /// Box around everything in the tree
+    pub fn bounds(&self) -> Aabb {
+        match self.nodes.first() {
+            Some(root) => root.bounds.clone(),
+            None => Aabb::empty()
+        }
+    }
+
+    /// Finds the closest primitive along the ray.
+    /// `hit` is called with a primitive id and the closest distance found so far and should
+    /// return the hit on that primitive if it is closer than that.
+    pub fn closest<F>(&self, ray: &Ray, t_max: f32, mut hit: F) -> Option<(usize, Hit)>
+    where F: FnMut(usize, f32) -> Option<Hit> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let origin = ray.get_origin();
+        let inv_dir = inverse(&ray.get_direction());
+
+        let mut closest: Option<(usize, Hit)> = None;
+        let mut t_closest = t_max;
+        let mut stack: Vec<usize> = Vec::with_capacity(64);
+        stack.push(0);
+
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if node.bounds.hit(&origin, &inv_dir, t_closest).is_none() {
+                continue;
+            }
+
+            if node.count > 0 {
+                for &i in &self.indices[node.first..node.first + node.count] {
+                    if let Some(h) = hit(i, t_closest) {
+                        if h.dist < t_closest {
+                            t_closest = h.dist;
+                            closest = Some((i, h));
+                        }
+                    }
+                }
+            } else {
+                let left = node.first;
+                let right = node.first + 1;
+                let t_left = self.nodes[left].bounds.hit(&origin, &inv_dir, t_closest);
+                let t_right = self.nodes[right].bounds.hit(&origin, &inv_dir, t_closest);
+                // push the far child first so the near one is visited first
+                match (t_left, t_right) {
+                    (Some(l), Some(r)) => {
+                        if l <= r {
+                            stack.push(right);
+                            stack.push(left);
+                        } else {
+                            stack.push(left);
+                            stack.push(right);
+                        }
+                    },
+                    (Some(_), None) => stack.push(left),
+                    (None, Some(_)) => stack.push(right),
+                    (None, None) => ()
+                }
+            }
+        }
+        closest
+    }
+
+    /// Returns true as soon as any primitive is hit before `t_max`, used for shadow rays
+    pub fn any<F>(&self, ray: &Ray, t_max: f32, mut hit: F) -> bool
+    where F: FnMut(usize, f32) -> bool {
+        if self.nodes.is_empty() {
+            return false;
+        }
+
+        let origin = ray.get_origin();
+        let inv_dir = inverse(&ray.get_direction());
+
+        let mut stack: Vec<usize> = Vec::with_capacity(64);
+        stack.push(0);
+
+        while let Some(node_index) = stack.pop() {
+            let node = &self.nodes[node_index];
+            if node.bounds.hit(&origin, &inv_dir, t_max).is_none() {
+                continue;
+            }
+
+            if node.count > 0 {
+                for &i in &self.indices[node.first..node.first + node.count] {
+                    if hit(i, t_max) {
+                        return true;
+                    }
+                }
+            } else {
+                stack.push(node.first);
+                stack.push(node.first + 1);
+            }
+        }
+        false
+    }
+}
+
+fn bin_of(value: f32, lo: f32, extent: f32) -> usize {
+    let bin = ((value - lo) / extent * SAH_BINS as f32) as usize;
+    usize::min(bin, SAH_BINS - 1)
+}
+
+fn inverse(dir: &Vec3) -> [f32; 3] {
+    [1.0 / dir[0], 1.0 / dir[1], 1.0 / dir[2]]
+}
@@ -0,0 +1,204 @@
+use std::fmt;
+
+use crate::vec3::{Vec3, length, normalize, random_in_unit_disk};
+use crate::ray::Ray;
+use crate::world::World;
+
+/// Why `Camera::new` could not point a camera
+#[derive(Debug, Clone, PartialEq)]
+pub enum CameraError {
+    // look_from and look_at are the same point, so there is no direction to look in
+    NoViewDirection,
+    // up is zero or points along the view direction, so nothing says which way is up in the image
+    UpAlongView,
+    // the vertical field of view has to be more than 0 and less than 180 degrees
+    FieldOfView(f32),
+    // the image needs at least one pixel each way
+    EmptyImage { width: i32, height: i32 }
+}
+impl fmt::Display for CameraError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CameraError::NoViewDirection => write!(f, "the camera looks at the point it is at, look_from and look_at have to differ"),
+            CameraError::UpAlongView => write!(f, "up can not be zero or point along the direction the camera looks in"),
+            CameraError::FieldOfView(vfov) => write!(f, "the field of view has to be between 0 and 180 degrees, got {}", vfov),
+            CameraError::EmptyImage { width, height } => write!(f, "an image needs at least one pixel, got {}x{}", width, height)
+        }
+    }
+}
+impl std::error::Error for CameraError {}
+
+pub struct Camera {
+    pub width: i32,
+    pub height: i32,
+
+    // what the camera was made with, kept around for saving scenes
+    look_at: Vec3,
+    up: Vec3,
+    vfov: f32,
+
+    origin: Vec3,
+    // w points backwards, u to the right and v up
+    u: Vec3,
+    v: Vec3,
+    w: Vec3,
+    // size of the image plane one unit in front of the camera
+    viewport_width: f32,
+    viewport_height: f32,
+    // 0 is a pinhole camera where everything is sharp
+    lens_radius: f32,
+    // distance to the plane that is in focus
+    focus_dist: f32
+}
+impl Camera {
+    /// Camera at `look_from` looking at `look_at`, `up` decides which way is up in the image.
+    /// `vfov` is the vertical field of view in degrees, the horizontal one follows from width / height.
+    /// Fails if that gives no direction to look in, no way to tell up from down or no pixels
+    pub fn new(look_from: Vec3, look_at: Vec3, up: Vec3, vfov: f32, width: i32, height: i32) -> Result<Camera, CameraError> {
+        if width <= 0 || height <= 0 {
+            return Err(CameraError::EmptyImage { width, height });
+        }
+        if !(vfov > 0.0 && vfov < 180.0) {
+            return Err(CameraError::FieldOfView(vfov));
+        }
+        let view = look_from.clone() - look_at.clone();
+        if view.dot(&view) == 0.0 {
+            return Err(CameraError::NoViewDirection);
+        }
+        let w = normalize(&view);
+        let side = up.cross(&w);
+        if length(side.clone()) <= 1e-6 * length(up.clone()) {
+            return Err(CameraError::UpAlongView);
+        }
+        let u = normalize(&side);
+        let v = w.cross(&u);
+
+        let aspect_ratio = width as f32 / height as f32;
+        let h = f32::tan(vfov.to_radians() / 2.0);
+        let viewport_height = 2.0 * h;
+        let viewport_width = aspect_ratio * viewport_height;
+
+        Ok(Camera {
+            width,
+            height,
+            look_at,
+            up,
+            vfov,
+            origin: look_from,
+            u,
+            v,
+            w,
+            viewport_width,
+            viewport_height,
+            lens_radius: 0.0,
+            focus_dist: 1.0
+        })
+    }
+
+    /// Changes the resolution, the vertical field of view stays the same
+    pub fn set_size(&mut self, width: i32, height: i32) -> Result<(), CameraError> {
+        if width <= 0 || height <= 0 {
+            return Err(CameraError::EmptyImage { width, height });
+        }
+        self.width = width;
+        self.height = height;
+        self.viewport_width = self.aspect_ratio() * self.viewport_height;
+        Ok(())
+    }
+
+    pub fn aspect_ratio(&self) -> f32 {
+        self.width as f32 / self.height as f32
+    }
+
+    pub fn look_from(&self) -> &Vec3 {
+        &self.origin
+    }
+
+    pub fn look_at(&self) -> &Vec3 {
+        &self.look_at
+    }
+
+    pub fn up(&self) -> &Vec3 {
+        &self.up
+    }
+
+    pub fn vfov(&self) -> f32 {
+        self.vfov
+    }
+
+    pub fn aperture_radius(&self) -> f32 {
+        self.lens_radius
+    }
+
+    pub fn focus_dist(&self) -> f32 {
+        self.focus_dist
+    }
+
+    /// Turns the pinhole into a thin lens, only things `focus_dist` away from the camera are sharp
+    /// and the bigger the aperture the blurrier everything else gets
+    pub fn set_lens(&mut self, aperture_radius: f32, focus_dist: f32) {
+        self.lens_radius = aperture_radius;
+        self.focus_dist = focus_dist;
+    }
+
+    /// Focuses on whatever is in the middle of the image, returns the new focus distance
+    /// or `None` (keeping the old one) if there is nothing there
+    pub fn autofocus(&mut self, world: &World) -> Option<f32> {
+        let center = Ray::new(self.origin.clone(), self.w.clone() * -1.0);
+        let dist = world.first_hit_distance(&center)?;
+        self.focus_dist = dist;
+        Some(dist)
+    }
+
+    /// Ray through the point (s, t) of the image, (0, 0) is the lower left corner and (1, 1) the upper right
+    pub fn get_ray(&self, s: f32, t: f32) -> Ray {
+        let target = self.origin.clone() + (
+            self.u.clone() * ((s - 0.5) * self.viewport_width)
+            + self.v.clone() * ((t - 0.5) * self.viewport_height)
+            - self.w.clone()
+        ) * self.focus_dist;
+
+        // rays start from a random point on the lens but all meet again on the focus plane
+        let rd = random_in_unit_disk() * self.lens_radius;
+        let origin = self.origin.clone() + self.u.clone() * rd[0] + self.v.clone() * rd[1];
+
+        Ray::new(
+            origin.clone(),
+            target - origin
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn camera(look_at: Vec3, up: Vec3, vfov: f32) -> Result<Camera, CameraError> {
+        Camera::new(Vec3::new(0.0, 0.0, 10.0), look_at, up, vfov, 64, 48)
+    }
+
+    #[test]
+    fn degenerate_views_are_refused() {
+        let (forward, up) = (Vec3::new(0.0, 0.0, -1.0), Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!(camera(Vec3::new(0.0, 0.0, 10.0), up.clone(), 40.0).err(), Some(CameraError::NoViewDirection));
+        assert_eq!(camera(forward.clone(), Vec3::new(0.0, 0.0, 3.0), 40.0).err(), Some(CameraError::UpAlongView));
+        assert_eq!(camera(forward.clone(), Vec3::new(0.0, 0.0, 0.0), 40.0).err(), Some(CameraError::UpAlongView));
+        assert_eq!(camera(forward.clone(), up.clone(), 0.0).err(), Some(CameraError::FieldOfView(0.0)));
+        assert_eq!(camera(forward.clone(), up.clone(), 180.0).err(), Some(CameraError::FieldOfView(180.0)));
+        let empty = Camera::new(Vec3::new(0.0, 0.0, 10.0), forward.clone(), up.clone(), 40.0, 0, 48);
+        assert_eq!(empty.err(), Some(CameraError::EmptyImage { width: 0, height: 48 }));
+        let empty = Camera::new(Vec3::new(0.0, 0.0, 10.0), forward.clone(), up.clone(), 40.0, 64, -1);
+        assert_eq!(empty.err(), Some(CameraError::EmptyImage { width: 64, height: -1 }));
+
+        // resizing keeps the vertical field of view, and has to leave some pixels too
+        let mut resized = camera(forward.clone(), up.clone(), 40.0).unwrap();
+        assert_eq!(resized.set_size(64, 0), Err(CameraError::EmptyImage { width: 64, height: 0 }));
+        assert_eq!((resized.width, resized.height), (64, 48));
+        resized.set_size(96, 48).unwrap();
+        assert!((resized.aspect_ratio() - 2.0).abs() < 1e-6);
+
+        // and the ray through the middle of a good one goes straight ahead
+        let direction = normalize(&camera(forward, up, 40.0).unwrap().get_ray(0.5, 0.5).get_direction());
+        assert!((direction[2] + 1.0).abs() < 1e-6, "{}", direction);
+    }
+}
@@ -0,0 +1,236 @@
+use std::f32::consts::PI;
+use std::path::{Path, PathBuf};
+
+use crate::framebuffer::ImageError;
+use crate::tonemap::luminance;
+use crate::vec3::{Vec3, random_f32};
+
+/// Light coming from infinitely far away in every direction, from an equirectangular (lat-long) image.
+/// The top row of the image is straight up (+y) and the middle of the image looks down -z.
+pub struct Environment {
+    width: usize,
+    height: usize,
+    // linear colors, row 0 at the top
+    pixels: Vec<Vec3>,
+    // turns the map around the y axis, in degrees
+    rotation: f32,
+    // every pixel is multiplied by this
+    intensity: f32,
+    // where the image was loaded from, for saving scenes
+    path: Option<PathBuf>,
+    // for picking pixels by how much light comes from them, see `sample`.
+    // the cumulative weight of each row, and of each pixel within its row
+    row_cdf: Vec<f32>,
+    pixel_cdfs: Vec<f32>
+}
+
+/// A direction towards the environment picked by `Environment::sample`
+pub struct EnvironmentSample {
+    pub direction: Vec3,
+    pub radiance: Vec3,
+    // probability density per solid angle
+    pub pdf: f32
+}
+
+impl Environment {
+    /// Reads a Radiance .hdr or OpenEXR image, or anything else the image crate can decode
+    pub fn load<P: AsRef<Path>>(path: P, rotation: f32, intensity: f32) -> Result<Environment, ImageError> {
+        let path = path.as_ref();
+        let image = image::open(path).map_err(|err| match err {
+            image::ImageError::IoError(err) => ImageError::Io(err),
+            err => ImageError::Decode(err.to_string())
+        })?.into_rgb32f();
+
+        let pixels = image.pixels().map(|p| Vec3::new(p[0], p[1], p[2])).collect();
+        let mut environment = Environment::new(image.width() as usize, image.height() as usize, pixels, rotation, intensity)?;
+        environment.path = Some(path.to_path_buf());
+        Ok(environment)
+    }
+
+    /// Environment from linear colors, `pixels` goes row by row from the top
+    pub fn new(width: usize, height: usize, pixels: Vec<Vec3>, rotation: f32, intensity: f32) -> Result<Environment, ImageError> {
+        if width == 0 || height == 0 || pixels.len() != width * height {
+            return Err(ImageError::Size { width, height, pixels: pixels.len() });
+        }
+
+        // rows near the poles are squeezed into a smaller solid angle, sin(theta) accounts for that
+        let mut row_cdf = Vec::with_capacity(height);
+        let mut pixel_cdfs = Vec::with_capacity(width * height);
+        let mut total = 0.0;
+        for y in 0..height {
+            let sin_theta = f32::sin((y as f32 + 0.5) / height as f32 * PI);
+            let mut row_total = 0.0;
+            for x in 0..width {
+                row_total += f32::max(0.0, luminance(&pixels[y * width + x])) * sin_theta;
+                pixel_cdfs.push(row_total);
+            }
+            total += row_total;
+            row_cdf.push(total);
+        }
+
+        Ok(Environment { width, height, pixels, rotation, intensity, path: None, row_cdf, pixel_cdfs })
+    }
+
+    pub fn rotation(&self) -> f32 {
+        self.rotation
+    }
+
+    pub fn intensity(&self) -> f32 {
+        self.intensity
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        (self.width, self.height)
+    }
+
+    /// Light arriving from `direction`
+    pub fn radiance(&self, direction: &Vec3) -> Vec3 {
+        let (x, y) = self.pixel(direction);
+        self.pixels[y * self.width + x].clone() * self.intensity
+    }
+
+    /// Picks a direction, brighter parts of the map more often. None if the whole map is black
+    pub fn sample(&self) -> Option<EnvironmentSample> {
+        let total = *self.row_cdf.last()?;
+        if total <= 0.0 {
+            return None;
+        }
+
+        let y = pick(&self.row_cdf, random_f32() * total);
+        let row = &self.pixel_cdfs[y * self.width..(y + 1) * self.width];
+        let x = pick(row, random_f32() * row[self.width - 1]);
+
+        // anywhere inside the pixel
+        let u = (x as f32 + random_f32()) / self.width as f32;
+        let v = (y as f32 + random_f32()) / self.height as f32;
+        let direction = self.direction(u, v);
+        // from the pixel that was picked, mapping the direction back can land next to it on the edges
+        let pdf = self.pixel_pdf(x, y, f32::sin(v * PI));
+        if pdf <= 0.0 || !pdf.is_finite() {
+            return None;
+        }
+        let radiance = self.pixels[y * self.width + x].clone() * self.intensity;
+        Some(EnvironmentSample { radiance, direction, pdf })
+    }
+
+    /// Probability density per solid angle that `sample` picks `direction`
+    pub fn pdf(&self, direction: &Vec3) -> f32 {
+        let sin_theta = f32::sqrt(f32::max(0.0, 1.0 - direction[1] * direction[1]));
+        let (x, y) = self.pixel(direction);
+        self.pixel_pdf(x, y, sin_theta)
+    }
+
+    // density per solid angle inside pixel (x, y), at a point sin_theta away from the poles
+    fn pixel_pdf(&self, x: usize, y: usize, sin_theta: f32) -> f32 {
+        let total = match self.row_cdf.last() {
+            Some(&total) if total > 0.0 => total,
+            _ => return 0.0
+        };
+        if sin_theta <= 0.0 {
+            return 0.0;
+        }
+        let i = y * self.width + x;
+        let weight = if x == 0 { self.pixel_cdfs[i] } else { self.pixel_cdfs[i] - self.pixel_cdfs[i - 1] };
+        // density over the image is weight / total per pixel, each pixel covers 1 / (width * height) of it,
+        // and the whole image covers 2 pi * pi in longitude and latitude
+        weight / total * (self.width * self.height) as f32 / (2.0 * PI * PI * sin_theta)
+    }
+
+    // image coordinates in [0, 1) to a unit direction
+    fn direction(&self, u: f32, v: f32) -> Vec3 {
+        let phi = (u - 0.5) * 2.0 * PI - self.rotation.to_radians();
+        let theta = v * PI;
+        Vec3::new(
+            f32::sin(theta) * f32::sin(phi),
+            f32::cos(theta),
+            -f32::sin(theta) * f32::cos(phi)
+        )
+    }
+
+    // pixel that the unit vector `direction` points at
+    fn pixel(&self, direction: &Vec3) -> (usize, usize) {
+        let phi = f32::atan2(direction[0], -direction[2]) + self.rotation.to_radians();
+        let u = (0.5 + phi / (2.0 * PI)).rem_euclid(1.0);
+        let v = f32::acos(f32::clamp(direction[1], -1.0, 1.0)) / PI;
+        (
+            usize::min((u * self.width as f32) as usize, self.width - 1),
+            usize::min((v * self.height as f32) as usize, self.height - 1)
+        )
+    }
+}
+
+// index of the first entry in the increasing `cdf` above `target`
+fn pick(cdf: &[f32], target: f32) -> usize {
+    usize::min(cdf.partition_point(|&c| c <= target), cdf.len() - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec3::seed_rng;
+
+    // bright in one corner, dim elsewhere and black in one pixel
+    fn environment() -> Environment {
+        let pixels = [0.2, 4.0, 1.0, 0.0, 0.5, 0.5, 2.0, 0.1].iter().map(|&v| Vec3::new(v, v, v)).collect();
+        Environment::new(4, 2, pixels, 30.0, 1.0).unwrap()
+    }
+
+    // the pdf added up over each pixel of the map, in (u, v) steps with the solid angle they cover
+    fn pdf_per_pixel(environment: &Environment) -> Vec<f64> {
+        const STEPS: usize = 200;
+        let (width, height) = environment.size();
+        let mut integrals = vec![0.0; width * height];
+        for y in 0..height * STEPS {
+            let v = (y as f32 + 0.5) / (height * STEPS) as f32;
+            let solid_angle = f32::sin(v * PI) * PI / (height * STEPS) as f32 * 2.0 * PI / (width * STEPS) as f32;
+            for x in 0..width * STEPS {
+                let direction = environment.direction((x as f32 + 0.5) / (width * STEPS) as f32, v);
+                integrals[y / STEPS * width + x / STEPS] += (environment.pdf(&direction) * solid_angle) as f64;
+            }
+        }
+        integrals
+    }
+
+    #[test]
+    fn pdf_is_the_density_of_sample() {
+        seed_rng(3);
+        let environment = environment();
+        let integrals = pdf_per_pixel(&environment);
+        let total: f64 = integrals.iter().sum();
+        assert!((total - 1.0).abs() < 1e-3, "the pdf integrates to {}", total);
+
+        const SAMPLES: usize = 400_000;
+        let mut histogram = [0usize; 8];
+        let mut on_edges = 0;
+        for _ in 0..SAMPLES {
+            let sample = environment.sample().unwrap();
+            let (x, y) = environment.pixel(&sample.direction);
+            histogram[y * 4 + x] += 1;
+            // right on the edge of a pixel the direction can map back to its neighbour, and near the poles
+            // sin theta from the direction is off by a little
+            if (sample.pdf - environment.pdf(&sample.direction)).abs() > 1e-2 * sample.pdf {
+                on_edges += 1;
+            }
+        }
+        assert!(on_edges < SAMPLES / 1000, "{} samples disagree with the pdf", on_edges);
+        for (i, (&count, &integral)) in histogram.iter().zip(&integrals).enumerate() {
+            let fraction = count as f64 / SAMPLES as f64;
+            assert!((fraction - integral).abs() < 0.005, "pixel {}: sampled {} of the time but the pdf says {}", i, fraction, integral);
+        }
+        assert_eq!(histogram[3], 0, "black pixels are never picked");
+    }
+
+    #[test]
+    fn pixels_have_to_fill_the_image() {
+        assert!(Environment::new(0, 2, vec![], 0.0, 1.0).is_err());
+        assert!(Environment::new(2, 2, vec![Vec3::new(1.0, 1.0, 1.0); 3], 0.0, 1.0).is_err());
+        assert!(environment().sample().is_some());
+        let black = Environment::new(2, 1, vec![Vec3::new(0.0, 0.0, 0.0); 2], 0.0, 1.0).unwrap();
+        assert!(black.sample().is_none());
+        assert_eq!(black.pdf(&Vec3::new(0.0, 0.0, -1.0)), 0.0);
+    }
+}
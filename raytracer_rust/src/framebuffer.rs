@@ -0,0 +1,498 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use half::f16;
+
+use crate::vec3::{Vec3, Color};
+use crate::tonemap::ToneMapping;
+
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    UnknownFormat(String),
+    Encode(String),
+    Decode(String),
+    // pixels that do not make up a width x height image
+    Size { width: usize, height: usize, pixels: usize }
+}
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ImageError::Io(err) => write!(f, "{}", err),
+            ImageError::UnknownFormat(ext) => write!(f, "unknown image format '{}', use png, ppm, pfm, exr or hdr", ext),
+            ImageError::Encode(message) => write!(f, "could not encode image: {}", message),
+            ImageError::Decode(message) => write!(f, "could not decode image: {}", message),
+            ImageError::Size { width, height, .. } if *width == 0 || *height == 0 => {
+                write!(f, "an image needs at least one pixel, got {}x{}", width, height)
+            },
+            ImageError::Size { width, height, pixels } => {
+                write!(f, "a {}x{} image needs {} pixels, got {}", width, height, width * height, pixels)
+            }
+        }
+    }
+}
+impl std::error::Error for ImageError {}
+impl From<io::Error> for ImageError {
+    fn from(err: io::Error) -> ImageError {
+        ImageError::Io(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ImageFormat {
+    Png,
+    // binary P6 ppm
+    Ppm,
+    // portable float map, keeps the linear colors as they are
+    Pfm,
+    // OpenEXR, linear colors plus optional depth and normals
+    Exr,
+    // Radiance rgbe, linear colors
+    Hdr
+}
+impl ImageFormat {
+    /// Picks the format from the file extension
+    pub fn from_path(path: &Path) -> Result<ImageFormat, ImageError> {
+        let ext = path.extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or("")
+            .to_lowercase();
+        match ext.as_str() {
+            "png" => Ok(ImageFormat::Png),
+            "ppm" => Ok(ImageFormat::Ppm),
+            "pfm" => Ok(ImageFormat::Pfm),
+            "exr" => Ok(ImageFormat::Exr),
+            "hdr" => Ok(ImageFormat::Hdr),
+            _ => Err(ImageError::UnknownFormat(ext))
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ExrOptions {
+    // 16 bit floats for color and normals, 32 bit if false. Depth is always 32 bit
+    pub half: bool,
+    // adds a Z channel with the distance to what is seen through each pixel
+    pub depth: bool,
+    // adds N.X, N.Y and N.Z channels with the normal of what is seen through each pixel
+    pub normals: bool
+}
+impl Default for ExrOptions {
+    fn default() -> ExrOptions {
+        ExrOptions { half: true, depth: false, normals: false }
+    }
+}
+
+/// Linear colors of a rendered image, row 0 is the top of the image.
+/// Also keeps the depth and normal seen through each pixel for writing to exr.
+pub struct Framebuffer {
+    pub width: usize,
+    pub height: usize,
+    // used when writing 8 bit formats, the float formats are always written linear
+    pub tone_mapping: ToneMapping,
+    pixels: Vec<Vec3>,
+    depth: Vec<f32>,
+    normals: Vec<Vec3>
+}
+impl Framebuffer {
+    pub fn new(width: usize, height: usize) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            tone_mapping: ToneMapping::default(),
+            pixels: vec![Color::black(); width * height],
+            depth: vec![f32::INFINITY; width * height],
+            normals: vec![Color::black(); width * height]
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> &Vec3 {
+        &self.pixels[y * self.width + x]
+    }
+
+    pub fn set(&mut self, x: usize, y: usize, color: Vec3) {
+        self.pixels[y * self.width + x] = color;
+    }
+
+    /// Distance from the camera, infinite where nothing was hit
+    pub fn depth(&self, x: usize, y: usize) -> f32 {
+        self.depth[y * self.width + x]
+    }
+
+    pub fn set_depth(&mut self, x: usize, y: usize, depth: f32) {
+        self.depth[y * self.width + x] = depth;
+    }
+
+    /// Surface normal in world space, zero where nothing was hit
+    pub fn normal(&self, x: usize, y: usize) -> &Vec3 {
+        &self.normals[y * self.width + x]
+    }
+
+    pub fn set_normal(&mut self, x: usize, y: usize, normal: Vec3) {
+        self.normals[y * self.width + x] = normal;
+    }
+
+    /// Tone mapped 8 bit sRGB, row by row from the top
+    pub fn to_rgb8(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.pixels.len() * 3);
+        for color in &self.pixels {
+            bytes.extend_from_slice(&self.tone_mapping.to_srgb8(color));
+        }
+        bytes
+    }
+
+    /// Writes the image to `path` in the format its extension asks for
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), ImageError> {
+        let path = path.as_ref();
+        match ImageFormat::from_path(path)? {
+            ImageFormat::Png => self.write_png(path),
+            ImageFormat::Ppm => self.write_ppm(&mut BufWriter::new(File::create(path)?)),
+            ImageFormat::Pfm => self.write_pfm(&mut BufWriter::new(File::create(path)?)),
+            ImageFormat::Exr => self.write_exr(&mut BufWriter::new(File::create(path)?), &ExrOptions::default()),
+            ImageFormat::Hdr => self.write_hdr(&mut BufWriter::new(File::create(path)?))
+        }
+    }
+
+    /// Like `save` but always writes exr, with the given channels
+    pub fn save_exr<P: AsRef<Path>>(&self, path: P, options: &ExrOptions) -> Result<(), ImageError> {
+        self.write_exr(&mut BufWriter::new(File::create(path)?), options)
+    }
+
+    fn write_png(&self, path: &Path) -> Result<(), ImageError> {
+        image::save_buffer_with_format(
+            path,
+            &self.to_rgb8(),
+            self.width as u32,
+            self.height as u32,
+            image::ExtendedColorType::Rgb8,
+            image::ImageFormat::Png
+        ).map_err(|err| match err {
+            image::ImageError::IoError(err) => ImageError::Io(err),
+            err => ImageError::Encode(err.to_string())
+        })
+    }
+
+    pub fn write_ppm<W: Write>(&self, out: &mut W) -> Result<(), ImageError> {
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        out.write_all(&self.to_rgb8())?;
+        out.flush()?;
+        Ok(())
+    }
+
+    pub fn write_pfm<W: Write>(&self, out: &mut W) -> Result<(), ImageError> {
+        // a negative scale means little endian
+        write!(out, "PF\n{} {}\n-1.0\n", self.width, self.height)?;
+        // pfm rows go from the bottom up
+        for y in (0..self.height).rev() {
+            for x in 0..self.width {
+                let color = self.get(x, y);
+                for c in 0..3 {
+                    out.write_all(&color[c].to_le_bytes())?;
+                }
+            }
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Uncompressed scanline OpenEXR with R, G, B and A channels and optionally depth and normals
+    pub fn write_exr<W: Write>(&self, out: &mut W, options: &ExrOptions) -> Result<(), ImageError> {
+        let color_type = if options.half { ExrPixelType::Half } else { ExrPixelType::Float };
+
+        // readers expect the channels sorted by name, and every line holds each channel in turn
+        let mut channels = vec![
+            ("A", color_type, ExrChannel::Alpha),
+            ("B", color_type, ExrChannel::Color(2)),
+            ("G", color_type, ExrChannel::Color(1))
+        ];
+        if options.normals {
+            channels.push(("N.X", color_type, ExrChannel::Normal(0)));
+            channels.push(("N.Y", color_type, ExrChannel::Normal(1)));
+            channels.push(("N.Z", color_type, ExrChannel::Normal(2)));
+        }
+        channels.push(("R", color_type, ExrChannel::Color(0)));
+        if options.depth {
+            channels.push(("Z", ExrPixelType::Float, ExrChannel::Depth));
+        }
+
+        let mut header = vec![];
+        header.extend_from_slice(&[0x76, 0x2f, 0x31, 0x01]);
+        // version 2, single part scanline file
+        header.extend_from_slice(&2u32.to_le_bytes());
+
+        let mut chlist = vec![];
+        for (name, pixel_type, _) in &channels {
+            chlist.extend_from_slice(name.as_bytes());
+            chlist.push(0);
+            chlist.extend_from_slice(&(*pixel_type as i32).to_le_bytes());
+            // pLinear and three reserved bytes
+            chlist.extend_from_slice(&[0, 0, 0, 0]);
+            // x and y sampling
+            chlist.extend_from_slice(&1i32.to_le_bytes());
+            chlist.extend_from_slice(&1i32.to_le_bytes());
+        }
+        chlist.push(0);
+
+        let mut window = vec![];
+        for v in [0, 0, self.width as i32 - 1, self.height as i32 - 1] {
+            window.extend_from_slice(&v.to_le_bytes());
+        }
+
+        exr_attribute(&mut header, "channels", "chlist", &chlist);
+        // no compression
+        exr_attribute(&mut header, "compression", "compression", &[0]);
+        exr_attribute(&mut header, "dataWindow", "box2i", &window);
+        exr_attribute(&mut header, "displayWindow", "box2i", &window);
+        // increasing y
+        exr_attribute(&mut header, "lineOrder", "lineOrder", &[0]);
+        exr_attribute(&mut header, "pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+        exr_attribute(&mut header, "screenWindowCenter", "v2f", &[0; 8]);
+        exr_attribute(&mut header, "screenWindowWidth", "float", &1.0f32.to_le_bytes());
+        header.push(0);
+
+        let bytes_per_pixel: usize = channels.iter().map(|(_, pixel_type, _)| pixel_type.size()).sum();
+        let line_size = bytes_per_pixel * self.width;
+        // each line is stored as its own chunk, preceded by its y and size
+        let chunk_size = 8 + line_size;
+        let first_chunk = header.len() + 8 * self.height;
+
+        out.write_all(&header)?;
+        for y in 0..self.height {
+            out.write_all(&((first_chunk + y * chunk_size) as u64).to_le_bytes())?;
+        }
+
+        let mut line = Vec::with_capacity(line_size);
+        for y in 0..self.height {
+            line.clear();
+            for (_, pixel_type, channel) in &channels {
+                for x in 0..self.width {
+                    let i = y * self.width + x;
+                    let v = match channel {
+                        ExrChannel::Color(c) => self.pixels[i][*c],
+                        ExrChannel::Alpha => 1.0,
+                        ExrChannel::Normal(c) => self.normals[i][*c],
+                        ExrChannel::Depth => self.depth[i]
+                    };
+                    match pixel_type {
+                        ExrPixelType::Half => line.extend_from_slice(&f16::from_f32(v).to_le_bytes()),
+                        ExrPixelType::Float => line.extend_from_slice(&v.to_le_bytes())
+                    }
+                }
+            }
+            out.write_all(&(y as i32).to_le_bytes())?;
+            out.write_all(&(line.len() as i32).to_le_bytes())?;
+            out.write_all(&line)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Radiance rgbe, written without run length encoding which every reader understands
+    pub fn write_hdr<W: Write>(&self, out: &mut W) -> Result<(), ImageError> {
+        write!(out, "#?RADIANCE\nFORMAT=32-bit_rle_rgbe\n\n-Y {} +X {}\n", self.height, self.width)?;
+        for color in &self.pixels {
+            out.write_all(&to_rgbe(color))?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+}
+
+enum ExrChannel {
+    Color(usize),
+    Alpha,
+    Normal(usize),
+    Depth
+}
+
+#[derive(Debug, Clone, Copy)]
+enum ExrPixelType {
+    Half = 1,
+    Float = 2
+}
+impl ExrPixelType {
+    fn size(&self) -> usize {
+        match self {
+            ExrPixelType::Half => 2,
+            ExrPixelType::Float => 4
+        }
+    }
+}
+
+fn exr_attribute(header: &mut Vec<u8>, name: &str, attribute_type: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(attribute_type.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as i32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+/// Shared exponent encoding, the largest component decides the exponent for all three
+fn to_rgbe(color: &Vec3) -> [u8; 4] {
+    let (r, g, b) = (f32::max(color[0], 0.0), f32::max(color[1], 0.0), f32::max(color[2], 0.0));
+    let v = f32::max(r, f32::max(g, b));
+    if v < 1e-32 {
+        return [0, 0, 0, 0];
+    }
+    // v = m * 2^e with m in [0.5, 1)
+    let e = v.log2().floor() as i32 + 1;
+    let scale = 256.0 / f32::powi(2.0, e);
+    [
+        f32::min(r * scale, 255.0) as u8,
+        f32::min(g * scale, 255.0) as u8,
+        f32::min(b * scale, 255.0) as u8,
+        (e + 128) as u8
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use exr::prelude::{ReadChannels, ReadLayers};
+    use crate::tonemap::Operator;
+
+    // 3 x 2 with a different color, depth and normal in every pixel, some brighter than 1
+    fn framebuffer() -> Framebuffer {
+        let mut framebuffer = Framebuffer::new(3, 2);
+        for y in 0..2 {
+            for x in 0..3 {
+                let i = (y * 3 + x) as f32;
+                framebuffer.set(x, y, Vec3::new(0.1 * i, 0.5 + i, 0.25 * (6.0 - i)));
+                framebuffer.set_depth(x, y, 1.5 + i);
+                framebuffer.set_normal(x, y, Vec3::new(x as f32, y as f32, -1.0));
+            }
+        }
+        framebuffer
+    }
+
+    fn close(a: f32, b: f32, tolerance: f32) -> bool {
+        (a - b).abs() <= tolerance * f32::max(1.0, b.abs())
+    }
+
+    #[test]
+    fn pfm_starts_with_the_bottom_row() {
+        let framebuffer = framebuffer();
+        let mut bytes = vec![];
+        framebuffer.write_pfm(&mut bytes).unwrap();
+
+        let header = b"PF\n3 2\n-1.0\n";
+        assert_eq!(&bytes[..header.len()], header);
+        let floats: Vec<f32> = bytes[header.len()..]
+            .chunks(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        assert_eq!(floats.len(), 3 * 2 * 3);
+        for (i, value) in floats.iter().enumerate() {
+            let (x, y, c) = ((i / 3) % 3, 1 - i / 9, i % 3);
+            assert_eq!(*value, framebuffer.get(x, y)[c], "pixel ({}, {}) channel {}", x, y, c);
+        }
+    }
+
+    #[test]
+    fn exr_reads_back() {
+        let framebuffer = framebuffer();
+        for half in [true, false] {
+            for (depth, normals) in [(false, false), (true, false), (false, true), (true, true)] {
+                let options = ExrOptions { half, depth, normals };
+                let mut bytes = vec![];
+                framebuffer.write_exr(&mut bytes, &options).unwrap();
+                let tolerance = if half { 1e-3 } else { 0.0 };
+
+                // colors through the image crate
+                let image = image::load_from_memory_with_format(&bytes, image::ImageFormat::OpenExr)
+                    .unwrap_or_else(|err| panic!("{:?}: {}", options, err))
+                    .to_rgba32f();
+                assert_eq!(image.dimensions(), (3, 2));
+                for (x, y, pixel) in image.enumerate_pixels() {
+                    for c in 0..3 {
+                        let expected = framebuffer.get(x as usize, y as usize)[c];
+                        assert!(close(pixel[c], expected, tolerance), "{:?} ({}, {}): {} != {}", options, x, y, pixel[c], expected);
+                    }
+                    assert_eq!(pixel[3], 1.0);
+                }
+
+                // and the extra channels straight from exr
+                let image = exr::prelude::read()
+                    .no_deep_data()
+                    .largest_resolution_level()
+                    .all_channels()
+                    .all_layers()
+                    .all_attributes()
+                    .from_buffered(Cursor::new(&bytes))
+                    .unwrap();
+                let channel = |name: &str| image.layer_data[0].channel_data.list.iter()
+                    .find(|channel| channel.name.to_string() == name)
+                    .map(|channel| channel.sample_data.values_as_f32().collect::<Vec<f32>>());
+                assert_eq!(channel("Z").is_some(), depth);
+                assert_eq!(channel("N.X").is_some(), normals);
+                for y in 0..2 {
+                    for x in 0..3 {
+                        let i = y * 3 + x;
+                        if let Some(z) = channel("Z") {
+                            assert_eq!(z[i], framebuffer.depth(x, y));
+                        }
+                        for (c, name) in ["N.X", "N.Y", "N.Z"].iter().enumerate() {
+                            if let Some(n) = channel(name) {
+                                assert_eq!(n[i], framebuffer.normal(x, y)[c], "{} at ({}, {})", name, x, y);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hdr_reads_back() {
+        let framebuffer = framebuffer();
+        let mut bytes = vec![];
+        framebuffer.write_hdr(&mut bytes).unwrap();
+        let image = image::load_from_memory_with_format(&bytes, image::ImageFormat::Hdr).unwrap().to_rgb32f();
+        assert_eq!(image.dimensions(), (3, 2));
+        for (x, y, pixel) in image.enumerate_pixels() {
+            let expected = framebuffer.get(x as usize, y as usize);
+            // the shared exponent leaves 8 bits for the brightest component and less for the others
+            let brightest = f32::max(expected[0], f32::max(expected[1], expected[2]));
+            for c in 0..3 {
+                assert!((pixel[c] - expected[c]).abs() <= brightest / 128.0, "({}, {}): {} != {}", x, y, pixel[c], expected[c]);
+            }
+        }
+    }
+
+    #[test]
+    fn ppm_is_tone_mapped_bytes_from_the_top_row() {
+        let framebuffer = framebuffer();
+        let mut bytes = vec![];
+        framebuffer.write_ppm(&mut bytes).unwrap();
+
+        let header = b"P6\n3 2\n255\n";
+        assert_eq!(&bytes[..header.len()], header);
+        let pixels = &bytes[header.len()..];
+        assert_eq!(pixels.len(), 3 * 2 * 3);
+        // the first pixel is (0, 0.5, 1.5), clamped and encoded as sRGB
+        assert_eq!(&pixels[..3], &[0, 188, 255]);
+        for (i, value) in pixels.iter().enumerate() {
+            let (x, y, c) = ((i / 3) % 3, i / 9, i % 3);
+            assert_eq!(*value, framebuffer.tone_mapping.to_srgb8(framebuffer.get(x, y))[c], "pixel ({}, {}) channel {}", x, y, c);
+        }
+    }
+
+    #[test]
+    fn png_reads_back() {
+        let mut framebuffer = framebuffer();
+        framebuffer.tone_mapping = ToneMapping::new(0.5, Operator::Aces);
+        let path = std::env::temp_dir().join(format!("raytracer_framebuffer_{}.png", std::process::id()));
+        framebuffer.save(&path).unwrap();
+        let image = image::open(&path).unwrap().to_rgb8();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(image.dimensions(), (3, 2));
+        // with the tone mapping of the framebuffer, which keeps 1.5 below white
+        assert!(image.get_pixel(0, 0)[2] < 255);
+        assert_eq!(image.into_raw(), framebuffer.to_rgb8());
+    }
+}
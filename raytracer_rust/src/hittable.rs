@@ -1,6 +1,20 @@
 
 
-use crate::{Vec3, length, Ray, vec3::normalize};
+use std::fmt;
+
+use crate::{Vec3, Ray, vec3::{normalize, length, orthonormal_basis, random_f32, random_in_cone, random_in_unit_sphere}};
+use crate::texture::{Bump, ImageTexture, Texture};
+use crate::bsdf::{Bsdf, Layers};
+use crate::bvh::{Aabb, Bvh};
+
+// rays closer than this to a triangle are not counted as hitting it
+const TRIANGLE_EPSILON: f32 = 1e-6;
+// conductors and dielectrics smoother than this are perfect mirrors and glass
+const MIN_ROUGHNESS: f32 = 0.01;
+// principled materials are never perfectly smooth, and see through ones always bend light a little, rough
+// refraction breaks down when the index of refraction gets to 1
+const MIN_PRINCIPLED_ROUGHNESS: f32 = 0.02;
+const MIN_PRINCIPLED_IOR: f32 = 1.01;
 
 pub struct Hittable {
     pub shape: Shape,
@@ -10,28 +24,25 @@ impl Hittable {
     pub fn new(shape: Shape, material: Material) -> Hittable {
         Hittable { shape, material }
     }
-    pub fn shortest_dist(&self, p: &Vec3) -> f32 {
+    /// Axis aligned box enclosing the shape, or `None` for unbounded shapes like planes
+    pub fn bounding_box(&self) -> Option<Aabb> {
         match &self.shape {
-            
-            Shape::Plane(plane) => {
-                let a = plane.a;
-                let b = plane.b;
-                let c = plane.c;
-                let d = plane.d;
-
-                // plane eq: ax + by + cz = d
-                // obs I had to set a minus befor d below
-                let dist = f32::abs(a*p[0] + b*p[1] + c*p[2] - d) / f32::sqrt(a*a + b*b + c*c);
-                dist
-            },
+            Shape::Plane(_) => None,
             Shape::Sphere(sphere) => {
-                let dist = length(p.clone() - sphere.c.clone()) - sphere.r.clone();
-                dist
-            }
+                Some(Aabb::new(
+                    sphere.c.clone() - sphere.r,
+                    sphere.c.clone() + sphere.r
+                ))
+            },
+            Shape::Triangle(triangle) => Some(triangle_bounds(
+                &triangle.vertices[0], &triangle.vertices[1], &triangle.vertices[2]
+            )),
+            Shape::Mesh(mesh) => Some(mesh.bvh.bounds())
         }
     }
 
-    pub fn get_normal(&self, p: &Vec3) -> Vec3 {
+    /// Geometric normal at `p`, `hit` is needed to know which triangle of a mesh `p` is on
+    pub fn get_normal(&self, p: &Vec3, hit: &Hit) -> Vec3 {
         match &self.shape {
             Shape::Plane(plane) => {
                 normalize(
@@ -42,11 +53,179 @@ impl Hittable {
                 normalize(
                     &(p.clone() - sphere.c.clone())
                 )
+            },
+            Shape::Triangle(triangle) => {
+                face_normal(&triangle.vertices[0], &triangle.vertices[1], &triangle.vertices[2])
+            },
+            Shape::Mesh(mesh) => {
+                let [a, b, c] = mesh.indices[hit.primitive];
+                face_normal(&mesh.positions[a], &mesh.positions[b], &mesh.positions[c])
+            }
+        }
+    }
+
+    /// Everything about the surface where `ray` hit, only worth computing for the closest hit
+    pub fn surface(&self, ray: &Ray, hit: &Hit) -> SurfacePoint {
+        let point = ray.scale(hit.dist);
+        let geometric_normal = self.get_normal(&point, hit);
+        // normals always point against the ray, front_face remembers which side was hit
+        let front_face = ray.get_direction().dot(&geometric_normal) < 0.0;
+        // weights of the three triangle corners
+        let weights = [1.0 - hit.u - hit.v, hit.u, hit.v];
+
+        let (normal, uv, (dpdu, dpdv)) = match &self.shape {
+            Shape::Sphere(sphere) => {
+                // longitude and latitude, u goes around the y axis and v from the bottom to the top
+                let d = (point.clone() - sphere.c.clone()) / sphere.r;
+                let u = (f32::atan2(-d[2], d[0]) + std::f32::consts::PI) / (2.0 * std::f32::consts::PI);
+                let v = f32::acos(f32::clamp(-d[1], -1.0, 1.0)) / std::f32::consts::PI;
+                // distance from the y axis, the poles have no direction for u
+                let rho = f32::sqrt(d[0] * d[0] + d[2] * d[2]);
+                let derivatives = if rho > 1e-6 {
+                    (
+                        Vec3::new(d[2], 0.0, -d[0]) * (2.0 * std::f32::consts::PI * sphere.r),
+                        Vec3::new(-d[1] * d[0] / rho, rho, -d[1] * d[2] / rho) * (std::f32::consts::PI * sphere.r)
+                    )
+                } else {
+                    orthonormal_basis(&geometric_normal)
+                };
+                (geometric_normal.clone(), (u, v), derivatives)
+            },
+            Shape::Plane(_) => {
+                // planes go on forever, so uvs are world units along two directions in the plane
+                let (t, b) = orthonormal_basis(&geometric_normal);
+                let uv = (point.dot(&t), point.dot(&b));
+                (geometric_normal.clone(), uv, (t, b))
+            },
+            Shape::Triangle(triangle) => {
+                let normal = match &triangle.normals {
+                    Some(normals) => interpolate(normals, &weights),
+                    None => geometric_normal.clone()
+                };
+                // without uvs the barycentric coordinates are used, as if the corners had uvs (0, 0), (1, 0) and (0, 1)
+                let uvs = triangle.uvs.unwrap_or([(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)]);
+                let [a, b, c] = &triangle.vertices;
+                (normal, interpolate_uv(&uvs, &weights), uv_derivatives([a, b, c], &uvs, &geometric_normal))
+            },
+            Shape::Mesh(mesh) => {
+                let [a, b, c] = mesh.indices[hit.primitive];
+                let normal = if mesh.normals.is_empty() {
+                    geometric_normal.clone()
+                } else {
+                    interpolate(&[mesh.normals[a].clone(), mesh.normals[b].clone(), mesh.normals[c].clone()], &weights)
+                };
+                let uvs = if mesh.uvs.is_empty() {
+                    [(0.0, 0.0), (1.0, 0.0), (0.0, 1.0)]
+                } else {
+                    [mesh.uvs[a], mesh.uvs[b], mesh.uvs[c]]
+                };
+                let vertices = [&mesh.positions[a], &mesh.positions[b], &mesh.positions[c]];
+                (normal, interpolate_uv(&uvs, &weights), uv_derivatives(vertices, &uvs, &geometric_normal))
+            }
+        };
+
+        let (normal, geometric_normal) = if front_face {
+            (normal, geometric_normal)
+        } else {
+            (normal * -1.0, geometric_normal * -1.0)
+        };
+        let (tangent, bitangent) = shading_frame(&normal, &dpdu, &dpdv);
+        let local_point = point.clone() - self.origin();
+
+        SurfacePoint { point, local_point, normal, geometric_normal, tangent, bitangent, dpdu, dpdv, uv, front_face }
+    }
+
+    // where object space starts, the middle of bounded shapes and the point closest to the world origin on planes
+    fn origin(&self) -> Vec3 {
+        match &self.shape {
+            Shape::Plane(plane) => {
+                let normal = Vec3::new(plane.a, plane.b, plane.c);
+                normal.clone() * (plane.d / normal.dot(&normal))
+            },
+            Shape::Sphere(sphere) => sphere.c.clone(),
+            Shape::Triangle(triangle) => {
+                (triangle.vertices[0].clone() + triangle.vertices[1].clone() + triangle.vertices[2].clone()) / 3.0
+            },
+            Shape::Mesh(mesh) => mesh.bvh.bounds().centroid()
+        }
+    }
+
+    /// Random point on the shape for lighting the point `from`, with its probability density per solid angle
+    /// as seen from `from`. Spheres are sampled over the cone they cover and everything else uniformly
+    /// over its area. None for planes, which go on forever, and when `from` only sees the shape edge on.
+    pub fn sample_from(&self, from: &Vec3) -> Option<ShapeSample> {
+        match &self.shape {
+            Shape::Plane(_) => None,
+            Shape::Sphere(sphere) => {
+                let to_center = sphere.c.clone() - from.clone();
+                let dist_squared = to_center.dot(&to_center);
+                let r_squared = sphere.r * sphere.r;
+                if dist_squared <= r_squared {
+                    // inside the sphere all of it is visible
+                    let normal = normalize(&random_in_unit_sphere());
+                    let point = sphere.c.clone() + normal.clone() * sphere.r;
+                    let area = 4.0 * std::f32::consts::PI * r_squared;
+                    return area_to_solid_angle(from, point, normal, 1.0 / area);
+                }
+
+                let cos_max = f32::sqrt(1.0 - r_squared / dist_squared);
+                let direction = random_in_cone(&(to_center.clone() / f32::sqrt(dist_squared)), cos_max);
+                // the near intersection with the sphere, or the closest point for directions grazing its edge
+                let along = direction.dot(&to_center);
+                let t = along - f32::sqrt(f32::max(0.0, r_squared - (dist_squared - along * along)));
+                let point = from.clone() + direction * t;
+                let normal = normalize(&(point.clone() - sphere.c.clone()));
+                let pdf = 1.0 / (2.0 * std::f32::consts::PI * (1.0 - cos_max));
+                Some(ShapeSample { point, normal, pdf })
+            },
+            Shape::Triangle(triangle) => {
+                let [a, b, c] = &triangle.vertices;
+                let point = random_in_triangle(a, b, c);
+                area_to_solid_angle(from, point, face_normal(a, b, c), 1.0 / triangle_area(a, b, c))
+            },
+            Shape::Mesh(mesh) => {
+                // bigger triangles are picked more often so every bit of area is as likely
+                let total_area = *mesh.cumulative_areas.last()?;
+                let target = random_f32() * total_area;
+                let i = usize::min(
+                    mesh.cumulative_areas.partition_point(|&area| area <= target),
+                    mesh.indices.len() - 1
+                );
+                let [a, b, c] = mesh.indices[i];
+                let (a, b, c) = (&mesh.positions[a], &mesh.positions[b], &mesh.positions[c]);
+                area_to_solid_angle(from, random_in_triangle(a, b, c), face_normal(a, b, c), 1.0 / total_area)
             }
         }
     }
 
-    pub fn is_hit(&self, ray: &Ray) -> Result<f32, ()> {
+    /// Probability density per solid angle that `sample_from(from)` picks `point`,
+    /// which has to be on the shape with geometric normal `normal`
+    pub fn pdf_from(&self, from: &Vec3, point: &Vec3, normal: &Vec3) -> f32 {
+        let pdf_area = match &self.shape {
+            Shape::Plane(_) => return 0.0,
+            Shape::Sphere(sphere) => {
+                let to_center = sphere.c.clone() - from.clone();
+                let dist_squared = to_center.dot(&to_center);
+                let r_squared = sphere.r * sphere.r;
+                if dist_squared > r_squared {
+                    let cos_max = f32::sqrt(1.0 - r_squared / dist_squared);
+                    return 1.0 / (2.0 * std::f32::consts::PI * (1.0 - cos_max));
+                }
+                1.0 / (4.0 * std::f32::consts::PI * r_squared)
+            },
+            Shape::Triangle(triangle) => {
+                let [a, b, c] = &triangle.vertices;
+                1.0 / triangle_area(a, b, c)
+            },
+            Shape::Mesh(mesh) => match mesh.cumulative_areas.last() {
+                Some(total_area) => 1.0 / total_area,
+                None => return 0.0
+            }
+        };
+        area_to_solid_angle(from, point.clone(), normal.clone(), pdf_area).map_or(0.0, |sample| sample.pdf)
+    }
+
+    pub fn is_hit(&self, ray: &Ray) -> Option<Hit> {
         match &self.shape {
             Shape::Plane(plane) => {
 
@@ -54,24 +233,24 @@ impl Hittable {
                 let dir = ray.get_direction();
                 let (a, b, c) = (origin[0], origin[1], origin[2]);
                 let (d, e, f) = (dir[0], dir[1], dir[2]);
-                let (A, B, C, D) = (plane.a, plane.b, plane.c, plane.d);
+                let (pa, pb, pc, pd) = (plane.a, plane.b, plane.c, plane.d);
 
-                let dir_dot_norm = A*d + B*e + C*f;
+                let dir_dot_norm = pa*d + pb*e + pc*f;
 
                 if f32::abs(dir_dot_norm) < 0.001 {
                     // line is parallel with plane
-                    return Err(());
+                    return None;
                 }
 
-                let t = (D - A*a - B*b - C*c) / dir_dot_norm;
+                let t = (pd - pa*a - pb*b - pc*c) / dir_dot_norm;
 
                 if t < 0.0 {
                     // plane intersection is in opposite direction from point
-                    return Err(());
+                    return None;
                 }
 
                 // distance is ||dir||*t = 1*t = t
-                return Ok(t);
+                Some(Hit::at(t))
             },
             Shape::Sphere(sphere) => {
                 let oc = ray.get_origin() - sphere.c.clone();
@@ -80,36 +259,91 @@ impl Hittable {
                 let c = oc.dot(&oc) - sphere.r * sphere.r;
                 let discriminant = b*b - 4.0*a*c;
                 if discriminant < 0.0 {
-                    return Err(());
+                    None
                 } else {
-                    let dist = (-b - f32::sqrt(discriminant)) / (2.0 * a);
-                    
+                    let mut dist = (-b - f32::sqrt(discriminant)) / (2.0 * a);
+
+                    // the ray starts inside the sphere, use the far side instead
+                    if dist < 0.0 {
+                        dist = (-b + f32::sqrt(discriminant)) / (2.0 * a);
+                    }
+
                     // check so that we look along the direction of ray and not opposite!
                     if dist < 0.0 {
-                        return Err(());
+                        return None;
                     }
-                    
-                    return Ok(
-                        dist
-                    );
+
+                    Some(
+                        Hit::at(dist)
+                    )
                 }
+            },
+            Shape::Triangle(triangle) => {
+                let [a, b, c] = &triangle.vertices;
+                intersect_triangle(ray, a, b, c)
+            },
+            Shape::Mesh(mesh) => {
+                mesh.bvh.closest(ray, f32::INFINITY, |i, t_max| {
+                    let [a, b, c] = mesh.indices[i];
+                    intersect_triangle(ray, &mesh.positions[a], &mesh.positions[b], &mesh.positions[c])
+                        .filter(|hit| hit.dist < t_max)
+                        .map(|hit| Hit { primitive: i, ..hit })
+                }).map(|(_, hit)| hit)
             }
         }
     }
+}
 
-    pub fn distance(&self, p: &Vec3) -> f32 {
-        match &self.shape {
-            Shape::Plane(plane) => panic!(),
-            Shape::Sphere(sphere) => {
-
-            }
-        }
-        panic!()
+/// Where along a ray a hittable was hit, `Hittable::surface` turns it into the rest of the surface data
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hit {
+    pub dist: f32,
+    // which triangle of a mesh was hit, 0 for all other shapes
+    pub primitive: usize,
+    // barycentric coordinates on triangles, the weights of the second and third corner
+    pub u: f32,
+    pub v: f32
+}
+impl Hit {
+    pub fn at(dist: f32) -> Hit {
+        Hit { dist, primitive: 0, u: 0.0, v: 0.0 }
     }
 }
+
+#[derive(Clone)]
+pub struct SurfacePoint {
+    pub point: Vec3,
+    // the point relative to the shape, for solid textures that should move along with it
+    pub local_point: Vec3,
+    // shading normal, interpolated from the vertex normals on triangles that have them and tilted by bump maps
+    pub normal: Vec3,
+    // normal of the actual surface, facing the same side as `normal`
+    pub geometric_normal: Vec3,
+    // unit vectors along u and v at right angles to `normal`, the shading frame normal maps are given in
+    pub tangent: Vec3,
+    pub bitangent: Vec3,
+    // how far the point moves per unit of u and v
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
+    pub uv: (f32, f32),
+    // false if the ray hit the inside of a sphere or the back of a plane or triangle
+    pub front_face: bool
+}
+
+/// A point on a light, see `Hittable::sample_from`
+pub struct ShapeSample {
+    pub point: Vec3,
+    // geometric normal, facing either way
+    pub normal: Vec3,
+    // probability density per solid angle
+    pub pdf: f32
+}
+
 pub enum Shape {
     Sphere(Sphere),
-    Plane(Plane)
+    Plane(Plane),
+    Triangle(Triangle),
+    Mesh(Mesh)
 }
 impl Shape {
     pub fn plane(a: f32, b: f32, c: f32, d: f32) -> Shape {
@@ -118,6 +352,25 @@ impl Shape {
     pub fn sphere(c: Vec3, r: f32) -> Shape {
         Shape::Sphere(Sphere{ c, r })
     }
+    /// Flat triangle, the front side is the one where a, b, c go counter clockwise
+    pub fn triangle(a: Vec3, b: Vec3, c: Vec3) -> Shape {
+        Shape::Triangle(Triangle { vertices: [a, b, c], normals: None, uvs: None })
+    }
+    /// Parallelogram with one corner at `corner` and sides `u` and `v`, made of two triangles.
+    /// The front side is the one u × v points to, uvs go from (0, 0) at `corner` to (1, 1) at the opposite corner.
+    pub fn quad(corner: Vec3, u: Vec3, v: Vec3) -> Shape {
+        let positions = vec![
+            corner.clone(),
+            corner.clone() + u.clone(),
+            corner.clone() + u.clone() + v.clone(),
+            corner + v
+        ];
+        let uvs = vec![(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)];
+        Shape::mesh(positions, vec![], uvs, vec![[0, 1, 2], [0, 2, 3]]).expect("a quad is always a valid mesh")
+    }
+    pub fn mesh(positions: Vec<Vec3>, normals: Vec<Vec3>, uvs: Vec<(f32, f32)>, indices: Vec<[usize; 3]>) -> Result<Shape, MeshError> {
+        Ok(Shape::Mesh(Mesh::new(positions, normals, uvs, indices)?))
+    }
 }
 pub struct Sphere {
     pub c: Vec3, pub r: f32
@@ -125,8 +378,545 @@ pub struct Sphere {
 pub struct Plane {
     pub a: f32, pub b: f32, pub c: f32, pub d: f32
 }
+pub struct Triangle {
+    pub vertices: [Vec3; 3],
+    pub normals: Option<[Vec3; 3]>,
+    pub uvs: Option<[(f32, f32); 3]>
+}
+
+/// Why `Mesh::new` refused to build a mesh
+#[derive(Debug, Clone, PartialEq)]
+pub enum MeshError {
+    // normals and uvs are either left out or given for every position
+    NormalCount { normals: usize, positions: usize },
+    UvCount { uvs: usize, positions: usize },
+    IndexOutOfBounds { index: usize, positions: usize }
+}
+impl fmt::Display for MeshError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MeshError::NormalCount { normals, positions } => write!(f, "{} normals for {} positions, a mesh needs one per position or none at all", normals, positions),
+            MeshError::UvCount { uvs, positions } => write!(f, "{} uvs for {} positions, a mesh needs one per position or none at all", uvs, positions),
+            MeshError::IndexOutOfBounds { index, positions } => write!(f, "index {} out of range, there are {} positions", index, positions)
+        }
+    }
+}
+impl std::error::Error for MeshError {}
+
+/// Many triangles sharing one vertex buffer, with a bvh of its own over the triangles
+pub struct Mesh {
+    pub positions: Vec<Vec3>,
+    // either empty or one per position
+    pub normals: Vec<Vec3>,
+    // either empty or one per position
+    pub uvs: Vec<(f32, f32)>,
+    // three indices into the vertex buffers per triangle
+    pub indices: Vec<[usize; 3]>,
+    bvh: Bvh,
+    // area of the triangles up to and including each one, for picking triangles by area
+    cumulative_areas: Vec<f32>
+}
+impl Mesh {
+    pub fn new(positions: Vec<Vec3>, normals: Vec<Vec3>, uvs: Vec<(f32, f32)>, indices: Vec<[usize; 3]>) -> Result<Mesh, MeshError> {
+        if !normals.is_empty() && normals.len() != positions.len() {
+            return Err(MeshError::NormalCount { normals: normals.len(), positions: positions.len() });
+        }
+        if !uvs.is_empty() && uvs.len() != positions.len() {
+            return Err(MeshError::UvCount { uvs: uvs.len(), positions: positions.len() });
+        }
+        if let Some(&index) = indices.iter().flatten().find(|&&i| i >= positions.len()) {
+            return Err(MeshError::IndexOutOfBounds { index, positions: positions.len() });
+        }
+
+        let bounds: Vec<(usize, Aabb)> = indices.iter()
+            .enumerate()
+            .map(|(i, [a, b, c])| (i, triangle_bounds(&positions[*a], &positions[*b], &positions[*c])))
+            .collect();
+        let bvh = Bvh::build(&bounds);
+
+        let mut total_area = 0.0;
+        let cumulative_areas = indices.iter()
+            .map(|[a, b, c]| {
+                total_area += triangle_area(&positions[*a], &positions[*b], &positions[*c]);
+                total_area
+            })
+            .collect();
+        Ok(Mesh { positions, normals, uvs, indices, bvh, cumulative_areas })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Material {
-    Light,
-    Lambertian(Vec3)
-}
\ No newline at end of file
+    // glows with the given color, can be brighter than 1
+    Emissive(Vec3),
+    Lambertian(Texture),
+    // mirror, fuzz goes from 0 (perfect reflection) to 1
+    Metal { albedo: Texture, fuzz: f32 },
+    // real metal from its complex index of refraction eta + ik, see `Material::gold` and friends.
+    // roughness goes from 0 (a mirror) to 1
+    Conductor { eta: Vec3, k: Vec3, roughness: f32 },
+    // glass, water and such. Light travelling inside loses exp(-absorption * distance) of itself,
+    // so an absorption of (0, 1, 1) gives red glass that gets darker the thicker it is.
+    // roughness 0 is polished and 1 very frosted
+    Dielectric { ior: f32, absorption: Option<Vec3>, roughness: f32 },
+    // any other material with a normal or bump map on top
+    Bumped { material: Box<Material>, bump: Bump },
+    // one material with sliders for most things, see `Principled`
+    Principled(Box<Principled>)
+}
+impl Material {
+    pub fn lambertian<T: Into<Texture>>(albedo: T) -> Material {
+        Material::Lambertian(albedo.into())
+    }
+    pub fn metal<T: Into<Texture>>(albedo: T, fuzz: f32) -> Material {
+        Material::Metal { albedo: albedo.into(), fuzz: f32::min(fuzz, 1.0) }
+    }
+    pub fn conductor(eta: Vec3, k: Vec3, roughness: f32) -> Material {
+        Material::Conductor { eta, k, roughness: f32::clamp(roughness, 0.0, 1.0) }
+    }
+    // indices of refraction for red, green and blue light
+    pub fn gold(roughness: f32) -> Material {
+        Material::conductor(Vec3::new(0.143, 0.374, 1.442), Vec3::new(3.983, 2.385, 1.603), roughness)
+    }
+    pub fn copper(roughness: f32) -> Material {
+        Material::conductor(Vec3::new(0.200, 0.924, 1.102), Vec3::new(3.912, 2.452, 2.142), roughness)
+    }
+    pub fn aluminium(roughness: f32) -> Material {
+        Material::conductor(Vec3::new(1.657, 0.880, 0.521), Vec3::new(9.224, 6.270, 4.837), roughness)
+    }
+    pub fn silver(roughness: f32) -> Material {
+        Material::conductor(Vec3::new(0.155, 0.117, 0.138), Vec3::new(4.828, 3.122, 2.147), roughness)
+    }
+    pub fn dielectric(ior: f32) -> Material {
+        Material::Dielectric { ior, absorption: None, roughness: 0.0 }
+    }
+    pub fn rough_dielectric(ior: f32, roughness: f32) -> Material {
+        Material::Dielectric { ior, absorption: None, roughness: f32::clamp(roughness, 0.0, 1.0) }
+    }
+    pub fn principled(principled: Principled) -> Material {
+        Material::Principled(Box::new(principled))
+    }
+    pub fn bumped(material: Material, bump: Bump) -> Material {
+        // a second map replaces the first
+        let material = match material {
+            Material::Bumped { material, .. } => *material,
+            material => material
+        };
+        Material::Bumped { material: Box::new(material), bump }
+    }
+
+    /// The material under any normal or bump map
+    pub fn base(&self) -> &Material {
+        match self {
+            Material::Bumped { material, .. } => material.base(),
+            material => material
+        }
+    }
+
+    /// How the material scatters light at `surface`, None for lights and for perfectly smooth
+    /// materials, which can only send light one way
+    pub fn bsdf(&self, surface: &SurfacePoint) -> Option<Bsdf> {
+        match self {
+            Material::Lambertian(albedo) => Some(Bsdf::Lambertian { albedo: albedo.value(surface) }),
+            Material::Conductor { eta, k, roughness } if *roughness >= MIN_ROUGHNESS => Some(Bsdf::Conductor {
+                eta: eta.clone(),
+                k: k.clone(),
+                alpha: roughness * roughness
+            }),
+            Material::Dielectric { ior, roughness, .. } if *roughness >= MIN_ROUGHNESS => Some(Bsdf::Dielectric {
+                eta: if surface.front_face { *ior } else { 1.0 / ior },
+                alpha: roughness * roughness
+            }),
+            Material::Principled(principled) => Some(Bsdf::Principled(principled.layers(surface))),
+            Material::Bumped { material, .. } => material.bsdf(surface),
+            _ => None
+        }
+    }
+
+    /// Light given off evenly by the material, None if it does not glow. These are sampled like lights,
+    /// the textured emission of principled materials is only found by running into it
+    pub fn emission(&self) -> Option<Vec3> {
+        match self {
+            Material::Emissive(color) => Some(color.clone()),
+            Material::Principled(principled) => match &principled.emission {
+                Texture::Constant(color) if color[0] > 0.0 || color[1] > 0.0 || color[2] > 0.0 => Some(color.clone()),
+                _ => None
+            },
+            Material::Bumped { material, .. } => material.emission(),
+            _ => None
+        }
+    }
+}
+
+/// A single material with sliders for base color, metalness, roughness and so on, layered like Disney's
+/// principled BRDF (Burley 2012) and glTF's metallic roughness materials. Every parameter is a texture,
+/// the ones that are a single number use the average of red, green and blue, clamped to [0, 1]
+#[derive(Debug, Clone)]
+pub struct Principled {
+    pub base_color: Texture,
+    // 0 is a dielectric like plastic or wood, 1 a metal that reflects the base color
+    pub metallic: Texture,
+    // 0 is polished and 1 very rough
+    pub roughness: Texture,
+    // how strongly dielectrics reflect, 0.5 is the 4% of most plastics and glass (ior 1.5) and 1 is 8%.
+    // also sets the index of refraction of light going through
+    pub specular: Texture,
+    // a layer of clear varnish on top, like on car paint
+    pub clearcoat: Texture,
+    pub clearcoat_roughness: Texture,
+    // a soft shine at grazing angles like on cloth, as a color so it can be tinted
+    pub sheen: Texture,
+    // how much of the dielectric part lets light through like glass instead of scattering it diffusely
+    pub transmission: Texture,
+    // light given off, can be brighter than 1. a constant glow lights the scene like an emissive
+    // material, a textured one is only found by paths that run into it
+    pub emission: Texture
+}
+impl Principled {
+    /// A rough white-ish plastic in the given color, change the rest from there
+    pub fn new<T: Into<Texture>>(base_color: T) -> Principled {
+        Principled {
+            base_color: base_color.into(),
+            metallic: 0.0.into(),
+            roughness: 0.5.into(),
+            specular: 0.5.into(),
+            clearcoat: 0.0.into(),
+            clearcoat_roughness: 0.03.into(),
+            sheen: 0.0.into(),
+            transmission: 0.0.into(),
+            emission: 0.0.into()
+        }
+    }
+
+    /// glTF's metallic roughness material. Its packed map keeps roughness in green and metalness in blue,
+    /// `metallic` and `roughness` scale them or are used as they are without a map
+    pub fn metallic_roughness<T: Into<Texture>>(base_color: T, metallic: f32, roughness: f32, map: Option<ImageTexture>) -> Principled {
+        let mut principled = Principled::new(base_color);
+        match map {
+            Some(map) => {
+                principled.metallic = Texture::channel(Texture::Image(map.clone()), 2, metallic);
+                principled.roughness = Texture::channel(Texture::Image(map), 1, roughness);
+            },
+            None => {
+                principled.metallic = metallic.into();
+                principled.roughness = roughness.into();
+            }
+        }
+        principled
+    }
+
+    // the lobes at `surface`
+    fn layers(&self, surface: &SurfacePoint) -> Layers {
+        let number = |texture: &Texture| {
+            let value = texture.value(surface);
+            f32::clamp((value[0] + value[1] + value[2]) / 3.0, 0.0, 1.0)
+        };
+        let roughness = f32::max(number(&self.roughness), MIN_PRINCIPLED_ROUGHNESS);
+        let clearcoat_roughness = f32::max(number(&self.clearcoat_roughness), MIN_PRINCIPLED_ROUGHNESS);
+        // the index of refraction that reflects as much as `specular` asks for head on
+        let r = f32::sqrt(0.08 * number(&self.specular));
+        let ior = f32::max((1.0 + r) / (1.0 - r), MIN_PRINCIPLED_IOR);
+        let clamped = |color: Vec3| Vec3::new(
+            f32::clamp(color[0], 0.0, 1.0),
+            f32::clamp(color[1], 0.0, 1.0),
+            f32::clamp(color[2], 0.0, 1.0)
+        );
+        Layers {
+            base_color: clamped(self.base_color.value(surface)),
+            metallic: number(&self.metallic),
+            eta: if surface.front_face { ior } else { 1.0 / ior },
+            alpha: roughness * roughness,
+            sheen: clamped(self.sheen.value(surface)),
+            clearcoat: number(&self.clearcoat),
+            clearcoat_alpha: clearcoat_roughness * clearcoat_roughness,
+            transmission: number(&self.transmission)
+        }
+    }
+}
+
+/// Möller–Trumbore ray triangle intersection
+fn intersect_triangle(ray: &Ray, a: &Vec3, b: &Vec3, c: &Vec3) -> Option<Hit> {
+    let dir = ray.get_direction();
+    let edge_1 = b.clone() - a.clone();
+    let edge_2 = c.clone() - a.clone();
+
+    let p = dir.cross(&edge_2);
+    let det = edge_1.dot(&p);
+    if f32::abs(det) < TRIANGLE_EPSILON {
+        // ray is parallel with the triangle
+        return None;
+    }
+    let inv_det = 1.0 / det;
+
+    let to_origin = ray.get_origin() - a.clone();
+    let u = to_origin.dot(&p) * inv_det;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+
+    let q = to_origin.cross(&edge_1);
+    let v = dir.dot(&q) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let dist = edge_2.dot(&q) * inv_det;
+    if dist < TRIANGLE_EPSILON {
+        return None;
+    }
+    Some(Hit { dist, primitive: 0, u, v })
+}
+
+fn face_normal(a: &Vec3, b: &Vec3, c: &Vec3) -> Vec3 {
+    normalize(&(b.clone() - a.clone()).cross(&(c.clone() - a.clone())))
+}
+
+fn triangle_bounds(a: &Vec3, b: &Vec3, c: &Vec3) -> Aabb {
+    Aabb::new(a.clone(), a.clone()).grow(b).grow(c)
+}
+
+fn triangle_area(a: &Vec3, b: &Vec3, c: &Vec3) -> f32 {
+    0.5 * length((b.clone() - a.clone()).cross(&(c.clone() - a.clone())))
+}
+
+// uniformly distributed over the triangle
+fn random_in_triangle(a: &Vec3, b: &Vec3, c: &Vec3) -> Vec3 {
+    let su = f32::sqrt(random_f32());
+    let (wa, wb) = (1.0 - su, random_f32() * su);
+    a.clone() * wa + b.clone() * wb + c.clone() * (1.0 - wa - wb)
+}
+
+// turns a density per area at `point` into one per solid angle seen from `from`
+fn area_to_solid_angle(from: &Vec3, point: Vec3, normal: Vec3, pdf_area: f32) -> Option<ShapeSample> {
+    let to_point = point.clone() - from.clone();
+    let dist_squared = to_point.dot(&to_point);
+    let cos = f32::abs(normal.dot(&to_point)) / f32::sqrt(dist_squared);
+    if cos < 1e-6 || dist_squared == 0.0 {
+        return None;
+    }
+    Some(ShapeSample { point, normal, pdf: pdf_area * dist_squared / cos })
+}
+
+fn interpolate(values: &[Vec3; 3], weights: &[f32; 3]) -> Vec3 {
+    normalize(&(
+        values[0].clone() * weights[0] + values[1].clone() * weights[1] + values[2].clone() * weights[2]
+    ))
+}
+
+// dp/du and dp/dv of a triangle, made up from the normal when its uvs do not span an area
+fn uv_derivatives(vertices: [&Vec3; 3], uvs: &[(f32, f32); 3], normal: &Vec3) -> (Vec3, Vec3) {
+    let e1 = vertices[1].clone() - vertices[0].clone();
+    let e2 = vertices[2].clone() - vertices[0].clone();
+    let (du1, dv1) = (uvs[1].0 - uvs[0].0, uvs[1].1 - uvs[0].1);
+    let (du2, dv2) = (uvs[2].0 - uvs[0].0, uvs[2].1 - uvs[0].1);
+    let det = du1 * dv2 - du2 * dv1;
+    if f32::abs(det) < 1e-12 {
+        return orthonormal_basis(normal);
+    }
+    (
+        (e1.clone() * dv2 - e2.clone() * dv1) / det,
+        (e2 * du1 - e1 * du2) / det
+    )
+}
+
+/// Unit tangent and bitangent at right angles to the unit `normal`, following dp/du and dp/dv.
+/// The bitangent keeps the side of dp/dv, so mirrored uvs give a mirrored frame.
+pub fn shading_frame(normal: &Vec3, dpdu: &Vec3, dpdv: &Vec3) -> (Vec3, Vec3) {
+    let t = dpdu.clone() - normal.clone() * normal.dot(dpdu);
+    let t_length = length(t.clone());
+    if t_length < 1e-8 || !t_length.is_finite() {
+        return orthonormal_basis(normal);
+    }
+    let tangent = t / t_length;
+    let bitangent = normal.cross(&tangent);
+    if bitangent.dot(dpdv) < 0.0 {
+        (tangent, bitangent * -1.0)
+    } else {
+        (tangent, bitangent)
+    }
+}
+
+fn interpolate_uv(values: &[(f32, f32); 3], weights: &[f32; 3]) -> (f32, f32) {
+    (
+        values[0].0 * weights[0] + values[1].0 * weights[1] + values[2].0 * weights[2],
+        values[0].1 * weights[0] + values[1].1 * weights[1] + values[2].1 * weights[2]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::WrapMode;
+
+    // a point on the xy plane facing +z with the given uv
+    fn surface(uv: (f32, f32), front_face: bool) -> SurfacePoint {
+        let z = Vec3::new(0.0, 0.0, 1.0);
+        SurfacePoint {
+            point: Vec3::new(uv.0, uv.1, 0.0),
+            local_point: Vec3::new(uv.0, uv.1, 0.0),
+            normal: z.clone(),
+            geometric_normal: z,
+            tangent: Vec3::new(1.0, 0.0, 0.0),
+            bitangent: Vec3::new(0.0, 1.0, 0.0),
+            dpdu: Vec3::new(1.0, 0.0, 0.0),
+            dpdv: Vec3::new(0.0, 1.0, 0.0),
+            uv,
+            front_face
+        }
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    #[test]
+    fn principled_textures_become_layers() {
+        // the left pixel is shiny metal, the right rough plastic. red is not used by either
+        let map = ImageTexture::new(2, 1, vec![Vec3::new(0.7, 0.2, 1.0), Vec3::new(0.7, 1.0, 0.0)], WrapMode::Clamp);
+        let mut principled = Principled::metallic_roughness(Vec3::new(0.9, 0.5, 0.1), 0.8, 0.5, Some(map));
+        principled.clearcoat = Texture::checker_2d(Texture::from(1.0), Texture::from(0.0), 2.0);
+        principled.sheen = Vec3::new(0.3, 0.2, 0.1).into();
+        principled.specular = 1.0.into();
+        let material = Material::principled(principled);
+        let layers = |uv, front_face| match material.bsdf(&surface(uv, front_face)) {
+            Some(Bsdf::Principled(layers)) => layers,
+            other => panic!("expected principled layers, got {:?}", other)
+        };
+
+        // blue is metallic and green roughness, each scaled by its factor
+        let left = layers((0.25, 0.25), true);
+        assert!(close(left.metallic, 0.8), "{:?}", left);
+        assert!(close(left.alpha, 0.1 * 0.1), "{:?}", left);
+        let right = layers((0.75, 0.25), true);
+        assert!(close(right.metallic, 0.0), "{:?}", right);
+        assert!(close(right.alpha, 0.5 * 0.5), "{:?}", right);
+
+        // the checker covers one cell of the clearcoat and leaves the next one bare
+        assert!(close(left.clearcoat, 1.0), "{:?}", left);
+        assert!(close(right.clearcoat, 0.0), "{:?}", right);
+        assert_eq!(left.base_color, Vec3::new(0.9, 0.5, 0.1));
+        assert_eq!(left.sheen, Vec3::new(0.3, 0.2, 0.1));
+
+        // specular 1 reflects 8% head on, from the inside the ratio flips
+        let f0 = ((left.eta - 1.0) / (left.eta + 1.0)).powi(2);
+        assert!(close(f0, 0.08), "{:?}", left);
+        let inside = layers((0.25, 0.25), false);
+        assert!(close(inside.eta, 1.0 / left.eta), "{:?}", inside);
+    }
+
+    #[test]
+    fn bumps_tilt_the_normal() {
+        let bumped = |bump: Bump| {
+            let mut surface = surface((0.5, 0.5), true);
+            bump.apply(&mut surface);
+            // the frame stays at right angles
+            assert!(close(surface.normal.dot(&surface.tangent), 0.0) && close(surface.normal.dot(&surface.bitangent), 0.0));
+            assert!(close(length(surface.normal.clone()), 1.0));
+            surface.normal
+        };
+        let normal_map = |texel: Vec3, strength| Bump::Normal {
+            map: ImageTexture::new(1, 1, vec![texel], WrapMode::Repeat), strength
+        };
+        let same = |a: &Vec3, b: &Vec3| (0..3).all(|i| close(a[i], b[i]));
+        let z = Vec3::new(0.0, 0.0, 1.0);
+
+        // a flat normal map leaves the normal alone
+        assert!(same(&bumped(normal_map(Vec3::new(0.5, 0.5, 1.0), 1.0)), &z));
+        // one leaning towards +u tilts it towards the tangent, and strength 0 flattens it again
+        let tilted = bumped(normal_map(Vec3::new(0.75, 0.5, 1.0), 1.0));
+        assert!(same(&tilted, &normalize(&Vec3::new(0.5, 0.0, 1.0))), "{:?}", tilted);
+        assert!(same(&bumped(normal_map(Vec3::new(0.75, 0.5, 1.0), 0.0)), &z));
+        // a normal along the surface would light its back, it is ignored
+        assert!(same(&bumped(normal_map(Vec3::new(1.0, 0.5, 0.5), 1.0)), &z));
+
+        // a constant height is a flat surface
+        assert!(same(&bumped(Bump::Height { map: Texture::from(0.7), scale: 2.0 }), &z));
+        // heights going up along u, by 2 per unit of u between the pixel centers, tilt the normal back
+        let ramp = ImageTexture::new(2, 1, vec![Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 1.0, 1.0)], WrapMode::Clamp);
+        let sloped = bumped(Bump::Height { map: Texture::Image(ramp), scale: 0.25 });
+        assert!(same(&sloped, &normalize(&Vec3::new(-0.5, 0.0, 1.0))), "{:?}", sloped);
+    }
+
+    #[test]
+    fn rays_hit_triangles_inside_and_on_the_edges() {
+        let (a, b, c) = (Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        let down = |x: f32, y: f32| intersect_triangle(&Ray::new(Vec3::new(x, y, 1.0), Vec3::new(0.0, 0.0, -1.0)), &a, &b, &c);
+
+        // u and v are the weights of b and c
+        let hit = down(0.2, 0.3).unwrap();
+        assert!(close(hit.dist, 1.0) && close(hit.u, 0.2) && close(hit.v, 0.3), "{:?}", hit);
+        // the edges and corners count
+        for (x, y) in [(0.5, 0.5), (0.0, 0.5), (0.5, 0.0), (0.0, 0.0), (1.0, 0.0), (0.0, 1.0)] {
+            assert!(down(x, y).is_some(), "({}, {})", x, y);
+        }
+        for (x, y) in [(0.51, 0.5), (-0.01, 0.5), (0.5, -0.01), (2.0, 2.0)] {
+            assert!(down(x, y).is_none(), "({}, {})", x, y);
+        }
+
+        // the back is hit too
+        let up = intersect_triangle(&Ray::new(Vec3::new(0.2, 0.3, -2.0), Vec3::new(0.0, 0.0, 1.0)), &a, &b, &c).unwrap();
+        assert!(close(up.dist, 2.0) && close(up.u, 0.2) && close(up.v, 0.3), "{:?}", up);
+        // but not behind the ray or along the triangle's plane
+        assert!(intersect_triangle(&Ray::new(Vec3::new(0.2, 0.3, -1.0), Vec3::new(0.0, 0.0, -1.0)), &a, &b, &c).is_none());
+        assert!(intersect_triangle(&Ray::new(Vec3::new(-1.0, 0.3, 0.0), Vec3::new(1.0, 0.0, 0.0)), &a, &b, &c).is_none());
+        assert!(intersect_triangle(&Ray::new(Vec3::new(-1.0, 0.3, 0.5), Vec3::new(1.0, 0.0, 0.0)), &a, &b, &c).is_none());
+    }
+
+    #[test]
+    fn meshes_interpolate_normals_and_uvs() {
+        // a unit square with uvs going to 2, and a normal leaning towards +x at its second corner
+        let positions = vec![Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(1.0, 1.0, 0.0), Vec3::new(0.0, 1.0, 0.0)];
+        let z = Vec3::new(0.0, 0.0, 1.0);
+        let normals = vec![z.clone(), normalize(&Vec3::new(1.0, 0.0, 1.0)), z.clone(), z.clone()];
+        let uvs = vec![(0.0, 0.0), (2.0, 0.0), (2.0, 2.0), (0.0, 2.0)];
+        let mesh = Hittable::new(
+            Shape::mesh(positions, normals.clone(), uvs, vec![[0, 1, 2], [0, 2, 3]]).unwrap(),
+            Material::lambertian(Vec3::new(1.0, 1.0, 1.0))
+        );
+        let surface_at = |x: f32, y: f32, from: f32| {
+            let ray = Ray::new(Vec3::new(x, y, from), Vec3::new(0.0, 0.0, -from));
+            let hit = mesh.is_hit(&ray).unwrap();
+            (hit.primitive, mesh.surface(&ray, &hit))
+        };
+
+        // corner weights 0.25, 0.5 and 0.25 on the first triangle
+        let (primitive, surface) = surface_at(0.75, 0.25, 1.0);
+        assert_eq!(primitive, 0);
+        assert!(close(surface.uv.0, 1.5) && close(surface.uv.1, 0.5), "{:?}", surface.uv);
+        let expected = normalize(&(normals[0].clone() * 0.25 + normals[1].clone() * 0.5 + normals[2].clone() * 0.25));
+        assert!((0..3).all(|i| close(surface.normal[i], expected[i])), "{:?}", surface.normal);
+        assert!(surface.front_face);
+
+        // the second triangle does not touch the leaning corner
+        let (primitive, surface) = surface_at(0.25, 0.75, 1.0);
+        assert_eq!(primitive, 1);
+        assert!(close(surface.uv.0, 0.5) && close(surface.uv.1, 1.5), "{:?}", surface.uv);
+        assert!((0..3).all(|i| close(surface.normal[i], z[i])), "{:?}", surface.normal);
+
+        // from below both normals turn around
+        let (_, surface) = surface_at(0.75, 0.25, -1.0);
+        assert!(!surface.front_face);
+        assert!((0..3).all(|i| close(surface.normal[i], -expected[i])), "{:?}", surface.normal);
+        assert!(close(surface.geometric_normal[2], -1.0));
+    }
+
+    #[test]
+    fn broken_meshes_are_refused() {
+        let positions = || vec![Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)];
+        let z = Vec3::new(0.0, 0.0, 1.0);
+        assert!(matches!(
+            Mesh::new(positions(), vec![z.clone(), z], vec![], vec![[0, 1, 2]]),
+            Err(MeshError::NormalCount { normals: 2, positions: 3 })
+        ));
+        assert!(matches!(
+            Mesh::new(positions(), vec![], vec![(0.0, 0.0)], vec![[0, 1, 2]]),
+            Err(MeshError::UvCount { uvs: 1, positions: 3 })
+        ));
+        assert!(matches!(
+            Mesh::new(positions(), vec![], vec![], vec![[0, 1, 2], [2, 3, 0]]),
+            Err(MeshError::IndexOutOfBounds { index: 3, positions: 3 })
+        ));
+        // leaving normals and uvs out is fine
+        assert!(Mesh::new(positions(), vec![], vec![], vec![[0, 1, 2]]).is_ok());
+    }
+}
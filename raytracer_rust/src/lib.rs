@@ -0,0 +1,42 @@
+pub mod vec3;
+pub use vec3::{Vec3, Color};
+
+pub mod ray;
+pub use ray::Ray;
+
+pub mod hittable;
+
+pub mod bvh;
+pub mod microfacet;
+pub mod bsdf;
+
+pub mod light;
+pub use light::Light;
+
+pub mod noise;
+pub mod texture;
+pub use texture::Texture;
+
+pub mod environment;
+pub use environment::Environment;
+
+pub mod sky;
+pub use sky::Sky;
+
+pub mod camera;
+pub use camera::Camera;
+
+pub mod world;
+pub use world::World;
+
+pub mod obj;
+
+pub mod tonemap;
+
+pub mod progress;
+
+pub mod framebuffer;
+pub use framebuffer::Framebuffer;
+
+pub mod scene;
+pub use scene::Scene;
@@ -0,0 +1,171 @@
+use crate::vec3::{Vec3, normalize, length, random_in_cone};
+
+/// Lights that are not part of the scene geometry, they light things up but are never seen themselves.
+/// `color` times `intensity` is how much light arrives one unit away (or anywhere for directional lights).
+#[derive(Debug, Clone)]
+pub enum Light {
+    // shines equally in every direction from one point
+    Point { position: Vec3, color: Vec3, intensity: f32 },
+    // light from very far away, like the sun. `direction` is the way the light travels,
+    // `angular_diameter` (in degrees) is how big the light looks, 0 gives hard shadows
+    Directional { direction: Vec3, color: Vec3, intensity: f32, angular_diameter: f32 },
+    // a point light that only shines into a cone around `direction`. `cone_angle` is the
+    // angle in degrees from the axis to where the light ends, it fades out over the last `falloff` degrees
+    Spot { position: Vec3, direction: Vec3, color: Vec3, intensity: f32, cone_angle: f32, falloff: f32 }
+}
+
+/// Light arriving at a point from one light
+pub struct LightSample {
+    // unit vector from the point towards the light
+    pub direction: Vec3,
+    // how far the light is, infinite for directional lights
+    pub distance: f32,
+    // light arriving on a surface facing the light, before the cosine
+    pub irradiance: Vec3
+}
+
+impl Light {
+    pub fn point(position: Vec3, color: Vec3, intensity: f32) -> Light {
+        Light::Point { position, color, intensity }
+    }
+
+    pub fn directional(direction: Vec3, color: Vec3, intensity: f32, angular_diameter: f32) -> Light {
+        Light::Directional { direction: normalize(&direction), color, intensity, angular_diameter }
+    }
+
+    pub fn spot(position: Vec3, direction: Vec3, color: Vec3, intensity: f32, cone_angle: f32, falloff: f32) -> Light {
+        Light::Spot {
+            position,
+            direction: normalize(&direction),
+            color,
+            intensity,
+            cone_angle,
+            falloff: f32::clamp(falloff, 0.0, cone_angle)
+        }
+    }
+
+    /// Picks a direction towards the light from `p`, None if no light reaches `p` at all
+    pub fn sample(&self, p: &Vec3) -> Option<LightSample> {
+        match self {
+            Light::Point { position, color, intensity } => {
+                let (direction, distance) = towards(p, position)?;
+                Some(LightSample { direction, distance, irradiance: color.clone() * (*intensity / (distance * distance)) })
+            },
+            Light::Directional { direction, color, intensity, angular_diameter } => {
+                let to_light = direction.clone() * -1.0;
+                let direction = if *angular_diameter > 0.0 {
+                    // somewhere on the disk of the light, uniformly over the solid angle it covers
+                    random_in_cone(&to_light, f32::cos((angular_diameter / 2.0).to_radians()))
+                } else {
+                    to_light
+                };
+                Some(LightSample { direction, distance: f32::INFINITY, irradiance: color.clone() * *intensity })
+            },
+            Light::Spot { position, direction: axis, color, intensity, cone_angle, falloff } => {
+                let (direction, distance) = towards(p, position)?;
+                let cos_outer = f32::cos(cone_angle.to_radians());
+                let cos_inner = f32::cos((cone_angle - falloff).to_radians());
+                let cos_theta = -direction.dot(axis);
+                if cos_theta <= cos_outer {
+                    return None;
+                }
+                let cone = smoothstep(cos_outer, cos_inner, cos_theta);
+                Some(LightSample { direction, distance, irradiance: color.clone() * (*intensity * cone / (distance * distance)) })
+            }
+        }
+    }
+}
+
+// unit direction and distance from `p` to `target`, None if they are the same point
+fn towards(p: &Vec3, target: &Vec3) -> Option<(Vec3, f32)> {
+    let to_target = target.clone() - p.clone();
+    let distance = length(to_target.clone());
+    if distance <= 0.0 {
+        return None;
+    }
+    Some((to_target / distance, distance))
+}
+
+// 0 below `edge0`, 1 above `edge1` and a smooth s curve in between
+fn smoothstep(edge0: f32, edge1: f32, x: f32) -> f32 {
+    if edge1 <= edge0 {
+        return if x >= edge1 { 1.0 } else { 0.0 };
+    }
+    let t = f32::clamp((x - edge0) / (edge1 - edge0), 0.0, 1.0);
+    t * t * (3.0 - 2.0 * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vec3::seed_rng;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    #[test]
+    fn point_lights_fall_off_with_the_square_of_the_distance() {
+        let light = Light::point(Vec3::new(0.0, 2.0, 0.0), Vec3::new(1.0, 0.5, 0.25), 8.0);
+        let near = light.sample(&Vec3::new(0.0, 0.0, 0.0)).unwrap();
+        let far = light.sample(&Vec3::new(0.0, -2.0, 0.0)).unwrap();
+        assert!(close(near.distance, 2.0) && close(far.distance, 4.0));
+        assert!(close(near.irradiance[0], 2.0) && close(near.irradiance[2], 0.5), "{:?}", near.irradiance);
+        assert!(close(far.irradiance[0], near.irradiance[0] / 4.0));
+        assert!(close(near.direction[1], 1.0));
+        // nothing to light at the light itself
+        assert!(light.sample(&Vec3::new(0.0, 2.0, 0.0)).is_none());
+    }
+
+    #[test]
+    fn spot_lights_fade_out_towards_the_edge_of_the_cone() {
+        // pointing down, full up to 20 degrees from the axis and dark past 30
+        let light = Light::spot(Vec3::new(0.0, 0.0, 0.0), Vec3::new(0.0, -1.0, 0.0), Vec3::new(1.0, 1.0, 1.0), 4.0, 30.0, 10.0);
+        let at = |degrees: f32| {
+            let angle = degrees.to_radians();
+            light.sample(&Vec3::new(2.0 * angle.sin(), -2.0 * angle.cos(), 0.0)).map_or(0.0, |sample| sample.irradiance[0])
+        };
+        for degrees in [0.0, 10.0, 19.9] {
+            assert!(close(at(degrees), 1.0), "{} at {} degrees", at(degrees), degrees);
+        }
+        for degrees in [30.1, 45.0, 90.0, 180.0] {
+            assert_eq!(at(degrees), 0.0, "{} degrees", degrees);
+        }
+        // and gets darker all the way through the falloff, without jumps
+        let mut last = at(20.0);
+        for i in 1..=100 {
+            let degrees = 20.0 + i as f32 * 0.1;
+            let value = at(degrees);
+            assert!(value <= last && last - value < 0.02, "{} after {} at {} degrees", value, last, degrees);
+            last = value;
+        }
+        assert!(last < 1e-3);
+        // the blend is in the cosine, so halfway is only about half
+        assert!((at(25.0) - 0.5).abs() < 0.1, "{}", at(25.0));
+    }
+
+    #[test]
+    fn directional_lights_sample_inside_their_disk() {
+        seed_rng(3);
+        let direction = Vec3::new(1.0, -2.0, 0.5);
+        let to_light = normalize(&direction) * -1.0;
+        let sharp = Light::directional(direction.clone(), Vec3::new(1.0, 1.0, 1.0), 3.0, 0.0);
+        let sample = sharp.sample(&Vec3::new(5.0, 0.0, 0.0)).unwrap();
+        assert!(close(sample.direction.dot(&to_light), 1.0) && sample.distance.is_infinite());
+        assert!(close(sample.irradiance[1], 3.0));
+
+        // 10 degrees across, so at most 5 from the middle
+        let soft = Light::directional(direction, Vec3::new(1.0, 1.0, 1.0), 3.0, 10.0);
+        let cos_radius = f32::cos(5f32.to_radians());
+        let mut widest: f32 = 1.0;
+        for _ in 0..10_000 {
+            let sample = soft.sample(&Vec3::new(0.0, 0.0, 0.0)).unwrap();
+            assert!(close(length(sample.direction.clone()), 1.0));
+            let cos = sample.direction.dot(&to_light);
+            assert!(cos >= cos_radius - 1e-6, "{} degrees off", cos.acos().to_degrees());
+            widest = widest.min(cos);
+        }
+        // and fill it out to the edge
+        assert!(widest < f32::cos(4.9f32.to_radians()));
+    }
+}
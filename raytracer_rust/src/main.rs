@@ -1,35 +1,355 @@
-mod vec3;
-use vec3::{Vec3, length, Color};
+use std::path::PathBuf;
+use std::process;
 
-mod ray;
-use ray::Ray;
+use raytracer_rust::bvh::Aabb;
+use raytracer_rust::framebuffer::ImageFormat;
+use raytracer_rust::progress::Progress;
+use raytracer_rust::hittable::*;
+use raytracer_rust::light::Light;
+use raytracer_rust::scene::{load_scene, Scene};
 
-mod hittable;
-use hittable::*;
+const USAGE: &str = "\
+usage:
+    raytracer_rust render <scene> [options]
+    raytracer_rust info <scene>
 
-mod world;
-use world::World;
+render options:
+    -o, --output <file>   where to save the image, .png .ppm .pfm .exr or .hdr (default image.png)
+    --width <pixels>      image width, keeps the aspect ratio of the scene if --height is not given
+    --height <pixels>     image height, keeps the aspect ratio of the scene if --width is not given
+    --spp <n>             samples per pixel
+    --max-depth <n>       how many times a path may bounce
+    --threads <n>         number of render threads (default one per core)
+    --seed <n>            renders with the same seed come out the same
+    -q, --quiet           no progress and no statistics at the end
+";
 
+// the framebuffer keeps a color, depth and normal per pixel, 2^28 pixels is already 7 GB
+const MAX_SIZE: u32 = 1 << 16;
+const MAX_PIXELS: u64 = 1 << 28;
 
+struct RenderArgs {
+    scene: PathBuf,
+    output: PathBuf,
+    width: Option<u32>,
+    height: Option<u32>,
+    spp: Option<u32>,
+    max_depth: Option<u32>,
+    threads: Option<u32>,
+    seed: Option<u64>,
+    quiet: bool
+}
 
+enum Command {
+    Render(RenderArgs),
+    Info(PathBuf),
+    Help
+}
 
 fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match parse_args(&args) {
+        Ok(command) => command,
+        Err(message) => {
+            eprintln!("error: {}\nrun with --help to see the options", message);
+            process::exit(2);
+        }
+    };
+
+    let result = match command {
+        Command::Render(args) => render(args),
+        Command::Info(scene) => info(scene),
+        Command::Help => {
+            print!("{}", USAGE);
+            Ok(())
+        }
+    };
+    if let Err(message) = result {
+        eprintln!("error: {}", message);
+        process::exit(1);
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Command, String> {
+    let (command, rest) = match args.split_first() {
+        Some((command, rest)) => (command.as_str(), rest),
+        None => return Err("missing command".to_string())
+    };
+
+    match command {
+        "-h" | "--help" | "help" => Ok(Command::Help),
+        "info" => match rest {
+            [scene] => Ok(Command::Info(PathBuf::from(scene))),
+            [] => Err("info needs a scene file".to_string()),
+            _ => Err(format!("info takes only a scene file, got '{}'", rest[1..].join(" ")))
+        },
+        "render" => parse_render_args(rest).map(Command::Render),
+        other => Err(format!("unknown command '{}'", other))
+    }
+}
+
+fn parse_render_args(args: &[String]) -> Result<RenderArgs, String> {
+    let mut scene = None;
+    let mut render_args = RenderArgs {
+        scene: PathBuf::new(),
+        output: PathBuf::from("image.png"),
+        width: None,
+        height: None,
+        spp: None,
+        max_depth: None,
+        threads: None,
+        seed: None,
+        quiet: false
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') {
+            if scene.is_some() {
+                return Err(format!("unexpected argument '{}', only one scene can be rendered at a time", arg));
+            }
+            scene = Some(PathBuf::from(arg));
+            continue;
+        }
+        if arg == "-q" || arg == "--quiet" {
+            render_args.quiet = true;
+            continue;
+        }
+
+        let value = args.next().ok_or_else(|| format!("{} needs a value", arg))?;
+        match arg.as_str() {
+            "-o" | "--output" => render_args.output = PathBuf::from(value),
+            "--width" => render_args.width = Some(size(arg, value)?),
+            "--height" => render_args.height = Some(size(arg, value)?),
+            "--spp" => render_args.spp = Some(positive(arg, value)?),
+            "--max-depth" => render_args.max_depth = Some(positive(arg, value)?),
+            "--threads" => render_args.threads = Some(positive(arg, value)?),
+            "--seed" => render_args.seed = Some(
+                value.parse().map_err(|_| format!("{} has to be a whole number that is not negative, got '{}'", arg, value))?
+            ),
+            _ => return Err(format!("unknown option '{}'", arg))
+        }
+    }
+
+    if let (Some(width), Some(height)) = (render_args.width, render_args.height) {
+        check_pixels(width, height)?;
+    }
+    render_args.scene = scene.ok_or("render needs a scene file")?;
+    Ok(render_args)
+}
+
+fn positive(option: &str, value: &str) -> Result<u32, String> {
+    match value.parse() {
+        Ok(0) | Err(_) => Err(format!("{} has to be a whole number of at least 1, got '{}'", option, value)),
+        Ok(n) => Ok(n)
+    }
+}
+
+fn size(option: &str, value: &str) -> Result<u32, String> {
+    let size = positive(option, value)?;
+    if size > MAX_SIZE {
+        return Err(format!("{} can be at most {}, got {}", option, MAX_SIZE, size));
+    }
+    Ok(size)
+}
+
+fn check_pixels(width: u32, height: u32) -> Result<(), String> {
+    if width > MAX_SIZE || height > MAX_SIZE || width as u64 * height as u64 > MAX_PIXELS {
+        return Err(format!("{}x{} is too large, images can have at most {} pixels and {} on a side", width, height, MAX_PIXELS, MAX_SIZE));
+    }
+    Ok(())
+}
+
+fn render(args: RenderArgs) -> Result<(), String> {
+    // fail before spending minutes on rendering
+    ImageFormat::from_path(&args.output).map_err(|err| err.to_string())?;
 
-    let mut world = World::new(512);
+    let Scene { mut world, mut camera, tone_mapping } = load_scene(&args.scene)
+        .map_err(|err| format!("{}: {}", args.scene.display(), err))?;
+    if world.hittables().is_empty() {
+        return Err(format!("{}: there is nothing to render, the scene has no objects", args.scene.display()));
+    }
 
-    // reference coordinates for the hittables, to make them easier to move around in the scene
-    let z = -2.0;
-    let x = 0.0;
-    
-    world.add(Shape::sphere(Vec3::new(x+0.2, 0.4, z-1.0), 0.3), Material::Lambertian(Color::red()));
-    world.add(Shape::sphere(Vec3::new(x+0.0, -0.5, z-3.0), 0.5), Material::Lambertian(Color::orange()));
-    world.add(Shape::sphere(Vec3::new(x+0.6, -0.2, z-0.5), 0.2), Material::Lambertian(Color::blue()));
-    world.add(Shape::sphere(Vec3::new(x-0.8, 0.5, z-0.4), 0.5), Material::Lambertian(Color::green()));
-    world.add(Shape::plane(0.0, 1.0, 0.0, -1.2), Material::Lambertian(Color::white()));
+    let aspect_ratio = camera.aspect_ratio();
+    let (width, height) = match (args.width, args.height) {
+        (Some(width), Some(height)) => (width, height),
+        (Some(width), None) => (width, u32::max(1, (width as f32 / aspect_ratio).round() as u32)),
+        (None, Some(height)) => (u32::max(1, (height as f32 * aspect_ratio).round() as u32), height),
+        (None, None) => (camera.width as u32, camera.height as u32)
+    };
+    // the other side follows the aspect ratio of the scene and can end up too large
+    check_pixels(width, height)?;
+    camera.set_size(width as i32, height as i32).map_err(|err| err.to_string())?;
+
+    if let Some(spp) = args.spp {
+        world.set_samples_per_pixel(spp);
+    }
+    if let Some(max_depth) = args.max_depth {
+        world.set_max_depth(max_depth);
+    }
+    if let Some(seed) = args.seed {
+        world.set_seed(seed);
+    }
+    if let Some(threads) = args.threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            .build_global()
+            .map_err(|err| format!("could not start {} threads: {}", threads, err))?;
+    }
+
+    world.set_progress(if args.quiet { Progress::Silent } else { Progress::auto() });
+
+    let (mut image, stats) = world.render_with_stats(&camera);
+    image.tone_mapping = tone_mapping;
+    image.save(&args.output).map_err(|err| format!("could not save {}: {}", args.output.display(), err))?;
+    if !args.quiet {
+        eprintln!("{}", stats);
+    }
+    Ok(())
+}
 
-    world.set_sun(Shape::sphere(Vec3::new(-10.0, 8.0, 5.0), 2.0));
+fn info(path: PathBuf) -> Result<(), String> {
+    let Scene { world, camera, tone_mapping } = load_scene(&path)
+        .map_err(|err| format!("{}: {}", path.display(), err))?;
 
-    world.render();
+    let (mut spheres, mut planes, mut triangles, mut meshes, mut mesh_triangles) = (0, 0, 0, 0, 0);
+    let (mut lights, mut lambertian, mut metal, mut dielectric, mut principled, mut bumped) = (0, 0, 0, 0, 0, 0);
+    let mut bounds: Option<Aabb> = None;
+    for hittable in world.hittables() {
+        match &hittable.shape {
+            Shape::Sphere(_) => spheres += 1,
+            Shape::Plane(_) => planes += 1,
+            Shape::Triangle(_) => triangles += 1,
+            Shape::Mesh(mesh) => {
+                meshes += 1;
+                mesh_triangles += mesh.indices.len();
+            }
+        }
+        if let Material::Bumped { .. } = hittable.material {
+            bumped += 1;
+        }
+        // principled materials can glow too
+        if hittable.material.emission().is_some() {
+            lights += 1;
+        }
+        match hittable.material.base() {
+            Material::Emissive(_) => (),
+            Material::Lambertian(_) => lambertian += 1,
+            Material::Metal { .. } | Material::Conductor { .. } => metal += 1,
+            Material::Dielectric { .. } => dielectric += 1,
+            Material::Principled(_) => principled += 1,
+            Material::Bumped { .. } => ()
+        }
+        if let Some(aabb) = hittable.bounding_box() {
+            bounds = Some(match bounds {
+                Some(bounds) => bounds.union(&aabb),
+                None => aabb
+            });
+        }
+    }
 
+    println!("scene        {}", path.display());
+    println!("resolution   {}x{}", camera.width, camera.height);
+    println!("camera       from {} at {}, vfov {}", camera.look_from(), camera.look_at(), camera.vfov());
+    if camera.aperture_radius() > 0.0 {
+        println!("lens         aperture {}, focus distance {}", camera.aperture_radius(), camera.focus_dist());
+    }
+    println!("samples      {} per pixel, at most {} bounces", world.samples_per_pixel(), world.max_depth());
+    println!("tone mapping {:?}, exposure {}", tone_mapping.operator, tone_mapping.exposure);
+    println!("objects      {}", world.hittables().len());
+    println!("  spheres    {}", spheres);
+    println!("  planes     {}", planes);
+    println!("  triangles  {}", triangles);
+    println!("  meshes     {} ({} triangles)", meshes, mesh_triangles);
+    let (mut point, mut directional, mut spot) = (0, 0, 0);
+    for light in world.lights() {
+        match light {
+            Light::Point { .. } => point += 1,
+            Light::Directional { .. } => directional += 1,
+            Light::Spot { .. } => spot += 1
+        }
+    }
+    let sun = if world.sun().is_some() { "a sun, " } else { "" };
+    println!("lights       {}{} point, {} directional, {} spot", sun, point, directional, spot);
+    if let Some(sky) = world.sky() {
+        println!("sky          sun {} degrees up at azimuth {}, turbidity {}, intensity {}", sky.elevation, sky.azimuth, sky.turbidity, sky.intensity);
+    } else if let Some(environment) = world.environment() {
+        let (width, height) = environment.size();
+        let source = environment.path().map_or("generated".to_string(), |path| path.display().to_string());
+        println!("environment  {} ({}x{}), rotated {} degrees, intensity {}", source, width, height, environment.rotation(), environment.intensity());
+    }
+    println!("materials    {} lambertian, {} metal, {} dielectric, {} principled, {} emissive", lambertian, metal, dielectric, principled, lights);
+    if bumped > 0 {
+        println!("             ({} with normal or bump maps)", bumped);
+    }
+    match bounds {
+        Some(bounds) => println!("bounds       {} to {}", bounds.min, bounds.max),
+        None => println!("bounds       none")
+    }
+    if planes > 0 {
+        println!("             (planes are infinite and not included)");
+    }
+    Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Command, String> {
+        parse_args(&args.split_whitespace().map(String::from).collect::<Vec<_>>())
+    }
+
+    fn error(args: &str) -> String {
+        match parse(args) {
+            Ok(_) => panic!("'{}' should not parse", args),
+            Err(message) => message
+        }
+    }
+
+    fn render_args(args: &str) -> RenderArgs {
+        match parse(args) {
+            Ok(Command::Render(args)) => args,
+            Ok(_) => panic!("'{}' is not a render command", args),
+            Err(message) => panic!("'{}': {}", args, message)
+        }
+    }
+
+    #[test]
+    fn render_options() {
+        let args = render_args("render scene.json -o out.exr --width 640 --spp 16 --seed 0 -q");
+        assert_eq!(args.scene, PathBuf::from("scene.json"));
+        assert_eq!(args.output, PathBuf::from("out.exr"));
+        assert_eq!((args.width, args.height), (Some(640), None));
+        assert_eq!((args.spp, args.seed, args.quiet), (Some(16), Some(0), true));
+        assert!(matches!(parse("info scene.json"), Ok(Command::Info(_))));
+        assert!(matches!(parse("--help"), Ok(Command::Help)));
+    }
+
+    #[test]
+    fn bad_arguments() {
+        assert_eq!(error(""), "missing command");
+        assert_eq!(error("draw scene.json"), "unknown command 'draw'");
+        assert_eq!(error("render scene.json --colour red"), "unknown option '--colour'");
+        assert_eq!(error("render scene.json --spp"), "--spp needs a value");
+        assert_eq!(error("render --width 100"), "render needs a scene file");
+        assert_eq!(error("render a.json b.json"), "unexpected argument 'b.json', only one scene can be rendered at a time");
+        assert_eq!(error("render scene.json --spp 0"), "--spp has to be a whole number of at least 1, got '0'");
+        assert_eq!(error("render scene.json --width 12px"), "--width has to be a whole number of at least 1, got '12px'");
+        assert_eq!(error("render scene.json --height -5"), "--height has to be a whole number of at least 1, got '-5'");
+        assert_eq!(error("render scene.json --seed many"), "--seed has to be a whole number that is not negative, got 'many'");
+    }
+
+    #[test]
+    fn image_size_is_limited() {
+        assert_eq!(error("render scene.json --width 3000000000"), "--width can be at most 65536, got 3000000000");
+        assert_eq!(error("render scene.json --width 99999999999"), "--width has to be a whole number of at least 1, got '99999999999'");
+        assert_eq!(error("render scene.json --height 65537"), "--height can be at most 65536, got 65537");
+        assert_eq!(
+            error("render scene.json --width 65536 --height 65536"),
+            "65536x65536 is too large, images can have at most 268435456 pixels and 65536 on a side"
+        );
+        let args = render_args("render scene.json --width 65536 --height 4096");
+        assert_eq!((args.width, args.height), (Some(65536), Some(4096)));
+    }
+}
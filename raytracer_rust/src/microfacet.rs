@@ -0,0 +1,100 @@
+//! The GGX (Trowbridge-Reitz) microfacet distribution and the Fresnel terms that go with it.
+//! Directions are in the local shading frame, z is the surface normal.
+
+use std::f32::consts::PI;
+
+use crate::vec3::{Vec3, normalize};
+
+/// Density of microfacet normals `m`, `alpha` is the width of the distribution (roughness squared)
+pub fn ggx_d(m: &Vec3, alpha: f32) -> f32 {
+    if m[2] <= 0.0 {
+        return 0.0;
+    }
+    let a2 = alpha * alpha;
+    let t = m[2] * m[2] * (a2 - 1.0) + 1.0;
+    a2 / (PI * t * t)
+}
+
+// Smith's auxiliary function, how much of the surface hides behind other microfacets seen from `w`
+fn lambda(w: &Vec3, alpha: f32) -> f32 {
+    let cos2 = w[2] * w[2];
+    if cos2 <= 0.0 {
+        return f32::INFINITY;
+    }
+    let tan2 = f32::max(0.0, 1.0 - cos2) / cos2;
+    (f32::sqrt(1.0 + alpha * alpha * tan2) - 1.0) / 2.0
+}
+
+/// Fraction of the microfacets facing `w` that `w` can see
+pub fn smith_g1(w: &Vec3, alpha: f32) -> f32 {
+    1.0 / (1.0 + lambda(w, alpha))
+}
+
+/// Fraction of the microfacets that are both seen from `wo` and lit from `wi`, the height correlated form
+pub fn smith_g2(wo: &Vec3, wi: &Vec3, alpha: f32) -> f32 {
+    1.0 / (1.0 + lambda(wo, alpha) + lambda(wi, alpha))
+}
+
+/// Picks a microfacet normal that `wo` can see, more likely the more of it `wo` sees.
+/// Heitz 2018, "Sampling the GGX Distribution of Visible Normals". `wo` has to be above the surface.
+pub fn sample_visible_normal(wo: &Vec3, alpha: f32, u1: f32, u2: f32) -> Vec3 {
+    // stretch the view so the distribution becomes a hemisphere
+    let v = normalize(&Vec3::new(alpha * wo[0], alpha * wo[1], wo[2]));
+    let len2 = v[0] * v[0] + v[1] * v[1];
+    let t1 = if len2 > 0.0 { Vec3::new(-v[1], v[0], 0.0) / f32::sqrt(len2) } else { Vec3::new(1.0, 0.0, 0.0) };
+    let t2 = v.cross(&t1);
+
+    // a point on the disk, squashed onto the part of the hemisphere that faces v
+    let r = f32::sqrt(u1);
+    let phi = 2.0 * PI * u2;
+    let p1 = r * f32::cos(phi);
+    let s = 0.5 * (1.0 + v[2]);
+    let p2 = (1.0 - s) * f32::sqrt(1.0 - p1 * p1) + s * r * f32::sin(phi);
+    let n = t1 * p1 + t2 * p2 + v * f32::sqrt(f32::max(0.0, 1.0 - p1 * p1 - p2 * p2));
+
+    // and back
+    normalize(&Vec3::new(alpha * n[0], alpha * n[1], f32::max(1e-6, n[2])))
+}
+
+/// Probability density of `sample_visible_normal` picking `m`
+pub fn visible_normal_pdf(wo: &Vec3, m: &Vec3, alpha: f32) -> f32 {
+    if wo[2] <= 0.0 {
+        return 0.0;
+    }
+    smith_g1(wo, alpha) * f32::max(0.0, wo.dot(m)) * ggx_d(m, alpha) / wo[2]
+}
+
+/// How much light a dielectric reflects, unpolarized. `cos_i` is on the side the light comes from and
+/// `eta` is the index of refraction on the other side over the one on this side
+pub fn fresnel_dielectric(cos_i: f32, eta: f32) -> f32 {
+    let cos_i = f32::clamp(cos_i, 0.0, 1.0);
+    let sin2_t = (1.0 - cos_i * cos_i) / (eta * eta);
+    if sin2_t >= 1.0 {
+        // total internal reflection
+        return 1.0;
+    }
+    let cos_t = f32::sqrt(1.0 - sin2_t);
+    let rs = (cos_i - eta * cos_t) / (cos_i + eta * cos_t);
+    let rp = (eta * cos_i - cos_t) / (eta * cos_i + cos_t);
+    (rs * rs + rp * rp) / 2.0
+}
+
+/// How much light a metal with complex index of refraction eta + ik reflects, per color channel
+pub fn fresnel_conductor(cos_i: f32, eta: &Vec3, k: &Vec3) -> Vec3 {
+    let channel = |c: usize| {
+        let cos2 = f32::clamp(cos_i * cos_i, 0.0, 1.0);
+        let sin2 = 1.0 - cos2;
+        let (eta, k) = (eta[c], k[c]);
+        let t0 = eta * eta - k * k - sin2;
+        let a2b2 = f32::sqrt(t0 * t0 + 4.0 * eta * eta * k * k);
+        let a = f32::sqrt(f32::max(0.0, 0.5 * (a2b2 + t0)));
+        let t1 = a2b2 + cos2;
+        let t2 = 2.0 * f32::sqrt(cos2) * a;
+        let rs = (t1 - t2) / (t1 + t2);
+        let t3 = cos2 * a2b2 + sin2 * sin2;
+        let t4 = t2 * sin2;
+        let rp = rs * (t3 - t4) / (t3 + t4);
+        0.5 * (rs + rp)
+    };
+    Vec3::new(channel(0), channel(1), channel(2))
+}
@@ -0,0 +1,121 @@
+use crate::vec3::Vec3;
+
+// each octave of fbm and turbulence is this much finer than the last
+const LACUNARITY: f32 = 2.0;
+// and contributes this much less
+const GAIN: f32 = 0.5;
+
+// gradients towards the edge midpoints of a cube, from Perlin's "Improving Noise" (2002)
+const GRADIENTS: [[f32; 3]; 12] = [
+    [1.0, 1.0, 0.0], [-1.0, 1.0, 0.0], [1.0, -1.0, 0.0], [-1.0, -1.0, 0.0],
+    [1.0, 0.0, 1.0], [-1.0, 0.0, 1.0], [1.0, 0.0, -1.0], [-1.0, 0.0, -1.0],
+    [0.0, 1.0, 1.0], [0.0, -1.0, 1.0], [0.0, 1.0, -1.0], [0.0, -1.0, -1.0]
+];
+
+/// Gradient noise at `p`, smooth and roughly in [-1, 1] with features about one unit apart.
+/// The same point always gives the same value, so textures do not change between renders.
+pub fn perlin(p: &Vec3) -> f32 {
+    let cell = [f32::floor(p[0]), f32::floor(p[1]), f32::floor(p[2])];
+    let f = [p[0] - cell[0], p[1] - cell[1], p[2] - cell[2]];
+    let cell = cell.map(|c| c as i32);
+
+    // dot products with the gradients of the 8 corners around p, blended with a smooth curve
+    let corner = |dx: i32, dy: i32, dz: i32| {
+        let g = GRADIENTS[(hash(cell[0] + dx, cell[1] + dy, cell[2] + dz) % 12) as usize];
+        g[0] * (f[0] - dx as f32) + g[1] * (f[1] - dy as f32) + g[2] * (f[2] - dz as f32)
+    };
+    let [u, v, w] = f.map(fade);
+    let lerp = |a: f32, b: f32, t: f32| a + (b - a) * t;
+    lerp(
+        lerp(lerp(corner(0, 0, 0), corner(1, 0, 0), u), lerp(corner(0, 1, 0), corner(1, 1, 0), u), v),
+        lerp(lerp(corner(0, 0, 1), corner(1, 0, 1), u), lerp(corner(0, 1, 1), corner(1, 1, 1), u), v),
+        w
+    )
+}
+
+/// Fractal Brownian motion, `octaves` layers of noise each finer and fainter than the last
+pub fn fbm(p: &Vec3, octaves: u32) -> f32 {
+    octaves_sum(p, octaves, perlin)
+}
+
+/// Like `fbm` but adds up the absolute noise, giving sharp creases where the noise crosses zero
+pub fn turbulence(p: &Vec3, octaves: u32) -> f32 {
+    octaves_sum(p, octaves, |p| f32::abs(perlin(p)))
+}
+
+fn octaves_sum<F: Fn(&Vec3) -> f32>(p: &Vec3, octaves: u32, noise: F) -> f32 {
+    let mut sum = 0.0;
+    let mut frequency = 1.0;
+    let mut amplitude = 1.0;
+    for _ in 0..octaves {
+        sum += noise(&(p.clone() * frequency)) * amplitude;
+        frequency *= LACUNARITY;
+        amplitude *= GAIN;
+    }
+    sum
+}
+
+// 6t^5 - 15t^4 + 10t^3, flat at both ends so the cells join up without creases
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+// scrambles a lattice point into a pseudo random number
+fn hash(x: i32, y: i32, z: i32) -> u32 {
+    let mut h = (x as u32).wrapping_mul(0x8da6_b343)
+        ^ (y as u32).wrapping_mul(0xd816_3841)
+        ^ (z as u32).wrapping_mul(0xcb1a_b31f);
+    h ^= h >> 16;
+    h = h.wrapping_mul(0x7feb_352d);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x846c_a68b);
+    h ^ (h >> 16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    fn random_points(n: usize) -> Vec<Vec3> {
+        let mut rng = StdRng::seed_from_u64(5);
+        (0..n).map(|_| Vec3::new(rng.gen_range(-50.0..50.0), rng.gen_range(-50.0..50.0), rng.gen_range(-50.0..50.0))).collect()
+    }
+
+    #[test]
+    fn perlin_is_smooth_and_in_range() {
+        let points = random_points(100_000);
+        let values: Vec<f32> = points.iter().map(perlin).collect();
+        assert!(values.iter().all(|v| v.abs() <= 1.0), "{:?}", values.iter().cloned().fold(0.0, f32::max));
+        // it does use the range, and is not lopsided
+        assert!(values.iter().any(|&v| v > 0.6) && values.iter().any(|&v| v < -0.6));
+        let mean = values.iter().sum::<f32>() / values.len() as f32;
+        assert!(mean.abs() < 0.01, "mean {}", mean);
+
+        for (p, &v) in points.iter().zip(&values) {
+            // the same point gives the same value every time
+            assert_eq!(perlin(p), v);
+            // and a point next to it a close one
+            let next = p.clone() + Vec3::new(1e-3, -1e-3, 1e-3);
+            assert!((perlin(&next) - v).abs() < 1e-2, "{:?}", p);
+        }
+        // zero on the lattice
+        for p in [Vec3::new(0.0, 0.0, 0.0), Vec3::new(3.0, -7.0, 12.0)] {
+            assert_eq!(perlin(&p), 0.0);
+        }
+    }
+
+    #[test]
+    fn octaves_add_up() {
+        for p in random_points(10_000) {
+            // turbulence adds up absolute values, each octave is at most half the last
+            let sum = turbulence(&p, 5);
+            assert!((0.0..=2.0).contains(&sum), "{} at {:?}", sum, p);
+            assert!(fbm(&p, 5).abs() <= 2.0);
+            // one octave is plain noise
+            assert_eq!(fbm(&p, 1), perlin(&p));
+            assert_eq!(turbulence(&p, 1), perlin(&p).abs());
+            assert_eq!(fbm(&p, 0), 0.0);
+        }
+    }
+}
@@ -0,0 +1,530 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::hittable::{Material, Principled, Shape};
+use crate::texture::{Bump, ImageTexture, Texture, WrapMode};
+use crate::vec3::{Vec3, Color};
+use crate::world::World;
+
+#[derive(Debug)]
+pub enum ObjError {
+    Io(PathBuf, io::Error),
+    Parse { path: PathBuf, line: usize, message: String }
+}
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ObjError::Io(path, err) => write!(f, "{}: {}", path.display(), err),
+            ObjError::Parse { path, line, message } => write!(f, "{}:{}: {}", path.display(), line, message)
+        }
+    }
+}
+impl std::error::Error for ObjError {}
+
+/// Material as written in a .mtl file
+#[derive(Debug, Clone)]
+pub struct MtlMaterial {
+    pub name: String,
+    // diffuse color
+    pub kd: Vec3,
+    // diffuse color from an image, used instead of kd
+    pub map_kd: Option<ImageTexture>,
+    // specular color
+    pub ks: Vec3,
+    // specular exponent
+    pub ns: f32,
+    // emitted color
+    pub ke: Vec3,
+    // index of refraction
+    pub ni: f32,
+    // opacity, 1.0 is opaque
+    pub d: f32,
+    // tangent space normal map, or a height map with how far out white is
+    pub norm: Option<ImageTexture>,
+    pub bump: Option<(ImageTexture, f32)>,
+    // the physically based extension, roughness and metalness either as numbers or maps, sheen, and
+    // clearcoat with its roughness. any of them makes a principled material
+    pub pr: Option<Texture>,
+    pub pm: Option<Texture>,
+    pub ps: Option<f32>,
+    pub pc: Option<f32>,
+    pub pcr: Option<f32>
+}
+impl MtlMaterial {
+    fn new(name: &str) -> MtlMaterial {
+        MtlMaterial {
+            name: name.to_string(),
+            kd: Vec3::new(0.8, 0.8, 0.8),
+            map_kd: None,
+            ks: Color::black(),
+            ns: 0.0,
+            ke: Color::black(),
+            ni: 1.0,
+            d: 1.0,
+            norm: None,
+            bump: None,
+            pr: None,
+            pm: None,
+            ps: None,
+            pc: None,
+            pcr: None
+        }
+    }
+
+    pub fn to_material(&self) -> Material {
+        let material = self.base_material();
+        if let Some(map) = &self.norm {
+            Material::bumped(material, Bump::Normal { map: map.clone(), strength: 1.0 })
+        } else if let Some((map, scale)) = &self.bump {
+            Material::bumped(material, Bump::Height { map: Texture::Image(map.clone()), scale: *scale })
+        } else {
+            material
+        }
+    }
+
+    fn base_material(&self) -> Material {
+        if self.pr.is_some() || self.pm.is_some() || self.ps.is_some() || self.pc.is_some() || self.pcr.is_some() {
+            Material::principled(self.principled())
+        } else if max_component(&self.ke) > 0.0 {
+            Material::Emissive(self.ke.clone())
+        } else if self.d < 1.0 {
+            // see through, assume glass like, Ni is 1 when it was not given which would make it invisible
+            Material::dielectric(if self.ni > 1.0 { self.ni } else { 1.5 })
+        } else if max_component(&self.ks) > max_component(&self.kd) {
+            // mostly specular, a sharper highlight (higher Ns) means a smoother metal
+            Material::metal(self.ks.clone(), f32::sqrt(2.0 / (self.ns + 2.0)))
+        } else if let Some(map_kd) = &self.map_kd {
+            Material::lambertian(Texture::Image(map_kd.clone()))
+        } else {
+            Material::lambertian(self.kd.clone())
+        }
+    }
+
+    fn principled(&self) -> Principled {
+        let mut principled = match &self.map_kd {
+            Some(map_kd) => Principled::new(Texture::Image(map_kd.clone())),
+            None => Principled::new(self.kd.clone())
+        };
+        if let Some(pr) = &self.pr {
+            principled.roughness = pr.clone();
+        }
+        if let Some(pm) = &self.pm {
+            principled.metallic = pm.clone();
+        }
+        // Ni is 1 when it was not given, which would reflect nothing
+        if self.ni > 1.0 {
+            let f0 = ((self.ni - 1.0) / (self.ni + 1.0)).powi(2);
+            principled.specular = (f0 / 0.08).into();
+        }
+        for (value, texture) in [
+            (self.ps, &mut principled.sheen),
+            (self.pc, &mut principled.clearcoat),
+            (self.pcr, &mut principled.clearcoat_roughness)
+        ] {
+            if let Some(value) = value {
+                *texture = value.into();
+            }
+        }
+        principled.transmission = (1.0 - self.d).into();
+        principled.emission = self.ke.clone().into();
+        principled
+    }
+}
+
+/// Loads every group of an .obj file (and the .mtl files it references) into the world,
+/// one mesh per group and material
+pub fn load_obj<P: AsRef<Path>>(world: &mut World, path: P) -> Result<(), ObjError> {
+    for (shape, material) in read_obj(path.as_ref())? {
+        world.add(shape, material);
+    }
+    Ok(())
+}
+
+pub fn read_obj(path: &Path) -> Result<Vec<(Shape, Material)>, ObjError> {
+    let source = fs::read_to_string(path).map_err(|err| ObjError::Io(path.to_path_buf(), err))?;
+    let mut parser = ObjParser::new(path);
+    for (i, line) in source.lines().enumerate() {
+        parser.parse_line(line).map_err(|message| ObjError::Parse {
+            path: path.to_path_buf(),
+            line: i + 1,
+            message
+        })?;
+    }
+    parser.finish_group();
+    Ok(parser.shapes)
+}
+
+pub fn read_mtl(path: &Path) -> Result<Vec<MtlMaterial>, ObjError> {
+    let source = fs::read_to_string(path).map_err(|err| ObjError::Io(path.to_path_buf(), err))?;
+    let mut materials: Vec<MtlMaterial> = vec![];
+    for (i, line) in source.lines().enumerate() {
+        parse_mtl_line(line, path.parent().unwrap_or(Path::new("")), &mut materials).map_err(|message| ObjError::Parse {
+            path: path.to_path_buf(),
+            line: i + 1,
+            message
+        })?;
+    }
+    Ok(materials)
+}
+
+fn parse_mtl_line(line: &str, dir: &Path, materials: &mut Vec<MtlMaterial>) -> Result<(), String> {
+    let mut words = line.split_whitespace();
+    let keyword = match words.next() {
+        Some(keyword) if !keyword.starts_with('#') => keyword,
+        _ => return Ok(())
+    };
+    let args: Vec<&str> = words.collect();
+
+    if keyword == "newmtl" {
+        let name = args.first().ok_or("newmtl without a name")?;
+        materials.push(MtlMaterial::new(name));
+        return Ok(());
+    }
+
+    let material = match materials.last_mut() {
+        Some(material) => material,
+        None if [
+            "Kd", "map_Kd", "Ks", "Ns", "Ke", "Ni", "d", "Tr", "norm", "bump", "map_Bump",
+            "Pr", "map_Pr", "Pm", "map_Pm", "Ps", "Pc", "Pcr"
+        ].contains(&keyword) => {
+            return Err(format!("{} before any newmtl", keyword));
+        },
+        // everything else is ignored anyway
+        None => return Ok(())
+    };
+    match keyword {
+        "Kd" => material.kd = parse_vec3(&args)?,
+        "map_Kd" => material.map_kd = Some(load_map(keyword, &args, dir, true)?),
+        "norm" => material.norm = Some(load_map(keyword, &args, dir, false)?),
+        "bump" | "map_Bump" => {
+            // -bm is the only option understood, how far out white is
+            let (scale, args) = match args.first() {
+                Some(&"-bm") => (parse_f32(&args, 1)?, &args[2..]),
+                _ => (1.0, &args[..])
+            };
+            material.bump = Some((load_map(keyword, args, dir, false)?, scale));
+        },
+        "Ks" => material.ks = parse_vec3(&args)?,
+        "Ke" => material.ke = parse_vec3(&args)?,
+        "Ns" => material.ns = parse_f32(&args, 0)?,
+        "Ni" => material.ni = parse_f32(&args, 0)?,
+        "d" => material.d = parse_f32(&args, 0)?,
+        // transparency, the inverse of d
+        "Tr" => material.d = 1.0 - parse_f32(&args, 0)?,
+        "Pr" => material.pr = Some(parse_f32(&args, 0)?.into()),
+        "map_Pr" => material.pr = Some(Texture::Image(load_map(keyword, &args, dir, false)?)),
+        "Pm" => material.pm = Some(parse_f32(&args, 0)?.into()),
+        "map_Pm" => material.pm = Some(Texture::Image(load_map(keyword, &args, dir, false)?)),
+        "Ps" => material.ps = Some(parse_f32(&args, 0)?),
+        "Pc" => material.pc = Some(parse_f32(&args, 0)?),
+        "Pcr" => material.pcr = Some(parse_f32(&args, 0)?),
+        _ => ()
+    }
+    Ok(())
+}
+
+struct ObjParser {
+    dir: PathBuf,
+    positions: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    uvs: Vec<(f32, f32)>,
+    materials: HashMap<String, MtlMaterial>,
+    current_material: Material,
+
+    // the group being built, vertices are deduplicated on their (position, uv, normal) indices
+    vertex_ids: HashMap<(usize, Option<usize>, Option<usize>), usize>,
+    group_vertices: Vec<(usize, Option<usize>, Option<usize>)>,
+    group_indices: Vec<[usize; 3]>,
+
+    shapes: Vec<(Shape, Material)>
+}
+impl ObjParser {
+    fn new(path: &Path) -> ObjParser {
+        ObjParser {
+            dir: path.parent().map(Path::to_path_buf).unwrap_or_default(),
+            positions: vec![],
+            normals: vec![],
+            uvs: vec![],
+            materials: HashMap::new(),
+            current_material: MtlMaterial::new("default").to_material(),
+            vertex_ids: HashMap::new(),
+            group_vertices: vec![],
+            group_indices: vec![],
+            shapes: vec![]
+        }
+    }
+
+    fn parse_line(&mut self, line: &str) -> Result<(), String> {
+        let mut words = line.split_whitespace();
+        let keyword = match words.next() {
+            Some(keyword) if !keyword.starts_with('#') => keyword,
+            _ => return Ok(())
+        };
+        let args: Vec<&str> = words.collect();
+
+        match keyword {
+            "v" => self.positions.push(parse_vec3(&args)?),
+            "vn" => self.normals.push(parse_vec3(&args)?),
+            "vt" => {
+                let u = parse_f32(&args, 0)?;
+                let v = if args.len() > 1 { parse_f32(&args, 1)? } else { 0.0 };
+                self.uvs.push((u, v));
+            },
+            "f" => self.parse_face(&args)?,
+            "g" | "o" => self.finish_group(),
+            "usemtl" => {
+                let name = args.first().ok_or("usemtl without a material name")?;
+                let material = self.materials.get(*name)
+                    .ok_or(format!("unknown material '{}'", name))?
+                    .to_material();
+                self.finish_group();
+                self.current_material = material;
+            },
+            "mtllib" => {
+                if args.is_empty() {
+                    return Err("mtllib without a file name".to_string());
+                }
+                // file names may contain spaces
+                let path = self.dir.join(args.join(" "));
+                let materials = read_mtl(&path).map_err(|err| err.to_string())?;
+                for material in materials {
+                    self.materials.insert(material.name.clone(), material);
+                }
+            },
+            // smoothing groups, lines, points and friends are not supported
+            _ => ()
+        }
+        Ok(())
+    }
+
+    fn parse_face(&mut self, args: &[&str]) -> Result<(), String> {
+        if args.len() < 3 {
+            return Err(format!("face needs at least 3 vertices, got {}", args.len()));
+        }
+        let mut corners = Vec::with_capacity(args.len());
+        for arg in args {
+            let corner = self.parse_face_vertex(arg)?;
+            let next_id = self.group_vertices.len();
+            let id = *self.vertex_ids.entry(corner).or_insert(next_id);
+            if id == next_id {
+                self.group_vertices.push(corner);
+            }
+            corners.push(id);
+        }
+        // polygons are split into a fan of triangles around the first corner
+        for i in 1..corners.len() - 1 {
+            self.group_indices.push([corners[0], corners[i], corners[i + 1]]);
+        }
+        Ok(())
+    }
+
+    /// Parses `v`, `v/vt`, `v//vn` or `v/vt/vn` into zero based indices
+    fn parse_face_vertex(&self, arg: &str) -> Result<(usize, Option<usize>, Option<usize>), String> {
+        let mut parts = arg.split('/');
+        let position = match parts.next() {
+            Some(p) if !p.is_empty() => resolve_index(p, self.positions.len(), "vertex")?,
+            _ => return Err(format!("face vertex '{}' has no position", arg))
+        };
+        let uv = match parts.next() {
+            Some(t) if !t.is_empty() => Some(resolve_index(t, self.uvs.len(), "texture coordinate")?),
+            _ => None
+        };
+        let normal = match parts.next() {
+            Some(n) if !n.is_empty() => Some(resolve_index(n, self.normals.len(), "normal")?),
+            _ => None
+        };
+        Ok((position, uv, normal))
+    }
+
+    /// Turns the faces read since the last group change into a mesh
+    fn finish_group(&mut self) {
+        if self.group_indices.is_empty() {
+            return;
+        }
+
+        let positions = self.group_vertices.iter().map(|(p, _, _)| self.positions[*p].clone()).collect();
+        // meshes have normals and uvs on every vertex or not at all
+        let normals = if self.group_vertices.iter().all(|(_, _, n)| n.is_some()) {
+            self.group_vertices.iter().map(|(_, _, n)| self.normals[n.unwrap()].clone()).collect()
+        } else {
+            vec![]
+        };
+        let uvs = if self.group_vertices.iter().all(|(_, t, _)| t.is_some()) {
+            self.group_vertices.iter().map(|(_, t, _)| self.uvs[t.unwrap()]).collect()
+        } else {
+            vec![]
+        };
+        let indices = std::mem::take(&mut self.group_indices);
+
+        self.shapes.push((
+            // resolve_index has already checked every index
+            Shape::mesh(positions, normals, uvs, indices).expect("obj groups are always valid meshes"),
+            self.current_material.clone()
+        ));
+        self.group_vertices.clear();
+        self.vertex_ids.clear();
+    }
+}
+
+// an image named by the rest of the line, options like -s are not supported
+fn load_map(keyword: &str, args: &[&str], dir: &Path, srgb: bool) -> Result<ImageTexture, String> {
+    if args.is_empty() {
+        return Err(format!("{} without a file name", keyword));
+    }
+    let path = dir.join(args.join(" "));
+    ImageTexture::load(&path, WrapMode::Repeat, srgb).map_err(|err| format!("{}: {}", path.display(), err))
+}
+
+/// OBJ indices start at 1, negative ones count backwards from the last element read so far
+fn resolve_index(word: &str, len: usize, what: &str) -> Result<usize, String> {
+    let i: i64 = word.parse().map_err(|_| format!("invalid {} index '{}'", what, word))?;
+    let resolved = if i > 0 { i - 1 } else { len as i64 + i };
+    if i == 0 || resolved < 0 || resolved >= len as i64 {
+        return Err(format!("{} index {} out of range, {} defined so far", what, i, len));
+    }
+    Ok(resolved as usize)
+}
+
+fn parse_f32(args: &[&str], i: usize) -> Result<f32, String> {
+    let word = args.get(i).ok_or(format!("expected at least {} numbers, got {}", i + 1, args.len()))?;
+    word.parse().map_err(|_| format!("invalid number '{}'", word))
+}
+
+fn max_component(v: &Vec3) -> f32 {
+    f32::max(v[0], f32::max(v[1], v[2]))
+}
+
+fn parse_vec3(args: &[&str]) -> Result<Vec3, String> {
+    Ok(Vec3::new(parse_f32(args, 0)?, parse_f32(args, 1)?, parse_f32(args, 2)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittable::Mesh;
+
+    // writes the files into a directory of their own and reads the first one as an obj
+    fn read(name: &str, files: &[(&str, &str)]) -> Result<Vec<(Shape, Material)>, ObjError> {
+        let dir = std::env::temp_dir().join(format!("raytracer_obj_{}_{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for (file, source) in files {
+            fs::write(dir.join(file), source).unwrap();
+        }
+        let result = read_obj(&dir.join(files[0].0));
+        fs::remove_dir_all(&dir).unwrap();
+        result
+    }
+
+    fn mesh(shape: &Shape) -> &Mesh {
+        match shape {
+            Shape::Mesh(mesh) => mesh,
+            _ => panic!("expected a mesh")
+        }
+    }
+
+    const SQUARE: &str = "v 0 0 0\nv 1 0 0\nv 1 1 0\nv 0 1 0\n";
+
+    #[test]
+    fn bad_face_index_reports_its_line() {
+        let err = read("bad_index", &[("a.obj", "v 0 0 0\nv 1 0 0\nv 0 1 0\n\nf 1 2 4\n")]).err().unwrap();
+        match err {
+            ObjError::Parse { line, message, .. } => {
+                assert_eq!(line, 5);
+                assert_eq!(message, "vertex index 4 out of range, 3 defined so far");
+            },
+            other => panic!("expected a parse error, got {}", other)
+        }
+    }
+
+    #[test]
+    fn negative_indices_count_back_from_the_last_vertex() {
+        let shapes = read("negative", &[("a.obj", &format!("{}f -4 -3 -2\n", SQUARE))]).unwrap();
+        let mesh = mesh(&shapes[0].0);
+        assert_eq!(mesh.indices, vec![[0, 1, 2]]);
+        assert_eq!(mesh.positions[2][0], 1.0);
+        assert_eq!(mesh.positions[2][1], 1.0);
+    }
+
+    #[test]
+    fn polygons_become_a_fan_of_triangles() {
+        let source = format!("{}v 0.5 2 0\nf 1 2 3 5 4\n", SQUARE);
+        let shapes = read("fan", &[("a.obj", &source)]).unwrap();
+        assert_eq!(mesh(&shapes[0].0).indices, vec![[0, 1, 2], [0, 2, 3], [0, 3, 4]]);
+    }
+
+    #[test]
+    fn shared_corners_are_stored_once() {
+        // the last face uses vertex 1 with another uv, which makes it a different vertex
+        let source = format!("{}vt 0 0\nvt 1 1\nf 1/1 2/1 3/1\nf 1/1 3/1 4/1\nf 1/2 2/1 4/1\n", SQUARE);
+        let shapes = read("dedup", &[("a.obj", &source)]).unwrap();
+        let mesh = mesh(&shapes[0].0);
+        assert_eq!(mesh.positions.len(), 5);
+        assert_eq!(mesh.indices, vec![[0, 1, 2], [0, 2, 3], [4, 1, 3]]);
+    }
+
+    #[test]
+    fn groups_and_materials_make_separate_meshes() {
+        let source = format!("{}f 1 2 3\ng second\nf 1 3 4\nf 2 3 4\n", SQUARE);
+        let shapes = read("groups", &[("a.obj", &source)]).unwrap();
+        assert_eq!(shapes.len(), 2);
+        assert_eq!(mesh(&shapes[1].0).indices.len(), 2);
+    }
+
+    #[test]
+    fn material_keywords_need_a_newmtl() {
+        let err = read("no_newmtl", &[("a.obj", "mtllib a.mtl\n"), ("a.mtl", "# colors\nillum 2\nKd 1 0 0\n")]).err().unwrap();
+        assert!(err.to_string().ends_with("a.mtl:3: Kd before any newmtl"), "{}", err);
+    }
+
+    #[test]
+    fn mtl_materials_map_to_materials() {
+        let mtl = "\
+newmtl matte\nKd 0.5 0.2 0.1\n\
+newmtl shiny\nKd 0.1 0.1 0.1\nKs 0.9 0.9 0.9\nNs 198\n\
+newmtl lamp\nKe 4 4 4\n\
+newmtl glass\nNi 1.3\nd 0.2\n\
+newmtl window\nTr 0.9\n\
+newmtl pbr\nKd 0.8 0.2 0.2\nPm 1\nPr 0.3\n";
+        let mut source = format!("mtllib a.mtl\n{}", SQUARE);
+        for name in ["matte", "shiny", "lamp", "glass", "window", "pbr"] {
+            source += &format!("usemtl {}\nf 1 2 3\n", name);
+        }
+        let shapes = read("materials", &[("a.obj", &source), ("a.mtl", mtl)]).unwrap();
+        let materials: Vec<&Material> = shapes.iter().map(|(_, material)| material).collect();
+        assert!(matches!(materials[0], Material::Lambertian(Texture::Constant(kd)) if kd[0] == 0.5));
+        assert!(matches!(materials[1], Material::Metal { fuzz, .. } if (fuzz - 0.1).abs() < 1e-6));
+        assert!(matches!(materials[2], Material::Emissive(ke) if ke[1] == 4.0));
+        assert!(matches!(materials[3], Material::Dielectric { ior, .. } if *ior == 1.3));
+        // no Ni, plain glass
+        assert!(matches!(materials[4], Material::Dielectric { ior, .. } if *ior == 1.5));
+        assert!(matches!(materials[5], Material::Principled(_)));
+    }
+
+    #[test]
+    fn pbr_keywords_map_to_principled() {
+        let mtl = "newmtl pbr\nKd 0.8 0.2 0.2\nKe 0 0 2\nNi 1.5\nd 0.75\nPr 0.3\nPm 0.9\nPs 0.4\nPc 0.6\nPcr 0.2\n";
+        let source = format!("mtllib a.mtl\n{}usemtl pbr\nf 1 2 3\n", SQUARE);
+        let shapes = read("pbr", &[("a.obj", &source), ("a.mtl", mtl)]).unwrap();
+        let principled = match &shapes[0].1 {
+            Material::Principled(principled) => principled,
+            other => panic!("expected a principled material, got {:?}", other)
+        };
+        let constant = |texture: &Texture| match texture {
+            Texture::Constant(value) => value.clone(),
+            other => panic!("expected a constant, got {:?}", other)
+        };
+        assert_eq!(constant(&principled.base_color), Vec3::new(0.8, 0.2, 0.2));
+        assert_eq!(constant(&principled.roughness)[0], 0.3);
+        assert_eq!(constant(&principled.metallic)[0], 0.9);
+        assert_eq!(constant(&principled.sheen)[0], 0.4);
+        assert_eq!(constant(&principled.clearcoat)[0], 0.6);
+        assert_eq!(constant(&principled.clearcoat_roughness)[0], 0.2);
+        assert_eq!(constant(&principled.transmission)[0], 0.25);
+        assert_eq!(constant(&principled.emission), Vec3::new(0.0, 0.0, 2.0));
+        // an ior of 1.5 reflects 4%, which is half of the most `specular` allows
+        assert!((constant(&principled.specular)[0] - 0.5).abs() < 1e-6);
+    }
+}
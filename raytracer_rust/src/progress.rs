@@ -0,0 +1,150 @@
+use std::fmt;
+use std::io::{self, IsTerminal, Write};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// width of the bar in characters, not counting the brackets
+const BAR_WIDTH: usize = 40;
+// the bar is redrawn at most this often so printing does not slow down the render
+const BAR_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How a render tells how far along it is, everything goes to stderr
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Progress {
+    // a bar with percentage and time left that redraws itself in place
+    Bar,
+    // a line every `interval`, for logs and pipes where the bar would be a mess
+    Lines { interval: Duration },
+    Silent
+}
+impl Progress {
+    /// A bar when stderr is a terminal and a line every 5 seconds otherwise
+    pub fn auto() -> Progress {
+        if io::stderr().is_terminal() {
+            Progress::Bar
+        } else {
+            Progress::Lines { interval: Duration::from_secs(5) }
+        }
+    }
+}
+
+/// Keeps track of a running render and prints its progress the way `Progress` says.
+/// Shared between the render threads, `advance` may be called from any of them.
+pub struct ProgressReporter {
+    progress: Progress,
+    total: usize,
+    start: Instant,
+    // work done so far and when progress was last printed
+    state: Mutex<(usize, Option<Instant>)>
+}
+impl ProgressReporter {
+    /// Starts the clock, `total` is how much work there is in whatever unit `advance` is called with
+    pub fn new(progress: Progress, total: usize) -> ProgressReporter {
+        ProgressReporter { progress, total, start: Instant::now(), state: Mutex::new((0, None)) }
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    /// Marks `amount` more work as done
+    pub fn advance(&self, amount: usize) {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            // a thread panicked while printing, the render is lost anyway
+            Err(_) => return
+        };
+        state.0 += amount;
+        let done = state.0;
+
+        let interval = match self.progress {
+            Progress::Silent => return,
+            Progress::Bar => BAR_INTERVAL,
+            Progress::Lines { interval } => interval
+        };
+        let now = Instant::now();
+        if state.1.is_some_and(|last| now - last < interval) && done < self.total {
+            return;
+        }
+        state.1 = Some(now);
+        self.print(done);
+    }
+
+    /// Ends the bar so whatever is printed next starts on its own line
+    pub fn finish(&self) {
+        if self.progress == Progress::Bar {
+            eprintln!();
+        }
+    }
+
+    fn print(&self, done: usize) {
+        let fraction = if self.total == 0 { 1.0 } else { done as f64 / self.total as f64 };
+        let elapsed = self.elapsed();
+        // assumes the rest goes as fast as what has been done so far
+        let eta = if fraction > 0.0 {
+            format_duration(elapsed.mul_f64((1.0 - fraction) / fraction))
+        } else {
+            "?".to_string()
+        };
+
+        match self.progress {
+            Progress::Bar => {
+                let filled = (fraction * BAR_WIDTH as f64) as usize;
+                eprint!(
+                    "\r[{}{}] {:5.1}%  {} elapsed, {} left ",
+                    "#".repeat(filled),
+                    "-".repeat(BAR_WIDTH - filled),
+                    fraction * 100.0,
+                    format_duration(elapsed),
+                    eta
+                );
+                let _ = io::stderr().flush();
+            },
+            Progress::Lines { .. } => eprintln!(
+                "rendered {:5.1}% after {}, about {} left",
+                fraction * 100.0,
+                format_duration(elapsed),
+                eta
+            ),
+            Progress::Silent => ()
+        }
+    }
+}
+
+/// What a finished render took
+#[derive(Debug, Clone, PartialEq)]
+pub struct RenderStats {
+    pub wall_time: Duration,
+    // camera rays, bounces and shadow rays
+    pub rays: u64,
+    pub samples_per_pixel: u32,
+    pub width: i32,
+    pub height: i32
+}
+impl RenderStats {
+    pub fn rays_per_second(&self) -> f64 {
+        let seconds = self.wall_time.as_secs_f64();
+        if seconds > 0.0 { self.rays as f64 / seconds } else { 0.0 }
+    }
+}
+impl fmt::Display for RenderStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "resolution        {}x{}", self.width, self.height)?;
+        writeln!(f, "samples per pixel {}", self.samples_per_pixel)?;
+        writeln!(f, "wall time         {}", format_duration(self.wall_time))?;
+        writeln!(f, "rays traced       {}", self.rays)?;
+        write!(f, "rays per second   {:.2}M", self.rays_per_second() / 1e6)
+    }
+}
+
+/// 1h02m, 3m20s or 12.5s
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs_f64();
+    if seconds >= 3600.0 {
+        format!("{}h{:02}m", (seconds / 3600.0) as u64, (seconds % 3600.0 / 60.0) as u64)
+    } else if seconds >= 60.0 {
+        format!("{}m{:02}s", (seconds / 60.0) as u64, (seconds % 60.0) as u64)
+    } else {
+        format!("{:.1}s", seconds)
+    }
+}
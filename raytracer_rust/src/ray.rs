@@ -15,14 +15,14 @@ impl Ray {
     }
 
     pub fn scale(&self, distance: f32) -> Vec3 {
-        return self.get_origin() + self.get_direction() * distance;
+        self.get_origin() + self.get_direction() * distance
     }
 
     pub fn get_origin(&self) -> Vec3 {
-        return self.origin.clone();
+        self.origin.clone()
     }
 
     pub fn get_direction(&self) -> Vec3 {
-        return self.direction.clone();
+        self.direction.clone()
     }
 }
\ No newline at end of file
@@ -0,0 +1,1255 @@
+//! Scenes described in json, for example
+//!
+//! ```json
+//! {
+//!     "camera": { "look_from": [0, 0, 10], "look_at": [0, 0, -1], "vfov": 11.7, "width": 512, "height": 288 },
+//!     "render": { "samples_per_pixel": 20, "max_depth": 10, "tone_mapping": "aces" },
+//!     "materials": {
+//!         "red": { "type": "lambertian", "albedo": [1, 0, 0] },
+//!         "tiles": { "type": "lambertian", "albedo": { "type": "checker", "even": [1, 1, 1], "odd": [0.1, 0.1, 0.1], "scale": 2, "space": "world" } },
+//!         "earth": { "type": "lambertian", "albedo": { "type": "image", "path": "textures/earth.jpg", "wrap": "repeat" } },
+//!         "marble": { "type": "lambertian", "albedo": { "type": "marble", "low": [0.2, 0.2, 0.25], "high": [1, 1, 1], "scale": 2, "octaves": 6, "space": "object" } },
+//!         "bricks": { "type": "lambertian", "albedo": [0.6, 0.3, 0.2], "normal_map": { "path": "textures/bricks_normal.png", "strength": 1 } },
+//!         "gold": { "type": "conductor", "preset": "gold", "roughness": 0.3 },
+//!         "frosted": { "type": "dielectric", "ior": 1.5, "roughness": 0.2 },
+//!         "hammered": { "type": "metal", "albedo": [0.9, 0.9, 0.9], "bump_map": { "height": { "type": "fbm", "scale": 20 }, "scale": 0.002 } },
+//!         "car_paint": { "type": "principled", "base_color": [0.6, 0.05, 0.05], "metallic": 0.3, "roughness": 0.4, "clearcoat": 1 },
+//!         "helmet": { "type": "principled", "base_color": { "type": "image", "path": "textures/helmet_color.png" }, "metallic_roughness": { "path": "textures/helmet_mr.png" } }
+//!     },
+//!     "sun": { "type": "sphere", "center": [-10, 8, 5], "radius": 2, "radiance": [50, 48, 45] },
+//!     "environment": { "path": "sky.hdr", "rotation": 90, "intensity": 1.5 },
+//!     "sky": { "elevation": 30, "azimuth": 120, "turbidity": 3 },
+//!     "lights": [
+//!         { "type": "point", "position": [0, 2, 0], "color": [1, 0.9, 0.8], "intensity": 5 },
+//!         { "type": "directional", "direction": [0, -1, -1], "intensity": 2, "angular_diameter": 0.53 },
+//!         { "type": "spot", "position": [0, 3, -2], "direction": [0, -1, 0], "intensity": 20, "cone_angle": 30, "falloff": 5 }
+//!     ],
+//!     "objects": [
+//!         { "shape": { "type": "sphere", "center": [0, 0, -3], "radius": 0.5 }, "material": "red" },
+//!         { "shape": { "type": "plane", "normal": [0, 1, 0], "d": -1.2 }, "material": { "type": "lambertian", "albedo": [1, 1, 1] } },
+//!         { "shape": { "type": "quad", "corner": [-1, 2, -3], "u": [2, 0, 0], "v": [0, 0, 1] }, "material": { "type": "emissive", "color": [4, 4, 4] } },
+//!         { "obj": "models/teapot.obj" }
+//!     ]
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Map, Value};
+
+use crate::camera::{Camera, CameraError};
+use crate::hittable::{Hittable, Material, MeshError, Principled, Shape};
+use crate::environment::Environment;
+use crate::light::Light;
+use crate::obj;
+use crate::sky::Sky;
+use crate::texture::{Bump, ImageTexture, Pattern, Space, Texture, WrapMode};
+use crate::tonemap::{Operator, ToneMapping};
+use crate::vec3::Vec3;
+use crate::world::{World, SUN_RADIANCE};
+
+#[derive(Debug)]
+pub struct SceneError {
+    // where in the file the problem is, like `objects[2].shape.radius`
+    pub key: String,
+    pub message: String
+}
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.key.is_empty() {
+            write!(f, "{}", self.message)
+        } else {
+            write!(f, "{}: {}", self.key, self.message)
+        }
+    }
+}
+impl std::error::Error for SceneError {}
+
+/// Everything needed to render an image
+pub struct Scene {
+    pub world: World,
+    pub camera: Camera,
+    pub tone_mapping: ToneMapping
+}
+
+pub fn load_scene<P: AsRef<Path>>(path: P) -> Result<Scene, SceneError> {
+    let path = path.as_ref();
+    let source = fs::read_to_string(path).map_err(|err| SceneError {
+        key: String::new(),
+        message: format!("could not read the file: {}", err)
+    })?;
+    // obj files are looked up next to the scene file
+    let dir = path.parent().unwrap_or(Path::new(""));
+    parse_scene(&source, dir)
+}
+
+pub fn parse_scene(source: &str, dir: &Path) -> Result<Scene, SceneError> {
+    let value: Value = serde_json::from_str(source).map_err(|err| SceneError {
+        key: String::new(),
+        message: format!("invalid json: {}", err)
+    })?;
+    let root = Node { value: &value, key: String::new() };
+    root.allow_keys(&["camera", "render", "materials", "sun", "environment", "sky", "lights", "objects"])?;
+
+    let camera = parse_camera(&root.get("camera")?)?;
+
+    let mut world = World::new();
+    let mut tone_mapping = ToneMapping::default();
+    if let Some(render) = root.get_opt("render") {
+        render.allow_keys(&["samples_per_pixel", "max_depth", "exposure", "tone_mapping"])?;
+        if let Some(spp) = render.get_opt("samples_per_pixel") {
+            world.set_samples_per_pixel(spp.positive_u32()?);
+        }
+        if let Some(max_depth) = render.get_opt("max_depth") {
+            world.set_max_depth(max_depth.positive_u32()?);
+        }
+        if let Some(exposure) = render.get_opt("exposure") {
+            tone_mapping.exposure = exposure.f32()?;
+        }
+        if let Some(operator) = render.get_opt("tone_mapping") {
+            tone_mapping.operator = parse_operator(&operator)?;
+        }
+    }
+
+    let mut materials = HashMap::new();
+    if let Some(node) = root.get_opt("materials") {
+        for (name, _) in node.object()? {
+            materials.insert(name.clone(), parse_material(&node.get(name)?, dir)?);
+        }
+    }
+
+    if let Some(sky) = root.get_opt("sky") {
+        if root.get_opt("environment").is_some() {
+            return Err(sky.error("a scene can have either a sky or an environment, not both"));
+        }
+        sky.allow_keys(&["elevation", "azimuth", "turbidity", "intensity"])?;
+        let azimuth = match sky.get_opt("azimuth") {
+            Some(azimuth) => azimuth.f32()?,
+            None => 0.0
+        };
+        let turbidity = match sky.get_opt("turbidity") {
+            Some(turbidity) => turbidity.range(1.7, 10.0)?,
+            None => 3.0
+        };
+        let mut parsed = Sky::new(sky.get("elevation")?.angle(0.0, 90.0)?, azimuth, turbidity);
+        if let Some(intensity) = sky.get_opt("intensity") {
+            parsed.intensity = intensity.non_negative_f32()?;
+        }
+        // replaces the default sun, a sun key can still bring one back
+        world.set_sky(parsed);
+    }
+
+    // without a sun key the default sun is kept, null turns it off
+    match root.get_opt("sun") {
+        Some(sun) if sun.value.is_null() => world.remove_sun(),
+        Some(sun) => {
+            // a shape with an optional radiance next to its keys
+            let mut shape = sun.object()?.clone();
+            let radiance = match shape.remove("radiance") {
+                Some(_) => sun.get("radiance")?.non_negative_vec3()?,
+                None => Vec3::new(SUN_RADIANCE, SUN_RADIANCE, SUN_RADIANCE)
+            };
+            let shape = Value::Object(shape);
+            let shape = parse_shape(&Node { value: &shape, key: sun.key.clone() })?;
+            world.set_sun(shape, radiance).map_err(|err| sun.error(&err.to_string()))?;
+        },
+        None => ()
+    }
+
+    if let Some(environment) = root.get_opt("environment") {
+        environment.allow_keys(&["path", "rotation", "intensity"])?;
+        let rotation = match environment.get_opt("rotation") {
+            Some(rotation) => rotation.f32()?,
+            None => 0.0
+        };
+        let intensity = match environment.get_opt("intensity") {
+            Some(intensity) => intensity.non_negative_f32()?,
+            None => 1.0
+        };
+        // relative to the scene file like obj files
+        let path = dir.join(environment.get("path")?.str()?);
+        let environment = Environment::load(&path, rotation, intensity)
+            .map_err(|err| path_error(&environment, "path", format!("{}: {}", path.display(), err)))?;
+        world.set_environment(environment);
+    }
+
+    if let Some(lights) = root.get_opt("lights") {
+        for light in lights.array()? {
+            world.add_light(parse_light(&light)?);
+        }
+    }
+
+    if let Some(objects) = root.get_opt("objects") {
+        for object in objects.array()? {
+            if let Some(path) = object.get_opt("obj") {
+                object.allow_keys(&["obj"])?;
+                let path = dir.join(path.str()?);
+                obj::load_obj(&mut world, &path).map_err(|err| path_error(&object, "obj", err))?;
+                continue;
+            }
+
+            object.allow_keys(&["shape", "material"])?;
+            let shape = parse_shape(&object.get("shape")?)?;
+            let material_node = object.get("material")?;
+            let material = match material_node.value {
+                Value::String(name) => materials.get(name)
+                    .ok_or_else(|| material_node.error(&format!("no material named '{}'", name)))?
+                    .clone(),
+                _ => parse_material(&material_node, dir)?
+            };
+            world.add(shape, material);
+        }
+    }
+
+    Ok(Scene { world, camera, tone_mapping })
+}
+
+fn path_error<E: fmt::Display>(node: &Node, key: &str, err: E) -> SceneError {
+    SceneError { key: node.child_key(key), message: err.to_string() }
+}
+
+fn parse_camera(node: &Node) -> Result<Camera, SceneError> {
+    node.allow_keys(&["look_from", "look_at", "up", "vfov", "width", "height", "aperture", "focus_distance"])?;
+    let up = match node.get_opt("up") {
+        Some(up) => up.vec3()?,
+        None => Vec3::new(0.0, 1.0, 0.0)
+    };
+    let mut camera = Camera::new(
+        node.get("look_from")?.vec3()?,
+        node.get("look_at")?.vec3()?,
+        up,
+        node.get("vfov")?.f32()?,
+        node.get("width")?.positive_u32()? as i32,
+        node.get("height")?.positive_u32()? as i32
+    ).map_err(|err| {
+        // blame the key that has to change, up may have been left out
+        let key = match err {
+            CameraError::NoViewDirection => "look_at",
+            CameraError::UpAlongView if node.get_opt("up").is_some() => "up",
+            CameraError::UpAlongView => "look_at",
+            CameraError::FieldOfView(_) => "vfov",
+            CameraError::EmptyImage { width, .. } if width <= 0 => "width",
+            CameraError::EmptyImage { .. } => "height"
+        };
+        path_error(node, key, err)
+    })?;
+    if let Some(aperture) = node.get_opt("aperture") {
+        let focus_dist = node.get("focus_distance")?.f32()?;
+        camera.set_lens(aperture.f32()?, focus_dist);
+    }
+    Ok(camera)
+}
+
+fn parse_operator(node: &Node) -> Result<Operator, SceneError> {
+    let (name, object) = match node.value {
+        Value::String(name) => (name.as_str(), None),
+        _ => (node.get("type")?.str()?, Some(node))
+    };
+    let operator = match name {
+        "clamp" => Operator::Clamp,
+        "reinhard" => Operator::Reinhard,
+        "aces" => Operator::Aces,
+        "extended_reinhard" => {
+            let object = object.ok_or_else(|| node.error("extended_reinhard needs a white point, use { \"type\": \"extended_reinhard\", \"white\": 4 }"))?;
+            object.allow_keys(&["type", "white"])?;
+            return Ok(Operator::ExtendedReinhard { white: object.get("white")?.positive_f32()? });
+        },
+        other => return Err(node.error(&format!("unknown tone mapping '{}', expected clamp, reinhard, extended_reinhard or aces", other)))
+    };
+    if let Some(object) = object {
+        object.allow_keys(&["type"])?;
+    }
+    Ok(operator)
+}
+
+fn parse_light(node: &Node) -> Result<Light, SceneError> {
+    let color = match node.get_opt("color") {
+        Some(color) => color.non_negative_vec3()?,
+        None => Vec3::new(1.0, 1.0, 1.0)
+    };
+    let intensity = match node.get_opt("intensity") {
+        Some(intensity) => intensity.non_negative_f32()?,
+        None => 1.0
+    };
+    let kind = node.get("type")?;
+    match kind.str()? {
+        "point" => {
+            node.allow_keys(&["type", "position", "color", "intensity"])?;
+            Ok(Light::point(node.get("position")?.vec3()?, color, intensity))
+        },
+        "directional" => {
+            node.allow_keys(&["type", "direction", "color", "intensity", "angular_diameter"])?;
+            let angular_diameter = match node.get_opt("angular_diameter") {
+                Some(angle) => angle.angle(0.0, 180.0)?,
+                None => 0.0
+            };
+            Ok(Light::directional(node.get("direction")?.direction()?, color, intensity, angular_diameter))
+        },
+        "spot" => {
+            node.allow_keys(&["type", "position", "direction", "color", "intensity", "cone_angle", "falloff"])?;
+            let cone_angle = node.get("cone_angle")?.angle(0.0, 180.0)?;
+            let falloff = match node.get_opt("falloff") {
+                Some(falloff) => falloff.angle(0.0, cone_angle)?,
+                None => 0.0
+            };
+            Ok(Light::spot(
+                node.get("position")?.vec3()?,
+                node.get("direction")?.direction()?,
+                color,
+                intensity,
+                cone_angle,
+                falloff
+            ))
+        },
+        other => Err(kind.error(&format!("unknown light type '{}'", other)))
+    }
+}
+
+fn parse_material(node: &Node, dir: &Path) -> Result<Material, SceneError> {
+    // normal and bump maps go on any material, next to its own keys
+    let allow = |keys: &[&str]| node.allow_keys(&[keys, &["normal_map", "bump_map"]].concat());
+    let kind = node.get("type")?;
+    let material = match kind.str()? {
+        // a white light, the same as emissive with a color of 1
+        "light" => {
+            allow(&["type"])?;
+            Ok(Material::Emissive(Vec3::new(1.0, 1.0, 1.0)))
+        },
+        "emissive" => {
+            allow(&["type", "color"])?;
+            Ok(Material::Emissive(node.get("color")?.non_negative_vec3()?))
+        },
+        "lambertian" => {
+            allow(&["type", "albedo"])?;
+            Ok(Material::Lambertian(parse_texture(&node.get("albedo")?, dir, true)?))
+        },
+        "metal" => {
+            allow(&["type", "albedo", "fuzz"])?;
+            let fuzz = match node.get_opt("fuzz") {
+                Some(fuzz) => fuzz.f32()?,
+                None => 0.0
+            };
+            Ok(Material::metal(parse_texture(&node.get("albedo")?, dir, true)?, fuzz))
+        },
+        "conductor" => {
+            allow(&["type", "preset", "eta", "k", "roughness"])?;
+            let roughness = match node.get_opt("roughness") {
+                Some(roughness) => roughness.range(0.0, 1.0)?,
+                None => 0.0
+            };
+            // either a known metal or its index of refraction
+            match node.get_opt("preset") {
+                Some(preset) => {
+                    if node.get_opt("eta").is_some() || node.get_opt("k").is_some() {
+                        return Err(preset.error("a conductor has either a preset or eta and k, not both"));
+                    }
+                    match preset.str()? {
+                        "gold" => Ok(Material::gold(roughness)),
+                        "copper" => Ok(Material::copper(roughness)),
+                        "aluminium" | "aluminum" => Ok(Material::aluminium(roughness)),
+                        "silver" => Ok(Material::silver(roughness)),
+                        other => Err(preset.error(&format!(
+                            "unknown conductor preset '{}', expected gold, copper, aluminium or silver", other
+                        )))
+                    }
+                },
+                None => Ok(Material::conductor(node.get("eta")?.vec3()?, node.get("k")?.vec3()?, roughness))
+            }
+        },
+        "dielectric" => {
+            allow(&["type", "ior", "absorption", "roughness"])?;
+            let absorption = match node.get_opt("absorption") {
+                Some(absorption) => Some(absorption.non_negative_vec3()?),
+                None => None
+            };
+            let roughness = match node.get_opt("roughness") {
+                Some(roughness) => roughness.range(0.0, 1.0)?,
+                None => 0.0
+            };
+            Ok(Material::Dielectric { ior: node.get("ior")?.positive_f32()?, absorption, roughness })
+        },
+        "principled" => {
+            allow(&[
+                "type", "base_color", "metallic", "roughness", "specular", "clearcoat", "clearcoat_roughness",
+                "sheen", "transmission", "emission", "metallic_roughness"
+            ])?;
+            let base_color = parse_texture(&node.get("base_color")?, dir, true)?;
+            let mut principled = match node.get_opt("metallic_roughness") {
+                // glTF's packed map, with metallic and roughness as numbers that scale it
+                Some(map) => {
+                    map.allow_keys(&["path", "wrap"])?;
+                    let factor = |key| match node.get_opt(key) {
+                        Some(factor) => factor.range(0.0, 1.0),
+                        None => Ok(1.0)
+                    };
+                    let path = dir.join(map.get("path")?.str()?);
+                    let image = ImageTexture::load(&path, parse_wrap(&map)?, false)
+                        .map_err(|err| path_error(&map, "path", format!("{}: {}", path.display(), err)))?;
+                    Principled::metallic_roughness(base_color, factor("metallic")?, factor("roughness")?, Some(image))
+                },
+                None => {
+                    let mut principled = Principled::new(base_color);
+                    for (key, texture) in [("metallic", &mut principled.metallic), ("roughness", &mut principled.roughness)] {
+                        if let Some(value) = node.get_opt(key) {
+                            *texture = parse_texture(&value, dir, false)?;
+                        }
+                    }
+                    principled
+                }
+            };
+            // colors are srgb like the base color, the rest are numbers
+            for (key, texture, srgb) in [
+                ("specular", &mut principled.specular, false),
+                ("clearcoat", &mut principled.clearcoat, false),
+                ("clearcoat_roughness", &mut principled.clearcoat_roughness, false),
+                ("sheen", &mut principled.sheen, true),
+                ("transmission", &mut principled.transmission, false),
+                ("emission", &mut principled.emission, true)
+            ] {
+                if let Some(value) = node.get_opt(key) {
+                    *texture = parse_texture(&value, dir, srgb)?;
+                }
+            }
+            // a constant glow is sampled as a light, which can not take light away
+            if let Texture::Constant(emission) = &principled.emission {
+                if emission[0] < 0.0 || emission[1] < 0.0 || emission[2] < 0.0 {
+                    return Err(node.get("emission")?.error("can not be negative"));
+                }
+            }
+            Ok(Material::principled(principled))
+        },
+        other => Err(kind.error(&format!("unknown material type '{}'", other)))
+    }?;
+
+    match (node.get_opt("normal_map"), node.get_opt("bump_map")) {
+        (Some(_), Some(bump_map)) => Err(bump_map.error("a material can have a normal map or a bump map, not both")),
+        (Some(normal_map), None) => {
+            normal_map.allow_keys(&["path", "strength", "wrap"])?;
+            let strength = match normal_map.get_opt("strength") {
+                Some(strength) => strength.f32()?,
+                None => 1.0
+            };
+            // normals are stored linear
+            let path = dir.join(normal_map.get("path")?.str()?);
+            let map = ImageTexture::load(&path, parse_wrap(&normal_map)?, false)
+                .map_err(|err| path_error(&normal_map, "path", format!("{}: {}", path.display(), err)))?;
+            Ok(Material::bumped(material, Bump::Normal { map, strength }))
+        },
+        (None, Some(bump_map)) => {
+            bump_map.allow_keys(&["height", "scale"])?;
+            let scale = match bump_map.get_opt("scale") {
+                Some(scale) => scale.f32()?,
+                None => 1.0
+            };
+            let map = parse_texture(&bump_map.get("height")?, dir, false)?;
+            Ok(Material::bumped(material, Bump::Height { map, scale }))
+        },
+        (None, None) => Ok(material)
+    }
+}
+
+// a color, a number for grey, or an object for checkers, images, noise and channels. `srgb` is set for
+// colors, images of anything else are stored linear
+fn parse_texture(node: &Node, dir: &Path, srgb: bool) -> Result<Texture, SceneError> {
+    if node.value.is_array() {
+        return Ok(Texture::Constant(node.vec3()?));
+    }
+    if node.value.is_number() {
+        return Ok(Texture::from(node.f32()?));
+    }
+    let kind = node.get("type")?;
+    match kind.str()? {
+        "checker" => {
+            node.allow_keys(&["type", "even", "odd", "scale", "space"])?;
+            let even = parse_texture(&node.get("even")?, dir, srgb)?;
+            let odd = parse_texture(&node.get("odd")?, dir, srgb)?;
+            let scale = match node.get_opt("scale") {
+                Some(scale) => scale.f32()?,
+                None => 1.0
+            };
+            match node.get_opt("space") {
+                None => Ok(Texture::checker_2d(even, odd, scale)),
+                Some(space) => match space.str()? {
+                    "uv" => Ok(Texture::checker_2d(even, odd, scale)),
+                    "world" => Ok(Texture::checker_3d(even, odd, scale, Space::World)),
+                    "object" => Ok(Texture::checker_3d(even, odd, scale, Space::Object)),
+                    other => Err(space.error(&format!("unknown checker space '{}', expected uv, world or object", other)))
+                }
+            }
+        },
+        "perlin" | "fbm" | "turbulence" | "marble" | "wood" => {
+            node.allow_keys(&["type", "low", "high", "scale", "octaves", "space"])?;
+            let pattern = match kind.str()? {
+                "perlin" => Pattern::Perlin,
+                "fbm" => Pattern::Fbm,
+                "turbulence" => Pattern::Turbulence,
+                "marble" => Pattern::Marble,
+                _ => Pattern::Wood
+            };
+            let low = match node.get_opt("low") {
+                Some(low) => parse_texture(&low, dir, srgb)?,
+                None => Texture::Constant(Vec3::new(0.0, 0.0, 0.0))
+            };
+            let high = match node.get_opt("high") {
+                Some(high) => parse_texture(&high, dir, srgb)?,
+                None => Texture::Constant(Vec3::new(1.0, 1.0, 1.0))
+            };
+            let scale = match node.get_opt("scale") {
+                Some(scale) => scale.f32()?,
+                None => 1.0
+            };
+            let octaves = match node.get_opt("octaves") {
+                Some(octaves) => octaves.positive_u32()?,
+                None => 6
+            };
+            // patterns usually belong to the object, like the grain of a wooden ball
+            let space = match node.get_opt("space") {
+                None => Space::Object,
+                Some(space) => match space.str()? {
+                    "world" => Space::World,
+                    "object" => Space::Object,
+                    other => return Err(space.error(&format!("unknown space '{}', expected world or object", other)))
+                }
+            };
+            Ok(Texture::noise(pattern, low, high, scale, octaves, space))
+        },
+        "image" => {
+            node.allow_keys(&["type", "path", "wrap"])?;
+            // relative to the scene file
+            let path = dir.join(node.get("path")?.str()?);
+            let image = ImageTexture::load(&path, parse_wrap(node)?, srgb)
+                .map_err(|err| path_error(node, "path", format!("{}: {}", path.display(), err)))?;
+            Ok(Texture::Image(image))
+        },
+        "channel" => {
+            node.allow_keys(&["type", "texture", "channel", "scale"])?;
+            let channel_node = node.get("channel")?;
+            let channel = match channel_node.str()? {
+                "r" => 0,
+                "g" => 1,
+                "b" => 2,
+                other => return Err(channel_node.error(&format!("unknown channel '{}', expected r, g or b", other)))
+            };
+            let scale = match node.get_opt("scale") {
+                Some(scale) => scale.f32()?,
+                None => 1.0
+            };
+            Ok(Texture::channel(parse_texture(&node.get("texture")?, dir, srgb)?, channel, scale))
+        },
+        other => Err(kind.error(&format!("unknown texture type '{}'", other)))
+    }
+}
+
+fn parse_wrap(node: &Node) -> Result<WrapMode, SceneError> {
+    match node.get_opt("wrap") {
+        None => Ok(WrapMode::Repeat),
+        Some(wrap) => match wrap.str()? {
+            "repeat" => Ok(WrapMode::Repeat),
+            "mirror" => Ok(WrapMode::Mirror),
+            "clamp" => Ok(WrapMode::Clamp),
+            other => Err(wrap.error(&format!("unknown wrap mode '{}', expected repeat, mirror or clamp", other)))
+        }
+    }
+}
+
+fn parse_shape(node: &Node) -> Result<Shape, SceneError> {
+    let kind = node.get("type")?;
+    match kind.str()? {
+        "sphere" => {
+            node.allow_keys(&["type", "center", "radius"])?;
+            Ok(Shape::sphere(node.get("center")?.vec3()?, node.get("radius")?.positive_f32()?))
+        },
+        "plane" => {
+            // all points p where normal . p = d
+            node.allow_keys(&["type", "normal", "d"])?;
+            let normal = node.get("normal")?.vec3()?;
+            Ok(Shape::plane(normal[0], normal[1], normal[2], node.get("d")?.f32()?))
+        },
+        "quad" => {
+            node.allow_keys(&["type", "corner", "u", "v"])?;
+            Ok(Shape::quad(node.get("corner")?.vec3()?, node.get("u")?.vec3()?, node.get("v")?.vec3()?))
+        },
+        "triangle" => {
+            node.allow_keys(&["type", "vertices", "normals", "uvs"])?;
+            let vertices = three(&node.get("vertices")?, Node::vec3)?;
+            let normals = match node.get_opt("normals") {
+                Some(normals) => Some(three(&normals, Node::vec3)?),
+                None => None
+            };
+            let uvs = match node.get_opt("uvs") {
+                Some(uvs) => Some(three(&uvs, Node::uv)?),
+                None => None
+            };
+            Ok(Shape::Triangle(crate::hittable::Triangle { vertices, normals, uvs }))
+        },
+        "mesh" => {
+            node.allow_keys(&["type", "positions", "normals", "uvs", "indices"])?;
+            let positions = node.get("positions")?.array()?
+                .iter().map(Node::vec3).collect::<Result<Vec<_>, _>>()?;
+            let normals = match node.get_opt("normals") {
+                Some(normals) => normals.array()?.iter().map(Node::vec3).collect::<Result<Vec<_>, _>>()?,
+                None => vec![]
+            };
+            let uvs = match node.get_opt("uvs") {
+                Some(uvs) => uvs.array()?.iter().map(Node::uv).collect::<Result<Vec<_>, _>>()?,
+                None => vec![]
+            };
+            let mut indices = vec![];
+            for triangle in node.get("indices")?.array()? {
+                indices.push(three(&triangle, |corner| Ok(corner.u32()? as usize))?);
+            }
+            Shape::mesh(positions, normals, uvs, indices).map_err(|err| {
+                let key = match err {
+                    MeshError::NormalCount { .. } => "normals",
+                    MeshError::UvCount { .. } => "uvs",
+                    MeshError::IndexOutOfBounds { .. } => "indices"
+                };
+                path_error(node, key, err)
+            })
+        },
+        other => Err(kind.error(&format!("unknown shape type '{}'", other)))
+    }
+}
+
+fn three<'a, T, F>(node: &Node<'a>, parse: F) -> Result<[T; 3], SceneError>
+where F: Fn(&Node<'a>) -> Result<T, SceneError> {
+    let items = node.array()?;
+    if items.len() != 3 {
+        return Err(node.error(&format!("expected 3 elements, got {}", items.len())));
+    }
+    Ok([parse(&items[0])?, parse(&items[1])?, parse(&items[2])?])
+}
+
+/// A json value and the key it was found at, for error messages
+struct Node<'a> {
+    value: &'a Value,
+    key: String
+}
+impl<'a> Node<'a> {
+    fn error(&self, message: &str) -> SceneError {
+        SceneError { key: self.key.clone(), message: message.to_string() }
+    }
+
+    fn child_key(&self, key: &str) -> String {
+        if self.key.is_empty() {
+            key.to_string()
+        } else {
+            format!("{}.{}", self.key, key)
+        }
+    }
+
+    fn object(&self) -> Result<&'a Map<String, Value>, SceneError> {
+        self.value.as_object().ok_or_else(|| self.error("expected an object"))
+    }
+
+    fn get_opt(&self, key: &str) -> Option<Node<'a>> {
+        self.value.get(key).map(|value| Node { value, key: self.child_key(key) })
+    }
+
+    fn get(&self, key: &str) -> Result<Node<'a>, SceneError> {
+        self.object()?;
+        self.get_opt(key).ok_or_else(|| SceneError {
+            key: self.child_key(key),
+            message: "missing".to_string()
+        })
+    }
+
+    /// Catches misspelled keys which would otherwise be ignored without a word
+    fn allow_keys(&self, allowed: &[&str]) -> Result<(), SceneError> {
+        for key in self.object()?.keys() {
+            if !allowed.contains(&key.as_str()) {
+                return Err(SceneError {
+                    key: self.child_key(key),
+                    message: format!("unknown key, expected one of {}", allowed.join(", "))
+                });
+            }
+        }
+        Ok(())
+    }
+
+    fn array(&self) -> Result<Vec<Node<'a>>, SceneError> {
+        let items = self.value.as_array().ok_or_else(|| self.error("expected an array"))?;
+        Ok(items.iter().enumerate().map(|(i, value)| Node {
+            value,
+            key: format!("{}[{}]", self.key, i)
+        }).collect())
+    }
+
+    fn str(&self) -> Result<&'a str, SceneError> {
+        self.value.as_str().ok_or_else(|| self.error("expected a string"))
+    }
+
+    fn f32(&self) -> Result<f32, SceneError> {
+        self.value.as_f64().map(|f| f as f32).ok_or_else(|| self.error("expected a number"))
+    }
+
+    fn positive_f32(&self) -> Result<f32, SceneError> {
+        match self.f32()? {
+            f if f > 0.0 => Ok(f),
+            _ => Err(self.error("has to be more than 0"))
+        }
+    }
+
+    fn non_negative_f32(&self) -> Result<f32, SceneError> {
+        match self.f32()? {
+            f if f >= 0.0 => Ok(f),
+            _ => Err(self.error("can not be negative"))
+        }
+    }
+
+    fn u32(&self) -> Result<u32, SceneError> {
+        self.value.as_u64()
+            .and_then(|i| u32::try_from(i).ok())
+            .ok_or_else(|| self.error("expected a whole number that is not negative"))
+    }
+
+    fn positive_u32(&self) -> Result<u32, SceneError> {
+        match self.u32()? {
+            0 => Err(self.error("has to be at least 1")),
+            i => Ok(i)
+        }
+    }
+
+    fn vec3(&self) -> Result<Vec3, SceneError> {
+        let [x, y, z] = three(self, Node::f32)?;
+        Ok(Vec3::new(x, y, z))
+    }
+
+    // colors, intensities and such where a negative part would take light away
+    fn non_negative_vec3(&self) -> Result<Vec3, SceneError> {
+        let [x, y, z] = three(self, Node::non_negative_f32)?;
+        Ok(Vec3::new(x, y, z))
+    }
+
+    fn direction(&self) -> Result<Vec3, SceneError> {
+        let v = self.vec3()?;
+        if v.dot(&v) == 0.0 {
+            return Err(self.error("a direction can not be all zeros"));
+        }
+        Ok(v)
+    }
+
+    fn range(&self, min: f32, max: f32) -> Result<f32, SceneError> {
+        let value = self.f32()?;
+        if !(min..=max).contains(&value) {
+            return Err(self.error(&format!("has to be between {} and {}", min, max)));
+        }
+        Ok(value)
+    }
+
+    // in degrees
+    fn angle(&self, min: f32, max: f32) -> Result<f32, SceneError> {
+        let angle = self.f32()?;
+        if !(min..=max).contains(&angle) {
+            return Err(self.error(&format!("has to be between {} and {} degrees", min, max)));
+        }
+        Ok(angle)
+    }
+
+    fn uv(&self) -> Result<(f32, f32), SceneError> {
+        let items = self.array()?;
+        if items.len() != 2 {
+            return Err(self.error(&format!("expected 2 elements, got {}", items.len())));
+        }
+        Ok((items[0].f32()?, items[1].f32()?))
+    }
+}
+
+/// Writes the scene back out as json that `load_scene` reads.
+/// Meshes loaded from obj files end up written out in full. Images, normal maps and
+/// environment maps that were made in code have no file to point to, saving them is an error.
+pub fn save_scene<P: AsRef<Path>>(scene: &Scene, path: P) -> Result<(), SceneError> {
+    let path = path.as_ref();
+    let json = scene_to_json(scene, path.parent().unwrap_or(Path::new("")))?;
+    fs::write(path, json).map_err(|err| SceneError {
+        key: String::new(),
+        message: format!("{}: {}", path.display(), err)
+    })
+}
+
+/// The scene as json that `parse_scene` reads with the same `dir`, files are written relative to it
+pub fn scene_to_json(scene: &Scene, dir: &Path) -> Result<String, SceneError> {
+    let camera = &scene.camera;
+    let mut camera_json = json!({
+        "look_from": vec3_json(camera.look_from()),
+        "look_at": vec3_json(camera.look_at()),
+        "up": vec3_json(camera.up()),
+        "vfov": camera.vfov(),
+        "width": camera.width,
+        "height": camera.height
+    });
+    if camera.aperture_radius() > 0.0 {
+        camera_json["aperture"] = json!(camera.aperture_radius());
+        camera_json["focus_distance"] = json!(camera.focus_dist());
+    }
+
+    let world = &scene.world;
+    let objects = world.hittables().iter().enumerate()
+        .map(|(i, hittable)| hittable_json(hittable, dir, &format!("objects[{}]", i)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut scene_json = json!({
+        "camera": camera_json,
+        "render": {
+            "samples_per_pixel": world.samples_per_pixel(),
+            "max_depth": world.max_depth(),
+            "exposure": scene.tone_mapping.exposure,
+            "tone_mapping": operator_json(&scene.tone_mapping.operator)
+        },
+        "sun": world.sun().map_or(Value::Null, |sun| {
+            let mut value = shape_json(&sun.shape);
+            if let Some(radiance) = sun.material.emission() {
+                value["radiance"] = vec3_json(&radiance);
+            }
+            value
+        }),
+        "lights": world.lights().iter().map(light_json).collect::<Vec<_>>(),
+        "objects": objects
+    });
+    if let Some(sky) = world.sky() {
+        scene_json["sky"] = json!({
+            "elevation": sky.elevation,
+            "azimuth": sky.azimuth,
+            "turbidity": sky.turbidity,
+            "intensity": sky.intensity
+        });
+    } else if let Some(environment) = world.environment() {
+        scene_json["environment"] = json!({
+            "path": path_json(environment.path(), dir, "environment")?,
+            "rotation": environment.rotation(),
+            "intensity": environment.intensity()
+        });
+    }
+    Ok(serde_json::to_string_pretty(&scene_json).unwrap_or_default())
+}
+
+fn hittable_json(hittable: &Hittable, dir: &Path, key: &str) -> Result<Value, SceneError> {
+    Ok(json!({
+        "shape": shape_json(&hittable.shape),
+        "material": material_json(&hittable.material, dir, &format!("{}.material", key))?
+    }))
+}
+
+// where `parse_scene` with `dir` finds the file again, files made in code have no path and can not be saved
+fn path_json(path: Option<&Path>, dir: &Path, key: &str) -> Result<Value, SceneError> {
+    let path = path.ok_or_else(|| SceneError {
+        key: key.to_string(),
+        message: "was made in code rather than loaded from a file, so it can not be saved".to_string()
+    })?;
+    Ok(json!(relative_path(path, dir).to_string_lossy()))
+}
+
+fn relative_path(path: &Path, dir: &Path) -> PathBuf {
+    // the usual case, saved next to where it was loaded from
+    if let Ok(relative) = path.strip_prefix(dir) {
+        return relative.to_path_buf();
+    }
+    match (fs::canonicalize(path), fs::canonicalize(dir)) {
+        (Ok(path), Ok(dir)) => {
+            let common = path.components().zip(dir.components()).take_while(|(a, b)| a == b).count();
+            let mut relative = PathBuf::new();
+            for _ in common..dir.components().count() {
+                relative.push("..");
+            }
+            relative.extend(path.components().skip(common));
+            relative
+        },
+        // the file or dir is gone, an absolute path is right from anywhere
+        _ => std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())
+    }
+}
+
+fn light_json(light: &Light) -> Value {
+    match light {
+        Light::Point { position, color, intensity } => json!({
+            "type": "point", "position": vec3_json(position), "color": vec3_json(color), "intensity": intensity
+        }),
+        Light::Directional { direction, color, intensity, angular_diameter } => json!({
+            "type": "directional", "direction": vec3_json(direction), "color": vec3_json(color),
+            "intensity": intensity, "angular_diameter": angular_diameter
+        }),
+        Light::Spot { position, direction, color, intensity, cone_angle, falloff } => json!({
+            "type": "spot", "position": vec3_json(position), "direction": vec3_json(direction), "color": vec3_json(color),
+            "intensity": intensity, "cone_angle": cone_angle, "falloff": falloff
+        })
+    }
+}
+
+fn operator_json(operator: &Operator) -> Value {
+    match operator {
+        Operator::Clamp => json!("clamp"),
+        Operator::Reinhard => json!("reinhard"),
+        Operator::ExtendedReinhard { white } => json!({ "type": "extended_reinhard", "white": white }),
+        Operator::Aces => json!("aces")
+    }
+}
+
+fn material_json(material: &Material, dir: &Path, key: &str) -> Result<Value, SceneError> {
+    let texture = |name: &str, texture: &Texture| texture_json(texture, dir, &format!("{}.{}", key, name));
+    Ok(match material {
+        Material::Emissive(color) => json!({ "type": "emissive", "color": vec3_json(color) }),
+        Material::Lambertian(albedo) => json!({ "type": "lambertian", "albedo": texture("albedo", albedo)? }),
+        Material::Metal { albedo, fuzz } => json!({ "type": "metal", "albedo": texture("albedo", albedo)?, "fuzz": fuzz }),
+        Material::Conductor { eta, k, roughness } => json!({
+            "type": "conductor", "eta": vec3_json(eta), "k": vec3_json(k), "roughness": roughness
+        }),
+        Material::Dielectric { ior, absorption, roughness } => {
+            let mut value = json!({ "type": "dielectric", "ior": ior, "roughness": roughness });
+            if let Some(absorption) = absorption {
+                value["absorption"] = vec3_json(absorption);
+            }
+            value
+        },
+        Material::Principled(principled) => json!({
+            "type": "principled",
+            "base_color": texture("base_color", &principled.base_color)?,
+            "metallic": texture("metallic", &principled.metallic)?,
+            "roughness": texture("roughness", &principled.roughness)?,
+            "specular": texture("specular", &principled.specular)?,
+            "clearcoat": texture("clearcoat", &principled.clearcoat)?,
+            "clearcoat_roughness": texture("clearcoat_roughness", &principled.clearcoat_roughness)?,
+            "sheen": texture("sheen", &principled.sheen)?,
+            "transmission": texture("transmission", &principled.transmission)?,
+            "emission": texture("emission", &principled.emission)?
+        }),
+        Material::Bumped { material, bump } => {
+            let mut value = material_json(material, dir, key)?;
+            match bump {
+                Bump::Normal { map, strength } => {
+                    value["normal_map"] = json!({
+                        "path": path_json(map.path(), dir, &format!("{}.normal_map", key))?,
+                        "strength": strength,
+                        "wrap": wrap_json(map.wrap)
+                    });
+                },
+                Bump::Height { map, scale } => {
+                    value["bump_map"] = json!({ "height": texture_json(map, dir, &format!("{}.bump_map.height", key))?, "scale": scale });
+                }
+            }
+            value
+        }
+    })
+}
+
+fn texture_json(texture: &Texture, dir: &Path, key: &str) -> Result<Value, SceneError> {
+    let inner = |name: &str, texture: &Texture| texture_json(texture, dir, &format!("{}.{}", key, name));
+    Ok(match texture {
+        Texture::Constant(color) => vec3_json(color),
+        Texture::Checker2d { even, odd, scale } => json!({
+            "type": "checker", "even": inner("even", even)?, "odd": inner("odd", odd)?, "scale": scale, "space": "uv"
+        }),
+        Texture::Checker3d { even, odd, scale, space } => json!({
+            "type": "checker", "even": inner("even", even)?, "odd": inner("odd", odd)?, "scale": scale, "space": space_json(space)
+        }),
+        Texture::Noise { pattern, low, high, scale, octaves, space } => {
+            let kind = match pattern {
+                Pattern::Perlin => "perlin",
+                Pattern::Fbm => "fbm",
+                Pattern::Turbulence => "turbulence",
+                Pattern::Marble => "marble",
+                Pattern::Wood => "wood"
+            };
+            json!({
+                "type": kind, "low": inner("low", low)?, "high": inner("high", high)?,
+                "scale": scale, "octaves": octaves, "space": space_json(space)
+            })
+        },
+        Texture::Channel { texture, channel, scale } => {
+            let channel = ["r", "g", "b"][*channel];
+            json!({ "type": "channel", "texture": inner("texture", texture)?, "channel": channel, "scale": scale })
+        },
+        Texture::Image(image) => json!({
+            "type": "image", "path": path_json(image.path(), dir, key)?, "wrap": wrap_json(image.wrap)
+        })
+    })
+}
+
+fn wrap_json(wrap: WrapMode) -> Value {
+    match wrap {
+        WrapMode::Repeat => json!("repeat"),
+        WrapMode::Mirror => json!("mirror"),
+        WrapMode::Clamp => json!("clamp")
+    }
+}
+
+fn space_json(space: &Space) -> Value {
+    match space {
+        Space::World => json!("world"),
+        Space::Object => json!("object")
+    }
+}
+
+fn shape_json(shape: &Shape) -> Value {
+    match shape {
+        Shape::Sphere(sphere) => json!({ "type": "sphere", "center": vec3_json(&sphere.c), "radius": sphere.r }),
+        Shape::Plane(plane) => json!({ "type": "plane", "normal": [plane.a, plane.b, plane.c], "d": plane.d }),
+        Shape::Triangle(triangle) => {
+            let mut value = json!({
+                "type": "triangle",
+                "vertices": triangle.vertices.iter().map(vec3_json).collect::<Vec<_>>()
+            });
+            if let Some(normals) = &triangle.normals {
+                value["normals"] = normals.iter().map(vec3_json).collect();
+            }
+            if let Some(uvs) = &triangle.uvs {
+                value["uvs"] = uvs.iter().map(|(u, v)| json!([u, v])).collect();
+            }
+            value
+        },
+        Shape::Mesh(mesh) => {
+            let mut value = json!({
+                "type": "mesh",
+                "positions": mesh.positions.iter().map(vec3_json).collect::<Vec<_>>(),
+                "indices": mesh.indices
+            });
+            if !mesh.normals.is_empty() {
+                value["normals"] = mesh.normals.iter().map(vec3_json).collect();
+            }
+            if !mesh.uvs.is_empty() {
+                value["uvs"] = mesh.uvs.iter().map(|(u, v)| json!([u, v])).collect();
+            }
+            value
+        }
+    }
+}
+
+fn vec3_json(v: &Vec3) -> Value {
+    json!([v[0], v[1], v[2]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::Environment;
+    use crate::framebuffer::Framebuffer;
+    use crate::vec3::Color;
+
+    const CAMERA: &str = r#""camera": { "look_from": [0, 0, 5], "look_at": [0, 0, 0], "vfov": 40, "width": 4, "height": 3 }"#;
+
+    // an empty directory of its own for each test
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("raytracer_scene_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn json(scene: &Scene, dir: &Path) -> Value {
+        serde_json::from_str(&scene_to_json(scene, dir).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn environment_paths_are_relative_to_the_saved_scene() {
+        let dir = temp_dir("environment");
+        fs::create_dir_all(dir.join("scenes/maps")).unwrap();
+        Framebuffer::new(4, 2).save(dir.join("scenes/maps/sky.hdr")).unwrap();
+        let source = format!(r#"{{ {}, "environment": {{ "path": "maps/sky.hdr" }}, "objects": [] }}"#, CAMERA);
+        let scene = parse_scene(&source, &dir.join("scenes")).unwrap();
+
+        assert_eq!(json(&scene, &dir.join("scenes"))["environment"]["path"], "maps/sky.hdr");
+        fs::create_dir_all(dir.join("saved")).unwrap();
+        let saved = json(&scene, &dir.join("saved"));
+        assert_eq!(saved["environment"]["path"], "../scenes/maps/sky.hdr");
+
+        // and loads from there again
+        let reloaded = parse_scene(&saved.to_string(), &dir.join("saved")).unwrap();
+        assert_eq!(reloaded.world.environment().unwrap().size(), (4, 2));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn environments_made_in_code_can_not_be_saved() {
+        let mut scene = parse_scene(&format!(r#"{{ {}, "objects": [] }}"#, CAMERA), Path::new("")).unwrap();
+        scene.world.set_environment(Environment::new(1, 1, vec![Vec3::new(1.0, 1.0, 1.0)], 0.0, 1.0).unwrap());
+        let err = scene_to_json(&scene, Path::new("")).unwrap_err();
+        assert_eq!(err.key, "environment");
+    }
+
+    #[test]
+    fn image_paths_are_relative_to_the_saved_scene() {
+        let dir = temp_dir("images");
+        fs::create_dir_all(dir.join("scenes/textures")).unwrap();
+        fs::create_dir_all(dir.join("saved")).unwrap();
+        Framebuffer::new(2, 2).save(dir.join("scenes/textures/color.png")).unwrap();
+        Framebuffer::new(2, 2).save(dir.join("scenes/textures/normal.png")).unwrap();
+        let source = format!(r#"{{ {}, "objects": [{{
+            "shape": {{ "type": "sphere", "center": [0, 0, 0], "radius": 1 }},
+            "material": {{
+                "type": "lambertian",
+                "albedo": {{ "type": "image", "path": "textures/color.png" }},
+                "normal_map": {{ "path": "textures/normal.png" }}
+            }}
+        }}] }}"#, CAMERA);
+        let scene = parse_scene(&source, &dir.join("scenes")).unwrap();
+
+        let material = &json(&scene, &dir.join("scenes"))["objects"][0]["material"];
+        assert_eq!(material["albedo"]["path"], "textures/color.png");
+        assert_eq!(material["normal_map"]["path"], "textures/normal.png");
+        let saved = json(&scene, &dir.join("saved"));
+        let material = &saved["objects"][0]["material"];
+        assert_eq!(material["albedo"]["path"], "../scenes/textures/color.png");
+        assert_eq!(material["normal_map"]["path"], "../scenes/textures/normal.png");
+        parse_scene(&saved.to_string(), &dir.join("saved")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn images_made_in_code_can_not_be_saved() {
+        let mut scene = parse_scene(&format!(r#"{{ {}, "objects": [] }}"#, CAMERA), Path::new("")).unwrap();
+        let image = ImageTexture::new(1, 1, vec![Vec3::new(1.0, 0.0, 0.0)], WrapMode::Repeat);
+        scene.world.add(Shape::sphere(Vec3::new(0.0, 0.0, 0.0), 1.0), Material::lambertian(Color::white()));
+        let mut principled = Principled::new(Vec3::new(0.5, 0.5, 0.5));
+        principled.roughness = Texture::Channel { texture: Box::new(Texture::Image(image)), channel: 1, scale: 1.0 };
+        scene.world.add(Shape::sphere(Vec3::new(2.0, 0.0, 0.0), 1.0), Material::Principled(Box::new(principled)));
+        let err = scene_to_json(&scene, Path::new("")).unwrap_err();
+        assert_eq!(err.key, "objects[1].material.roughness.texture");
+    }
+
+    // equal json apart from the last bit of floats, directions are normalized again when loaded
+    fn same(a: &Value, b: &Value, key: &str) {
+        match (a, b) {
+            (Value::Number(a), Value::Number(b)) => {
+                let (a, b) = (a.as_f64().unwrap(), b.as_f64().unwrap());
+                assert!((a - b).abs() <= 1e-6 * f64::max(1.0, b.abs()), "{}: {} != {}", key, a, b);
+            },
+            (Value::Array(a), Value::Array(b)) if a.len() == b.len() => {
+                for (i, (a, b)) in a.iter().zip(b).enumerate() {
+                    same(a, b, &format!("{}[{}]", key, i));
+                }
+            },
+            (Value::Object(a), Value::Object(b)) if a.len() == b.len() => {
+                for (name, a) in a {
+                    let b = b.get(name).unwrap_or_else(|| panic!("{}.{} is missing", key, name));
+                    same(a, b, &format!("{}.{}", key, name));
+                }
+            },
+            _ => assert_eq!(a, b, "{}", key)
+        }
+    }
+
+    // every kind of camera setting, material, texture, light and shape
+    const EVERYTHING: &str = r#"
+        "camera": { "look_from": [1, 2, 8], "look_at": [0, 0.5, 0], "up": [0, 1, 0.1], "vfov": 35, "width": 64, "height": 48, "aperture": 0.05, "focus_distance": 7.5 },
+        "render": { "samples_per_pixel": 7, "max_depth": 5, "exposure": 0.5, "tone_mapping": { "type": "extended_reinhard", "white": 4 } },
+        "materials": {
+            "tiles": { "type": "lambertian", "albedo": { "type": "checker", "even": [1, 1, 1], "odd": { "type": "image", "path": "color.png", "wrap": "mirror" }, "scale": 2, "space": "world" } },
+            "marble": { "type": "metal", "albedo": { "type": "marble", "low": [0.2, 0.2, 0.25], "high": [1, 1, 1], "scale": 2, "octaves": 4, "space": "object" }, "fuzz": 0.1 },
+            "bricks": { "type": "lambertian", "albedo": [0.6, 0.3, 0.2], "normal_map": { "path": "normal.png", "strength": 0.5 } },
+            "hammered": { "type": "conductor", "preset": "gold", "roughness": 0.3, "bump_map": { "height": { "type": "fbm", "scale": 20 }, "scale": 0.002 } },
+            "glass": { "type": "dielectric", "ior": 1.5, "roughness": 0.2, "absorption": [0.1, 0.2, 0.3] },
+            "helmet": { "type": "principled", "base_color": { "type": "image", "path": "color.png" }, "metallic_roughness": { "path": "mr.png" }, "clearcoat": 1, "sheen": [0.1, 0.1, 0.1], "emission": [0, 0, 0.5] }
+        },
+        "lights": [
+            { "type": "point", "position": [0, 2, 0], "color": [1, 0.9, 0.8], "intensity": 5 },
+            { "type": "directional", "direction": [0, -1, -1], "intensity": 2, "angular_diameter": 0.53 },
+            { "type": "spot", "position": [0, 3, -2], "direction": [0, -1, 0], "intensity": 20, "cone_angle": 30, "falloff": 5 }
+        ],
+        "objects": [
+            { "shape": { "type": "sphere", "center": [0, 0, -3], "radius": 0.5 }, "material": "tiles" },
+            { "shape": { "type": "plane", "normal": [0, 1, 0], "d": -1.2 }, "material": "marble" },
+            { "shape": { "type": "quad", "corner": [-1, 2, -3], "u": [2, 0, 0], "v": [0, 0, 1] }, "material": { "type": "emissive", "color": [4, 4, 4] } },
+            { "shape": { "type": "triangle", "vertices": [[0, 0, 0], [1, 0, 0], [0, 1, 0]], "uvs": [[0, 0], [1, 0], [0, 1]] }, "material": "bricks" },
+            { "shape": { "type": "mesh", "positions": [[0, 0, 0], [1, 0, 0], [1, 1, 0], [0, 1, 0]], "normals": [[0, 0, 1], [0, 0, 1], [0, 0, 1], [0, 0, 1]], "indices": [[0, 1, 2], [0, 2, 3]] }, "material": "helmet" },
+            { "shape": { "type": "sphere", "center": [2, 0, 0], "radius": 1 }, "material": "hammered" },
+            { "shape": { "type": "sphere", "center": [-2, 0, 0], "radius": 1 }, "material": "glass" },
+            { "shape": { "type": "sphere", "center": [0, 5, 0], "radius": 0.2 }, "material": { "type": "light" } }
+        ]"#;
+
+    #[test]
+    fn saved_scenes_load_the_same() {
+        let dir = temp_dir("round_trip");
+        for file in ["color.png", "normal.png", "mr.png"] {
+            Framebuffer::new(2, 2).save(dir.join(file)).unwrap();
+        }
+        Framebuffer::new(4, 2).save(dir.join("sky.hdr")).unwrap();
+
+        for extra in [
+            r#""sky": { "elevation": 30, "azimuth": 120, "turbidity": 3, "intensity": 2 }"#,
+            r#""environment": { "path": "sky.hdr", "rotation": 90, "intensity": 1.5 }, "sun": { "type": "sphere", "center": [-10, 8, 5], "radius": 2, "radiance": [50, 48, 45] }"#,
+            r#""sun": null"#
+        ] {
+            let source = format!("{{ {}, {} }}", EVERYTHING, extra);
+            let scene = parse_scene(&source, &dir).unwrap_or_else(|err| panic!("{}: {}", extra, err));
+            let saved = scene_to_json(&scene, &dir).unwrap();
+            let reloaded = parse_scene(&saved, &dir).unwrap_or_else(|err| panic!("{}: {}\n{}", extra, err, saved));
+            let saved: Value = serde_json::from_str(&saved).unwrap();
+            same(&json(&reloaded, &dir), &saved, "");
+            // the parts that are written out exactly as they were read
+            let source: Value = serde_json::from_str(&source).unwrap();
+            for key in ["camera", "sky", "environment", "sun"] {
+                if let Some(value) = source.get(key) {
+                    same(&saved[key], value, key);
+                }
+            }
+
+            // and nothing got lost on the way
+            assert_eq!(reloaded.world.hittables().len(), 8);
+            assert_eq!(reloaded.world.lights().len(), 3);
+            assert_eq!(reloaded.camera.aperture_radius(), 0.05);
+            assert_eq!(reloaded.world.samples_per_pixel(), 7);
+            assert_eq!(reloaded.world.sky().is_some(), extra.contains("sky\""));
+            assert_eq!(reloaded.world.environment().is_some(), !extra.contains("null"));
+            assert_eq!(reloaded.world.sun().is_some(), extra.contains("radiance"));
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn impossible_values_are_refused() {
+        let error = |scene: &str| match parse_scene(scene, Path::new("")) {
+            Ok(_) => panic!("{} should not load", scene),
+            Err(err) => err.key
+        };
+        let camera = |camera: &str| error(&format!(r#"{{ "camera": {{ "width": 4, "height": 3, {} }}, "objects": [] }}"#, camera));
+        assert_eq!(camera(r#""look_from": [1, 2, 3], "look_at": [1, 2, 3], "vfov": 40"#), "camera.look_at");
+        assert_eq!(camera(r#""look_from": [0, 5, 0], "look_at": [0, 0, 0], "vfov": 40"#), "camera.look_at");
+        assert_eq!(camera(r#""look_from": [0, 0, 5], "look_at": [0, 0, 0], "up": [0, 0, -2], "vfov": 40"#), "camera.up");
+        assert_eq!(camera(r#""look_from": [0, 0, 5], "look_at": [0, 0, 0], "vfov": 0"#), "camera.vfov");
+        assert_eq!(camera(r#""look_from": [0, 0, 5], "look_at": [0, 0, 0], "vfov": 180"#), "camera.vfov");
+        assert_eq!(
+            error(&format!(r#"{{ {}, "render": {{ "tone_mapping": {{ "type": "extended_reinhard", "white": 0 }} }} }}"#, CAMERA)),
+            "render.tone_mapping.white"
+        );
+
+        let object = |shape: &str, material: &str| error(&format!(
+            r#"{{ {}, "objects": [{{ "shape": {}, "material": {} }}] }}"#, CAMERA, shape, material
+        ));
+        let sphere = r#"{ "type": "sphere", "center": [0, 0, 0], "radius": 1 }"#;
+        let white = r#"{ "type": "lambertian", "albedo": [1, 1, 1] }"#;
+        assert_eq!(object(r#"{ "type": "sphere", "center": [0, 0, 0], "radius": 0 }"#, white), "objects[0].shape.radius");
+        assert_eq!(object(r#"{ "type": "sphere", "center": [0, 0, 0], "radius": -1 }"#, white), "objects[0].shape.radius");
+        assert_eq!(object(sphere, r#"{ "type": "dielectric", "ior": 0 }"#), "objects[0].material.ior");
+        assert_eq!(object(sphere, r#"{ "type": "dielectric", "ior": -1.5 }"#), "objects[0].material.ior");
+        // nothing takes light away
+        assert_eq!(object(sphere, r#"{ "type": "dielectric", "ior": 1.5, "absorption": [0, 0, -1] }"#), "objects[0].material.absorption[2]");
+        assert_eq!(object(sphere, r#"{ "type": "emissive", "color": [-1, 1, 1] }"#), "objects[0].material.color[0]");
+        assert_eq!(
+            object(sphere, r#"{ "type": "principled", "base_color": [1, 1, 1], "emission": [0, -2, 0] }"#),
+            "objects[0].material.emission"
+        );
+        let scene = |extra: &str| error(&format!(r#"{{ {}, {}, "objects": [] }}"#, CAMERA, extra));
+        assert_eq!(scene(r#""lights": [{ "type": "point", "position": [0, 1, 0], "intensity": -5 }]"#), "lights[0].intensity");
+        assert_eq!(scene(r#""lights": [{ "type": "point", "position": [0, 1, 0], "color": [1, -1, 1] }]"#), "lights[0].color[1]");
+        assert_eq!(scene(r#""sky": { "elevation": 30, "intensity": -1 }"#), "sky.intensity");
+        assert_eq!(scene(r#""environment": { "path": "sky.hdr", "intensity": -1 }"#), "environment.intensity");
+        assert_eq!(
+            scene(r#""sun": { "type": "sphere", "center": [0, 9, 0], "radius": 2, "radiance": [-50, 50, 50] }"#),
+            "sun.radiance[0]"
+        );
+        // broken meshes point at the list that has to change
+        let mesh = |extra: &str| object(
+            &format!(r#"{{ "type": "mesh", "positions": [[0, 0, 0], [1, 0, 0], [0, 1, 0]], {} }}"#, extra), white
+        );
+        assert_eq!(mesh(r#""indices": [[0, 1, 3]]"#), "objects[0].shape.indices");
+        assert_eq!(mesh(r#""indices": [[0, 1, 2]], "normals": [[0, 0, 1]]"#), "objects[0].shape.normals");
+        assert_eq!(mesh(r#""indices": [[0, 1, 2]], "uvs": [[0, 0], [1, 0]]"#), "objects[0].shape.uvs");
+        assert_eq!(
+            error(&format!(r#"{{ {}, "sun": {{ "type": "sphere", "center": [0, 9, 0], "radius": -2 }} }}"#, CAMERA)),
+            "sun.radius"
+        );
+        assert_eq!(error(&format!(r#"{{ {}, "sun": {{ "type": "plane", "normal": [0, 1, 0], "d": -9 }} }}"#, CAMERA)), "sun");
+    }
+}
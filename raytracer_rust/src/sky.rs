@@ -0,0 +1,229 @@
+use std::f32::consts::PI;
+
+use crate::environment::Environment;
+use crate::light::Light;
+use crate::vec3::Vec3;
+
+// size of the environment map the sky is baked into
+const SKY_WIDTH: usize = 512;
+const SKY_HEIGHT: usize = 256;
+// the model gives luminance in kcd/m², this brings a clear day sky to about a sixth of the sun
+const SKY_SCALE: f32 = 1.0 / 30.0;
+// sunlight before it enters the atmosphere, a white surface facing a clear noon sun is about fully lit
+const SUN_IRRADIANCE: f32 = 4.0;
+// how big the sun looks, in degrees
+const SUN_ANGULAR_DIAMETER: f32 = 0.53;
+// wavelengths in micrometers used for red, green and blue when working out the color of the sun
+const WAVELENGTHS: [f32; 3] = [0.68, 0.55, 0.44];
+
+/// Daylight sky after Preetham, Shirley and Smits, "A Practical Analytic Model for Daylight" (1999).
+/// Nothing comes from below the horizon, scenes are expected to have a ground.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sky {
+    // degrees above the horizon
+    pub elevation: f32,
+    // degrees around the y axis, 0 puts the sun towards -z and 90 towards +x
+    pub azimuth: f32,
+    // haziness, 2 is a very clear day and 10 a hazy one
+    pub turbidity: f32,
+    // multiplies both the sky and the sun
+    pub intensity: f32
+}
+
+impl Sky {
+    pub fn new(elevation: f32, azimuth: f32, turbidity: f32) -> Sky {
+        Sky { elevation, azimuth, turbidity, intensity: 1.0 }
+    }
+
+    /// Unit vector towards the sun
+    pub fn sun_direction(&self) -> Vec3 {
+        let (elevation, azimuth) = (self.elevation.to_radians(), self.azimuth.to_radians());
+        Vec3::new(
+            f32::cos(elevation) * f32::sin(azimuth),
+            f32::sin(elevation),
+            -f32::cos(elevation) * f32::cos(azimuth)
+        )
+    }
+
+    /// Light from the sky in `direction`, without the sun itself
+    pub fn radiance(&self, direction: &Vec3) -> Vec3 {
+        if direction[1] <= 0.0 {
+            return Vec3::new(0.0, 0.0, 0.0);
+        }
+        let t = self.turbidity;
+        let theta_sun = self.sun_zenith();
+        let theta = f32::acos(f32::min(direction[1], 1.0));
+        let gamma = f32::acos(f32::clamp(direction.dot(&self.sun_direction()), -1.0, 1.0));
+
+        // zenith luminance and chromaticity
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta_sun);
+        let zenith_luminance = (4.0453 * t - 4.9710) * f32::tan(chi) - 0.2155 * t + 2.4192;
+        let zenith_x = chromaticity(t, theta_sun, &[
+            [0.00166, -0.00375, 0.00209, 0.0],
+            [-0.02903, 0.06377, -0.03202, 0.00394],
+            [0.11693, -0.21196, 0.06052, 0.25886]
+        ]);
+        let zenith_y = chromaticity(t, theta_sun, &[
+            [0.00275, -0.00610, 0.00317, 0.0],
+            [-0.04214, 0.08970, -0.04153, 0.00516],
+            [0.15346, -0.26756, 0.06670, 0.26688]
+        ]);
+
+        // how much brighter or darker than the zenith the sky is at (theta, gamma)
+        let luminance = zenith_luminance * perez_ratio(&[
+            0.1787 * t - 1.4630,
+            -0.3554 * t + 0.4275,
+            -0.0227 * t + 5.3251,
+            0.1206 * t - 2.5771,
+            -0.0670 * t + 0.3703
+        ], theta, gamma, theta_sun);
+        let x = zenith_x * perez_ratio(&[
+            -0.0193 * t - 0.2592,
+            -0.0665 * t + 0.0008,
+            -0.0004 * t + 0.2125,
+            -0.0641 * t - 0.8989,
+            -0.0033 * t + 0.0452
+        ], theta, gamma, theta_sun);
+        let y = zenith_y * perez_ratio(&[
+            -0.0167 * t - 0.2608,
+            -0.0950 * t + 0.0092,
+            -0.0079 * t + 0.2102,
+            -0.0441 * t - 1.6537,
+            -0.0109 * t + 0.0529
+        ], theta, gamma, theta_sun);
+
+        xyy_to_rgb(x, y, f32::max(0.0, luminance)) * (SKY_SCALE * self.intensity)
+    }
+
+    /// Directional light for the sun, reddened by as much air as it shines through.
+    /// It only lights surfaces directly, reflections and refractions do not see it
+    pub fn sun_light(&self) -> Light {
+        let theta_sun = self.sun_zenith();
+        // relative optical mass, how much more air the light goes through than straight from above
+        let mass = 1.0 / (f32::cos(theta_sun) + 0.15 * f32::powf(93.885 - theta_sun.to_degrees(), -1.253));
+        // Angstrom's turbidity coefficient for haze
+        let beta = 0.04608 * self.turbidity - 0.04586;
+        let transmittance = WAVELENGTHS.map(|lambda| {
+            let rayleigh = f32::exp(-0.008735 * f32::powf(lambda, -4.08) * mass);
+            let aerosol = f32::exp(-beta * f32::powf(lambda, -1.3) * mass);
+            rayleigh * aerosol
+        });
+        let color = Vec3::new(transmittance[0], transmittance[1], transmittance[2]);
+        Light::directional(self.sun_direction() * -1.0, color, SUN_IRRADIANCE * self.intensity, SUN_ANGULAR_DIAMETER)
+    }
+
+    /// The sky baked into an environment map, which can be importance sampled
+    pub fn to_environment(&self) -> Environment {
+        let mut pixels = Vec::with_capacity(SKY_WIDTH * SKY_HEIGHT);
+        for y in 0..SKY_HEIGHT {
+            let theta = (y as f32 + 0.5) / SKY_HEIGHT as f32 * PI;
+            for x in 0..SKY_WIDTH {
+                // same layout as `Environment`, the middle of the image looks down -z
+                let phi = ((x as f32 + 0.5) / SKY_WIDTH as f32 - 0.5) * 2.0 * PI;
+                let direction = Vec3::new(
+                    f32::sin(theta) * f32::sin(phi),
+                    f32::cos(theta),
+                    -f32::sin(theta) * f32::cos(phi)
+                );
+                pixels.push(self.radiance(&direction));
+            }
+        }
+        Environment::new(SKY_WIDTH, SKY_HEIGHT, pixels, 0.0, 1.0).expect("the sky map has a pixel for every direction")
+    }
+
+    // angle between the sun and straight up in radians, kept just above the horizon where the model holds
+    fn sun_zenith(&self) -> f32 {
+        f32::min(90.0 - self.elevation, 89.5).to_radians()
+    }
+}
+
+// Perez et al. sky luminance distribution at (theta, gamma) relative to the zenith
+fn perez_ratio(coefficients: &[f32; 5], theta: f32, gamma: f32, theta_sun: f32) -> f32 {
+    let [a, b, c, d, e] = *coefficients;
+    let perez = |theta: f32, gamma: f32| {
+        (1.0 + a * f32::exp(b / f32::max(f32::cos(theta), 0.01)))
+            * (1.0 + c * f32::exp(d * gamma) + e * f32::cos(gamma) * f32::cos(gamma))
+    };
+    perez(theta, gamma) / perez(0.0, theta_sun)
+}
+
+// zenith chromaticity, a cubic in the sun angle for each power of the turbidity
+fn chromaticity(t: f32, theta_sun: f32, rows: &[[f32; 4]; 3]) -> f32 {
+    let cubic = |row: &[f32; 4]| ((row[0] * theta_sun + row[1]) * theta_sun + row[2]) * theta_sun + row[3];
+    t * t * cubic(&rows[0]) + t * cubic(&rows[1]) + cubic(&rows[2])
+}
+
+// CIE xyY to linear Rec. 709 RGB
+fn xyy_to_rgb(x: f32, y: f32, luminance: f32) -> Vec3 {
+    if y <= 0.0 {
+        return Vec3::new(0.0, 0.0, 0.0);
+    }
+    let big_x = x / y * luminance;
+    let big_z = (1.0 - x - y) / y * luminance;
+    Vec3::new(
+        f32::max(0.0, 3.2406 * big_x - 1.5372 * luminance - 0.4986 * big_z),
+        f32::max(0.0, -0.9689 * big_x + 1.8758 * luminance + 0.0415 * big_z),
+        f32::max(0.0, 0.0557 * big_x - 0.2040 * luminance + 1.0570 * big_z)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: &Vec3, b: &Vec3) -> bool {
+        (0..3).all(|i| (a[i] - b[i]).abs() < 1e-5)
+    }
+
+    #[test]
+    fn sun_direction_follows_elevation_and_azimuth() {
+        assert!(close(&Sky::new(90.0, 0.0, 3.0).sun_direction(), &Vec3::new(0.0, 1.0, 0.0)));
+        assert!(close(&Sky::new(0.0, 0.0, 3.0).sun_direction(), &Vec3::new(0.0, 0.0, -1.0)));
+        assert!(close(&Sky::new(0.0, 90.0, 3.0).sun_direction(), &Vec3::new(1.0, 0.0, 0.0)));
+        let h = f32::sqrt(0.5);
+        assert!(close(&Sky::new(45.0, 180.0, 3.0).sun_direction(), &Vec3::new(0.0, h, h)));
+
+        // and the light shines away from it
+        match Sky::new(30.0, 120.0, 3.0).sun_light() {
+            Light::Directional { direction, .. } => {
+                assert!(close(&(direction * -1.0), &Sky::new(30.0, 120.0, 3.0).sun_direction()));
+            },
+            other => panic!("expected a directional light, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn sky_is_brighter_towards_the_sun() {
+        for (elevation, turbidity) in [(5.0, 2.0), (30.0, 3.0), (60.0, 6.0), (85.0, 10.0)] {
+            let sky = Sky::new(elevation, 40.0, turbidity);
+            let sun = sky.sun_direction();
+            // the same height above the horizon, once beside the sun and once on the other side of the sky
+            let height = f32::max(elevation, 10.0).to_radians();
+            let beside = Sky { azimuth: sky.azimuth + 20.0, ..sky.clone() }.sun_direction_at(height);
+            let opposite = Sky { azimuth: sky.azimuth + 180.0, ..sky.clone() }.sun_direction_at(height);
+            let brightness = |v: Vec3| v[0] + v[1] + v[2];
+            assert!(
+                brightness(sky.radiance(&beside)) > brightness(sky.radiance(&opposite)),
+                "elevation {} turbidity {}", elevation, turbidity
+            );
+            assert!(brightness(sky.radiance(&sun)) > brightness(sky.radiance(&beside)));
+
+            // nothing negative anywhere down to the horizon, and nothing from below it
+            for degrees in [0.01, 1.0, 10.0, 45.0, 90.0] {
+                for azimuth in [0.0, 90.0, 180.0, 270.0] {
+                    let direction = Sky { azimuth, ..sky.clone() }.sun_direction_at(f32::to_radians(degrees));
+                    let radiance = sky.radiance(&direction);
+                    assert!((0..3).all(|i| radiance[i] >= 0.0 && radiance[i].is_finite()), "{:?} at {:?}", radiance, direction);
+                }
+            }
+            assert_eq!(sky.radiance(&Vec3::new(0.0, -1.0, 0.0)), Vec3::new(0.0, 0.0, 0.0));
+        }
+    }
+
+    impl Sky {
+        // direction at this sky's azimuth and `elevation` radians up
+        fn sun_direction_at(&self, elevation: f32) -> Vec3 {
+            Sky { elevation: elevation.to_degrees(), ..self.clone() }.sun_direction()
+        }
+    }
+}
@@ -0,0 +1,386 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::framebuffer::ImageError;
+use crate::hittable::{SurfacePoint, shading_frame};
+use crate::noise;
+use crate::vec3::Vec3;
+
+// step in uv for measuring the slope of height maps
+const BUMP_STEP: f32 = 1.0 / 1024.0;
+// how far turbulence bends the veins of marble and fbm the rings of wood, in stripes
+const MARBLE_DISTORTION: f32 = 1.5;
+const WOOD_DISTORTION: f32 = 0.15;
+
+/// Where a material parameter gets its value from at a point on a surface
+#[derive(Debug, Clone)]
+pub enum Texture {
+    Constant(Vec3),
+    // squares of side 1 / `scale` in uv space, alternating between `even` and `odd`
+    Checker2d { even: Box<Texture>, odd: Box<Texture>, scale: f32 },
+    // cubes of side 1 / `scale` in world or object space, for shapes without useful uvs
+    Checker3d { even: Box<Texture>, odd: Box<Texture>, scale: f32, space: Space },
+    Image(ImageTexture),
+    // blends from `low` to `high` as the pattern goes from 0 to 1. `scale` is how many features
+    // there are per unit, and `octaves` how many layers of detail fbm, turbulence, marble and wood have
+    Noise { pattern: Pattern, low: Box<Texture>, high: Box<Texture>, scale: f32, octaves: u32, space: Space },
+    // one channel of another texture times `scale`, as grey. for maps that pack several parameters into
+    // one image, like glTF's metallic roughness maps
+    Channel { texture: Box<Texture>, channel: usize, scale: f32 }
+}
+
+/// Which point solid textures use
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Space {
+    World,
+    // relative to the shape (the center of a sphere or mesh), so the pattern moves with it
+    Object
+}
+
+/// Procedural patterns for `Texture::Noise`, see the `noise` module
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Pattern {
+    Perlin,
+    Fbm,
+    Turbulence,
+    // veins along the x axis bent by turbulence
+    Marble,
+    // rings around the y axis, slightly wobbly
+    Wood
+}
+impl Pattern {
+    // value in [0, 1] at `p`
+    fn value(&self, p: &Vec3, octaves: u32) -> f32 {
+        let t = match self {
+            Pattern::Perlin => 0.5 * (1.0 + noise::perlin(p)),
+            Pattern::Fbm => 0.5 * (1.0 + noise::fbm(p, octaves)),
+            Pattern::Turbulence => noise::turbulence(p, octaves),
+            Pattern::Marble => {
+                let stripe = p[0] + MARBLE_DISTORTION * noise::turbulence(p, octaves);
+                0.5 * (1.0 + f32::sin(2.0 * std::f32::consts::PI * stripe))
+            },
+            Pattern::Wood => {
+                let radius = f32::sqrt(p[0] * p[0] + p[2] * p[2]) + WOOD_DISTORTION * noise::fbm(p, octaves);
+                radius.rem_euclid(1.0)
+            }
+        };
+        f32::clamp(t, 0.0, 1.0)
+    }
+}
+impl From<Vec3> for Texture {
+    fn from(color: Vec3) -> Texture {
+        Texture::Constant(color)
+    }
+}
+impl From<f32> for Texture {
+    fn from(value: f32) -> Texture {
+        Texture::Constant(Vec3::new(value, value, value))
+    }
+}
+impl Texture {
+    pub fn checker_2d(even: Texture, odd: Texture, scale: f32) -> Texture {
+        Texture::Checker2d { even: Box::new(even), odd: Box::new(odd), scale }
+    }
+
+    pub fn checker_3d(even: Texture, odd: Texture, scale: f32, space: Space) -> Texture {
+        Texture::Checker3d { even: Box::new(even), odd: Box::new(odd), scale, space }
+    }
+
+    pub fn noise(pattern: Pattern, low: Texture, high: Texture, scale: f32, octaves: u32, space: Space) -> Texture {
+        Texture::Noise { pattern, low: Box::new(low), high: Box::new(high), scale, octaves: u32::max(octaves, 1), space }
+    }
+
+    pub fn channel(texture: Texture, channel: usize, scale: f32) -> Texture {
+        assert!(channel < 3, "a texture has only the channels 0, 1 and 2");
+        Texture::Channel { texture: Box::new(texture), channel, scale }
+    }
+
+    /// Value at a point on a surface
+    pub fn value(&self, surface: &SurfacePoint) -> Vec3 {
+        match self {
+            Texture::Constant(color) => color.clone(),
+            Texture::Checker2d { even, odd, scale } => {
+                let (u, v) = surface.uv;
+                let cell = f32::floor(u * scale) + f32::floor(v * scale);
+                if cell.rem_euclid(2.0) == 0.0 { even.value(surface) } else { odd.value(surface) }
+            },
+            Texture::Checker3d { even, odd, scale, space } => {
+                let p = solid_point(surface, *space);
+                let cell = f32::floor(p[0] * scale) + f32::floor(p[1] * scale) + f32::floor(p[2] * scale);
+                if cell.rem_euclid(2.0) == 0.0 { even.value(surface) } else { odd.value(surface) }
+            },
+            Texture::Image(image) => image.value(surface.uv),
+            Texture::Noise { pattern, low, high, scale, octaves, space } => {
+                let t = pattern.value(&(solid_point(surface, *space).clone() * *scale), *octaves);
+                low.value(surface) * (1.0 - t) + high.value(surface) * t
+            },
+            Texture::Channel { texture, channel, scale } => {
+                let value = texture.value(surface)[*channel] * scale;
+                Vec3::new(value, value, value)
+            }
+        }
+    }
+}
+
+/// Tilts the shading normal to fake small bumps and dents without changing the shape.
+/// Both kinds follow the uvs, shapes without useful uvs get bumps in odd directions.
+#[derive(Debug, Clone)]
+pub enum Bump {
+    // tangent space normals stored in a linear image, red along u, green along v and blue out of the surface.
+    // `strength` scales the tilt, 1 uses the map as it is
+    Normal { map: ImageTexture, strength: f32 },
+    // heights, the average of red, green and blue. `scale` is how far out a height of 1 is, in the same
+    // units as the shape
+    Height { map: Texture, scale: f32 }
+}
+impl Bump {
+    /// Tilts the normal of `surface` and turns its tangent frame along
+    pub fn apply(&self, surface: &mut SurfacePoint) {
+        let normal = match self {
+            Bump::Normal { map, strength } => {
+                let n = map.value(surface.uv) * 2.0 - 1.0;
+                surface.tangent.clone() * (n[0] * strength)
+                    + surface.bitangent.clone() * (n[1] * strength)
+                    + surface.normal.clone() * n[2]
+            },
+            Bump::Height { map, scale } => {
+                // how fast the height changes along u and v, measured a small step away
+                let height = |surface: &SurfacePoint| {
+                    let h = map.value(surface);
+                    (h[0] + h[1] + h[2]) / 3.0 * scale
+                };
+                let h = height(surface);
+                let step = |du: f32, dv: f32| {
+                    let offset = surface.dpdu.clone() * du + surface.dpdv.clone() * dv;
+                    let mut shifted = surface.clone();
+                    shifted.uv = (surface.uv.0 + du, surface.uv.1 + dv);
+                    shifted.point = surface.point.clone() + offset.clone();
+                    shifted.local_point = surface.local_point.clone() + offset;
+                    (height(&shifted) - h) / BUMP_STEP
+                };
+                let (dhdu, dhdv) = (step(BUMP_STEP, 0.0), step(0.0, BUMP_STEP));
+                // the displaced surface moves along the normal as the height changes
+                let dpdu = surface.dpdu.clone() + surface.normal.clone() * dhdu;
+                let dpdv = surface.dpdv.clone() + surface.normal.clone() * dhdv;
+                let normal = dpdu.cross(&dpdv);
+                // uvs can run either way round
+                if normal.dot(&surface.normal) < 0.0 { normal * -1.0 } else { normal }
+            }
+        };
+
+        let length = f32::sqrt(normal.dot(&normal));
+        let normal = normal / length;
+        // a normal tilted past the surface itself would light the back of it, those are left alone
+        if !length.is_finite() || length == 0.0 || normal.dot(&surface.geometric_normal) <= 0.0 {
+            return;
+        }
+        let (tangent, bitangent) = shading_frame(&normal, &surface.dpdu, &surface.dpdv);
+        surface.normal = normal;
+        surface.tangent = tangent;
+        surface.bitangent = bitangent;
+    }
+}
+
+fn solid_point(surface: &SurfacePoint, space: Space) -> &Vec3 {
+    match space {
+        Space::World => &surface.point,
+        Space::Object => &surface.local_point
+    }
+}
+
+/// What happens to uvs outside of [0, 1]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WrapMode {
+    // the image tiles
+    Repeat,
+    // every other tile is flipped so the edges line up
+    Mirror,
+    // the edge pixels go on forever
+    Clamp
+}
+
+/// An image looked up by uv with bilinear filtering, (0, 0) is the lower left corner
+#[derive(Clone)]
+pub struct ImageTexture {
+    // shared so materials can be cloned without copying the pixels
+    pixels: Arc<Vec<Vec3>>,
+    width: usize,
+    height: usize,
+    pub wrap: WrapMode,
+    // where the image was loaded from and whether it was decoded from sRGB, for saving scenes
+    path: Option<PathBuf>,
+    srgb: bool
+}
+impl fmt::Debug for ImageTexture {
+    // leaves out the pixels
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ImageTexture")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("wrap", &self.wrap)
+            .field("path", &self.path)
+            .finish()
+    }
+}
+impl ImageTexture {
+    /// Reads a png or jpeg. Colors are stored as sRGB and turned linear with `srgb`,
+    /// data like normal maps are stored linear already.
+    pub fn load<P: AsRef<Path>>(path: P, wrap: WrapMode, srgb: bool) -> Result<ImageTexture, ImageError> {
+        let path = path.as_ref();
+        let image = image::open(path).map_err(|err| match err {
+            image::ImageError::IoError(err) => ImageError::Io(err),
+            err => ImageError::Decode(err.to_string())
+        })?.into_rgb32f();
+
+        let decode = |x: f32| if srgb { srgb_to_linear(x) } else { x };
+        let pixels = image.pixels().map(|p| Vec3::new(decode(p[0]), decode(p[1]), decode(p[2]))).collect();
+        let mut texture = ImageTexture::new(image.width() as usize, image.height() as usize, pixels, wrap);
+        texture.path = Some(path.to_path_buf());
+        texture.srgb = srgb;
+        Ok(texture)
+    }
+
+    /// Texture from linear colors, `pixels` goes row by row from the top
+    pub fn new(width: usize, height: usize, pixels: Vec<Vec3>, wrap: WrapMode) -> ImageTexture {
+        assert!(width > 0 && height > 0, "an image texture needs at least one pixel");
+        assert_eq!(pixels.len(), width * height, "image texture needs width * height pixels");
+        ImageTexture { pixels: Arc::new(pixels), width, height, wrap, path: None, srgb: false }
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    pub fn srgb(&self) -> bool {
+        self.srgb
+    }
+
+    pub fn value(&self, uv: (f32, f32)) -> Vec3 {
+        // pixel centers are at half pixels, v goes up while rows go down
+        let x = uv.0 * self.width as f32 - 0.5;
+        let y = (1.0 - uv.1) * self.height as f32 - 0.5;
+        let (x0, y0) = (f32::floor(x), f32::floor(y));
+        let (fx, fy) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i64, y0 as i64);
+
+        let texel = |x: i64, y: i64| {
+            let x = wrap(x, self.width, self.wrap);
+            let y = wrap(y, self.height, self.wrap);
+            self.pixels[y * self.width + x].clone()
+        };
+        let top = texel(x0, y0) * (1.0 - fx) + texel(x0 + 1, y0) * fx;
+        let bottom = texel(x0, y0 + 1) * (1.0 - fx) + texel(x0 + 1, y0 + 1) * fx;
+        top * (1.0 - fy) + bottom * fy
+    }
+}
+
+// pixel index `i` wrapped into [0, n)
+fn wrap(i: i64, n: usize, mode: WrapMode) -> usize {
+    let n = n as i64;
+    let i = match mode {
+        WrapMode::Repeat => i.rem_euclid(n),
+        WrapMode::Mirror => {
+            let i = i.rem_euclid(2 * n);
+            if i >= n { 2 * n - 1 - i } else { i }
+        },
+        WrapMode::Clamp => i.clamp(0, n - 1)
+    };
+    i as usize
+}
+
+/// The inverse of `tonemap::srgb_oetf`, encoded values in [0, 1] to linear ones
+pub fn srgb_to_linear(x: f32) -> f32 {
+    if x <= 0.04045 {
+        x / 12.92
+    } else {
+        f32::powf((x + 0.055) / 1.055, 2.4)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tonemap::srgb_oetf;
+
+    // a point facing +z at `point` with the given uv
+    fn surface(point: Vec3, uv: (f32, f32)) -> SurfacePoint {
+        let z = Vec3::new(0.0, 0.0, 1.0);
+        SurfacePoint {
+            point: point.clone(),
+            local_point: point,
+            normal: z.clone(),
+            geometric_normal: z,
+            tangent: Vec3::new(1.0, 0.0, 0.0),
+            bitangent: Vec3::new(0.0, 1.0, 0.0),
+            dpdu: Vec3::new(1.0, 0.0, 0.0),
+            dpdv: Vec3::new(0.0, 1.0, 0.0),
+            uv,
+            front_face: true
+        }
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    #[test]
+    fn checkers_alternate() {
+        let at_uv = |uv| surface(Vec3::new(0.0, 0.0, 0.0), uv);
+        let checker = Texture::checker_2d(Texture::from(1.0), Texture::from(0.0), 2.0);
+        assert_eq!(checker.value(&at_uv((0.25, 0.25)))[0], 1.0);
+        assert_eq!(checker.value(&at_uv((0.75, 0.25)))[0], 0.0);
+        assert_eq!(checker.value(&at_uv((0.25, 0.75)))[0], 0.0);
+        assert_eq!(checker.value(&at_uv((0.75, 0.75)))[0], 1.0);
+        // and keep alternating on the other side of 0
+        assert_eq!(checker.value(&at_uv((-0.25, 0.25)))[0], 0.0);
+        assert_eq!(checker.value(&at_uv((-0.25, -0.25)))[0], 1.0);
+
+        let mut moved = surface(Vec3::new(0.5, 0.5, 1.5), (0.0, 0.0));
+        moved.local_point = Vec3::new(0.5, 0.5, 0.5);
+        let world = Texture::checker_3d(Texture::from(1.0), Texture::from(0.0), 1.0, Space::World);
+        let object = Texture::checker_3d(Texture::from(1.0), Texture::from(0.0), 1.0, Space::Object);
+        assert_eq!(world.value(&moved)[0], 0.0);
+        assert_eq!(object.value(&moved)[0], 1.0);
+        assert_eq!(world.value(&surface(Vec3::new(-0.5, 0.5, 0.5), (0.0, 0.0)))[0], 0.0);
+    }
+
+    #[test]
+    fn images_are_filtered_and_wrapped() {
+        // a black and a white pixel, their centers are at u = 0.25 and 0.75
+        let image = |wrap| ImageTexture::new(2, 1, vec![Vec3::new(0.0, 0.0, 0.0), Vec3::new(1.0, 1.0, 1.0)], wrap);
+        let repeat = image(WrapMode::Repeat);
+        assert!(close(repeat.value((0.25, 0.5))[0], 0.0));
+        assert!(close(repeat.value((0.75, 0.5))[0], 1.0));
+        // bilinear between the two centers
+        assert!(close(repeat.value((0.5, 0.5))[0], 0.5));
+        assert!(close(repeat.value((0.375, 0.5))[0], 0.25));
+        // v does not matter in a single row
+        assert!(close(repeat.value((0.375, 0.9))[0], 0.25));
+
+        // past the edges
+        let clamp = image(WrapMode::Clamp);
+        let mirror = image(WrapMode::Mirror);
+        assert!(close(repeat.value((1.25, 0.5))[0], 0.0));
+        assert!(close(clamp.value((1.25, 0.5))[0], 1.0));
+        assert!(close(mirror.value((1.25, 0.5))[0], 1.0));
+        assert!(close(repeat.value((-0.25, 0.5))[0], 1.0));
+        assert!(close(clamp.value((-0.25, 0.5))[0], 0.0));
+        assert!(close(mirror.value((-0.25, 0.5))[0], 0.0));
+        // the left edge blends with the right one only when repeating
+        assert!(close(repeat.value((0.0, 0.5))[0], 0.5));
+        assert!(close(clamp.value((0.0, 0.5))[0], 0.0));
+    }
+
+    #[test]
+    fn srgb_decodes_to_linear() {
+        assert_eq!(srgb_to_linear(0.0), 0.0);
+        assert!(close(srgb_to_linear(1.0), 1.0));
+        assert!(close(srgb_to_linear(0.5), 0.214_041));
+        // both pieces meet at the knee
+        assert!(close(0.04045 / 12.92, f32::powf((0.04045 + 0.055) / 1.055, 2.4)));
+        for x in [0.001, 0.003, 0.01, 0.2, 0.5, 0.9] {
+            assert!(close(srgb_to_linear(srgb_oetf(x)), x), "{}", x);
+        }
+    }
+}
@@ -0,0 +1,135 @@
+use crate::vec3::Vec3;
+
+/// How colors brighter than 1 are squeezed into what a screen can show
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    // anything above 1 is cut off
+    Clamp,
+    // L / (1 + L) on the luminance, never quite reaches white
+    Reinhard,
+    // Reinhard that reaches white at luminance `white`, which has to be more than 0
+    ExtendedReinhard { white: f32 },
+    // Krzysztof Narkowicz's fit of the ACES filmic curve
+    Aces
+}
+
+/// Turns linear scene colors into 8 bit sRGB for png and ppm
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToneMapping {
+    // in stops, +1 doubles the brightness
+    pub exposure: f32,
+    pub operator: Operator
+}
+impl Default for ToneMapping {
+    fn default() -> ToneMapping {
+        ToneMapping { exposure: 0.0, operator: Operator::Clamp }
+    }
+}
+impl ToneMapping {
+    pub fn new(exposure: f32, operator: Operator) -> ToneMapping {
+        ToneMapping { exposure, operator }
+    }
+
+    /// Linear color with every component in [0, 1]
+    pub fn apply(&self, color: &Vec3) -> Vec3 {
+        let color = color.clone() * f32::powf(2.0, self.exposure);
+        let mapped = match self.operator {
+            Operator::Clamp => color,
+            Operator::Reinhard => {
+                let l = luminance(&color);
+                scale_luminance(color, l, l / (1.0 + l))
+            },
+            Operator::ExtendedReinhard { white } => {
+                let l = luminance(&color);
+                scale_luminance(color, l, l * (1.0 + l / (white * white)) / (1.0 + l))
+            },
+            Operator::Aces => {
+                // the fit expects the input scaled down a bit
+                let c = color * 0.6;
+                let curve = |x: f32| (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14);
+                Vec3::new(curve(c[0]), curve(c[1]), curve(c[2]))
+            }
+        };
+        Vec3::new(
+            f32::clamp(mapped[0], 0.0, 1.0),
+            f32::clamp(mapped[1], 0.0, 1.0),
+            f32::clamp(mapped[2], 0.0, 1.0)
+        )
+    }
+
+    pub fn to_srgb8(&self, color: &Vec3) -> [u8; 3] {
+        let mapped = self.apply(color);
+        let quantize = |x: f32| (srgb_oetf(x) * 255.0 + 0.5) as u8;
+        [quantize(mapped[0]), quantize(mapped[1]), quantize(mapped[2])]
+    }
+}
+
+/// The piecewise sRGB transfer function, linear values in [0, 1] to encoded ones
+pub fn srgb_oetf(x: f32) -> f32 {
+    if x <= 0.0031308 {
+        12.92 * x
+    } else {
+        1.055 * f32::powf(x, 1.0 / 2.4) - 0.055
+    }
+}
+
+/// Relative luminance of a linear Rec. 709 color
+pub fn luminance(color: &Vec3) -> f32 {
+    0.2126 * color[0] + 0.7152 * color[1] + 0.0722 * color[2]
+}
+
+// scales the color so its luminance goes from `from` to `to`, which keeps the hue
+fn scale_luminance(color: Vec3, from: f32, to: f32) -> Vec3 {
+    if from <= 0.0 {
+        return color;
+    }
+    color * (to / from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-5
+    }
+
+    #[test]
+    fn srgb_curve_is_continuous() {
+        assert_eq!(srgb_oetf(0.0), 0.0);
+        assert!(close(srgb_oetf(1.0), 1.0));
+        // both pieces give the same value at the knee
+        let knee = 0.0031308;
+        assert!(close(12.92 * knee, 1.055 * f32::powf(knee, 1.0 / 2.4) - 0.055));
+        assert!(close(srgb_oetf(knee), 0.04045));
+        assert!(close(srgb_oetf(knee + 1e-7), 0.04045));
+        assert!(close(srgb_oetf(0.214_041), 0.5));
+    }
+
+    #[test]
+    fn operators_map_into_the_screen_range() {
+        let operators = [Operator::Clamp, Operator::Reinhard, Operator::ExtendedReinhard { white: 4.0 }, Operator::Aces];
+        for operator in operators {
+            let tone_mapping = ToneMapping::new(0.0, operator);
+            // greys, whose luminance is their value
+            let grey = |x: f32| tone_mapping.apply(&Vec3::new(x, x, x))[0];
+            assert_eq!(grey(0.0), 0.0, "{:?}", operator);
+            let mut last = 0.0;
+            for i in 1..=1000 {
+                let mapped = grey(i as f32 * 0.02);
+                assert!(mapped >= last && mapped <= 1.0, "{:?} maps {} to {} after {}", operator, i as f32 * 0.02, mapped, last);
+                last = mapped;
+            }
+            assert!(close(grey(1e6), 1.0), "{:?}", operator);
+        }
+
+        // reinhard only gets close to white, the extended one gets there at its white point
+        assert!(ToneMapping::new(0.0, Operator::Reinhard).apply(&Vec3::new(4.0, 4.0, 4.0))[0] < 0.81);
+        let extended = ToneMapping::new(0.0, Operator::ExtendedReinhard { white: 4.0 });
+        assert!(close(extended.apply(&Vec3::new(4.0, 4.0, 4.0))[0], 1.0));
+        assert!(extended.apply(&Vec3::new(3.9, 3.9, 3.9))[0] < 1.0);
+        // exposure is in stops
+        let brighter = ToneMapping::new(1.0, Operator::Clamp);
+        assert!(close(brighter.apply(&Vec3::new(0.25, 0.1, 0.0))[0], 0.5));
+    }
+}
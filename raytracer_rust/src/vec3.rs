@@ -1,6 +1,7 @@
 use std::ops::{Add, Mul, Div, Sub, Index, IndexMut};
 use std::fmt;
-use rand::Rng;
+use std::cell::RefCell;
+use rand::{Rng, SeedableRng, rngs::StdRng};
 
 
 #[derive(Debug, Clone, PartialEq)]
@@ -16,17 +17,14 @@ impl Vec3 {
         self[0]*other[0] + self[1]*other[1] + self[2]*other[2]
     }
 
-    pub fn red() -> Vec3 {
-        Vec3 { xyz: [1.0, 0.0, 0.0] }
+    pub fn cross(&self, other: &Vec3) -> Vec3 {
+        Vec3::new(
+            self[1]*other[2] - self[2]*other[1],
+            self[2]*other[0] - self[0]*other[2],
+            self[0]*other[1] - self[1]*other[0]
+        )
     }
 
-    pub fn black() -> Vec3 {
-        Vec3 { xyz: [0.0, 0.0, 0.0] }
-    }
-
-    pub fn white() -> Vec3 {
-        Vec3 { xyz: [1.0, 1.0, 1.0] }
-    }
 }
 //////////////////////////////////////// INDEXING
 impl Index<usize> for Vec3 {
@@ -53,77 +51,69 @@ impl IndexMut<usize> for Vec3 {
 //////////////////////////////////////// ADD
 impl Add for Vec3 {
     type Output = Vec3;
-    fn add(mut self, B: Vec3) -> Vec3 {
-        self[0] += B[0];
-        self[1] += B[1];
-        self[2] += B[2];
+    fn add(mut self, b: Vec3) -> Vec3 {
+        self[0] += b[0];
+        self[1] += b[1];
+        self[2] += b[2];
         self
     }
 }
 impl Add<f32> for Vec3 {
     type Output = Vec3;
-    fn add(mut self, f: f32) -> Vec3 {
-        let F = Vec3::new(f, f, f);
-        self = self + F;
-        self
+    fn add(self, f: f32) -> Vec3 {
+        self + Vec3::new(f, f, f)
     }
 }
 
 //////////////////////////////////////// SUB
 impl Sub for Vec3 {
     type Output = Vec3;
-    fn sub(mut self, B: Vec3) -> Vec3 {
-        self[0] -= B[0];
-        self[1] -= B[1];
-        self[2] -= B[2];
+    fn sub(mut self, b: Vec3) -> Vec3 {
+        self[0] -= b[0];
+        self[1] -= b[1];
+        self[2] -= b[2];
         self
     }
 }
 impl Sub<f32> for Vec3 {
     type Output = Vec3;
-    fn sub(mut self, f: f32) -> Vec3 {
-        let F = Vec3::new(f, f, f);
-        self = self - F;
-        self
+    fn sub(self, f: f32) -> Vec3 {
+        self - Vec3::new(f, f, f)
     }
 }
 
 //////////////////////////////////////// MUL
 impl Mul for Vec3 {
     type Output = Vec3;
-    fn mul(mut self, B: Vec3) -> Vec3 {
-        self[0] *= B[0];
-        self[1] *= B[1];
-        self[2] *= B[2];
+    fn mul(mut self, b: Vec3) -> Vec3 {
+        self[0] *= b[0];
+        self[1] *= b[1];
+        self[2] *= b[2];
         self
     }
 }
 impl Mul<f32> for Vec3 {
     type Output = Vec3;
-    fn mul(mut self, f: f32) -> Vec3 {
-        let F = Vec3::new(f, f, f);
-        self = self * F;
-        self
+    fn mul(self, f: f32) -> Vec3 {
+        self * Vec3::new(f, f, f)
     }
 }
 
 //////////////////////////////////////// DIV
 impl Div for Vec3 {
     type Output = Vec3;
-    fn div(mut self, B: Vec3) -> Vec3 {
-        self[0] /= B[0];
-        self[1] /= B[1];
-        self[2] /= B[2];
+    fn div(mut self, b: Vec3) -> Vec3 {
+        self[0] /= b[0];
+        self[1] /= b[1];
+        self[2] /= b[2];
         self
     }
 }
 
 impl Div<f32> for Vec3 {
     type Output = Vec3;
-    fn div(mut self, f: f32) -> Vec3 {
-        let F = Vec3::new(f, f, f);
-        self = self / F;
-        self
+    fn div(self, f: f32) -> Vec3 {
+        self / Vec3::new(f, f, f)
     }
 }
 //////////////////////////////////////// DISPLAY
@@ -139,24 +129,6 @@ impl fmt::Display for Vec3 {
     }
 }
 
-pub fn clamp(v: &Vec3) -> Vec3{
-    Vec3::new(
-        clamp_f32(v[0]),
-        clamp_f32(v[1]),
-        clamp_f32(v[2])
-    )
-}
-
-fn clamp_f32(f: f32) -> f32 {
-    if f < 0.0 {
-        return 0.0
-    } else if f > 0.999 {
-        return 0.999
-    } else {
-        return f
-    }
-}
-
 pub fn length(v: Vec3) -> f32 {
     f32::sqrt(
         v[0]*v[0] + v[1]*v[1] + v[2]*v[2]
@@ -167,23 +139,108 @@ pub fn normalize(v: &Vec3) -> Vec3 {
     v.clone() / length(v.clone())
 }
 
+thread_local! {
+    // every thread draws from its own generator so they never wait on each other
+    static RNG: RefCell<StdRng> = RefCell::new(StdRng::from_entropy());
+}
+
+/// Restarts the random numbers of the calling thread from `seed`, the same seed
+/// gives the same numbers in the same order
+pub fn seed_rng(seed: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = StdRng::seed_from_u64(seed));
+}
+
+fn with_rng<T, F: FnOnce(&mut StdRng) -> T>(f: F) -> T {
+    RNG.with(|rng| f(&mut rng.borrow_mut()))
+}
+
 pub fn random_f32() -> f32 {
-    let mut rng = rand::thread_rng();
-    return rng.gen_range(0.0..0.9);
+    with_rng(|rng| rng.gen_range(0.0..1.0))
 }
 
 pub fn random_in_unit_sphere() -> Vec3 {
-    let mut rng = rand::thread_rng();
-    
-    let rho = rng.gen_range(0.0..0.9);
-    let theta = rng.gen_range(0.0..360.0);
-    let phi = rng.gen_range(0.0..180.0);
+    with_rng(|rng| {
+        // pick points in the surrounding cube until one lands inside the sphere
+        loop {
+            let p = Vec3::new(
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0)
+            );
+            if p.dot(&p) < 1.0 {
+                return p;
+            }
+        }
+    })
+}
 
-    let x = rho * f32::sin(phi) * f32::cos(theta);
-    let y = rho * f32::sin(phi) * f32::sin(theta);
-    let z = rho * f32::cos(phi);
+/// Random point in the disk of radius 1 around the origin in the xy plane
+pub fn random_in_unit_disk() -> Vec3 {
+    with_rng(|rng| {
+        loop {
+            let p = Vec3::new(
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0),
+                0.0
+            );
+            if p.dot(&p) < 1.0 {
+                return p;
+            }
+        }
+    })
+}
+
+/// Two unit vectors that together with the unit vector `n` make an orthonormal basis
+pub fn orthonormal_basis(n: &Vec3) -> (Vec3, Vec3) {
+    // Duff et al. 2017, "Building an Orthonormal Basis, Revisited"
+    let sign = f32::copysign(1.0, n[2]);
+    let a = -1.0 / (sign + n[2]);
+    let b = n[0] * n[1] * a;
+    (
+        Vec3::new(1.0 + sign * n[0] * n[0] * a, sign * b, -sign * n[0]),
+        Vec3::new(b, sign + n[1] * n[1] * a, -n[1])
+    )
+}
+
+/// Random direction on the hemisphere around `n`, more likely the closer it is to `n` (pdf = cos / pi)
+pub fn random_cosine_direction(n: &Vec3) -> Vec3 {
+    let r1 = random_f32();
+    let r2 = random_f32();
+
+    let phi = 2.0 * std::f32::consts::PI * r1;
+    let r = f32::sqrt(r2);
+    let (t, b) = orthonormal_basis(n);
+    t * (r * f32::cos(phi)) + b * (r * f32::sin(phi)) + n.clone() * f32::sqrt(1.0 - r2)
+}
+
+/// Random direction at most acos(`cos_max`) away from the unit vector `axis`, uniform over the solid angle
+pub fn random_in_cone(axis: &Vec3, cos_max: f32) -> Vec3 {
+    let cos_theta = 1.0 - random_f32() * (1.0 - cos_max);
+    let sin_theta = f32::sqrt(f32::max(0.0, 1.0 - cos_theta * cos_theta));
+    let phi = 2.0 * std::f32::consts::PI * random_f32();
+    let (t, b) = orthonormal_basis(axis);
+    t * (sin_theta * f32::cos(phi)) + b * (sin_theta * f32::sin(phi)) + axis.clone() * cos_theta
+}
+
+/// Mirrors `v` about the plane with normal `n`
+pub fn reflect(v: &Vec3, n: &Vec3) -> Vec3 {
+    v.clone() - n.clone() * (2.0 * v.dot(n))
+}
+
+/// Bends the unit vector `v` through a surface with normal `n` (pointing against `v`),
+/// `eta_ratio` is the index of refraction on the side `v` comes from over the one it goes into
+pub fn refract(v: &Vec3, n: &Vec3, eta_ratio: f32) -> Vec3 {
+    let cos_theta = f32::min(-v.dot(n), 1.0);
+    let out_perpendicular = (v.clone() + n.clone() * cos_theta) * eta_ratio;
+    let out_parallel = n.clone() * -f32::sqrt(f32::abs(1.0 - out_perpendicular.dot(&out_perpendicular)));
+    out_perpendicular + out_parallel
+}
 
-    return Vec3::new(x, y, z);
+/// Schlick's approximation of how much light is reflected at a dielectric surface
+pub fn reflectance(cos_theta: f32, eta_ratio: f32) -> f32 {
+    let r0 = (1.0 - eta_ratio) / (1.0 + eta_ratio);
+    let r0 = r0 * r0;
+    r0 + (1.0 - r0) * f32::powi(1.0 - cos_theta, 5)
 }
 
 pub struct Color {
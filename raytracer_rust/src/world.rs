@@ -1,313 +1,814 @@
 use crate::hittable::*;
-use crate::vec3::{Vec3, Color, normalize, random_in_unit_sphere, random_f32};
+use crate::bsdf::{Bsdf, Frame};
+use crate::microfacet::fresnel_conductor;
+use crate::vec3::{Vec3, Color, random_f32, seed_rng, random_in_unit_sphere, reflect, refract, reflectance, length};
 use crate::ray::Ray;
+use crate::bvh::Bvh;
+use crate::camera::Camera;
+use crate::light::{Light, LightSample};
+use crate::environment::Environment;
+use crate::sky::Sky;
+use crate::framebuffer::Framebuffer;
+use crate::progress::{Progress, ProgressReporter, RenderStats};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, Ordering};
+use rayon::prelude::*;
+use std::fmt;
+
+// rays leaving a surface start this far off it so they do not hit it again right away
+const SURFACE_OFFSET: f32 = 1e-4;
+// shadow rays towards area lights end this fraction of the distance before the light
+const SHADOW_EPSILON: f32 = 1e-3;
+// paths always get this many bounces before russian roulette may end them
+const MIN_BOUNCES: u32 = 3;
+/// Radiance of the sun when none is given. A sun of radius 1 that is 7 away
+/// lights a white surface facing it about fully
+pub const SUN_RADIANCE: f32 = 50.0;
+
+#[derive(Debug)]
+pub enum SunError {
+    // a plane or another shape without a bounding box, points on it can not be sampled
+    Unbounded
+}
+impl fmt::Display for SunError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SunError::Unbounded => write!(f, "the sun has to be a bounded shape")
+        }
+    }
+}
+impl std::error::Error for SunError {}
+
+// side of the square tiles the image is split into for rendering in parallel
+const TILE_SIZE: i32 = 16;
+
+struct Tile {
+    x: i32,
+    y: i32,
+    width: i32,
+    height: i32
+}
+
+// color, depth and normal of a pixel
+type RenderedPixel = (Vec3, f32, Vec3);
+
+thread_local! {
+    // rays traced by this thread since the count was last taken, see `take_ray_count`
+    static RAYS_TRACED: Cell<u64> = const { Cell::new(0) };
+}
+
+fn count_ray() {
+    RAYS_TRACED.with(|rays| rays.set(rays.get() + 1));
+}
+
+fn take_ray_count() -> u64 {
+    RAYS_TRACED.with(|rays| rays.replace(0))
+}
+
+/// Splits the image into tiles, the ones along the right and top edge may be smaller
+fn tiles(width: i32, height: i32) -> Vec<Tile> {
+    let mut tiles = vec![];
+    for y in (0..height).step_by(TILE_SIZE as usize) {
+        for x in (0..width).step_by(TILE_SIZE as usize) {
+            tiles.push(Tile {
+                x,
+                y,
+                width: i32::min(TILE_SIZE, width - x),
+                height: i32::min(TILE_SIZE, height - y)
+            });
+        }
+    }
+    tiles
+}
+
+// where a path last bounced off a lambertian surface, off which hittable and how likely the direction it took was
+struct Bounce {
+    origin: Vec3,
+    pdf: f32,
+    hittable: usize
+}
+
+// where a ray leaving the surface in `direction` starts, just off the side it goes out of.
+// None if the shading normal and the real surface disagree about which side that is
+fn leaving_point(surface: &SurfacePoint, direction: &Vec3) -> Option<Vec3> {
+    let outside = direction.dot(&surface.geometric_normal);
+    if outside == 0.0 || (outside > 0.0) != (direction.dot(&surface.normal) > 0.0) {
+        return None;
+    }
+    Some(surface.point.clone() + surface.geometric_normal.clone() * SURFACE_OFFSET.copysign(outside))
+}
+
+/// Weight for a sample taken with density `pdf` when another strategy would have taken it with `other_pdf`
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b == 0.0 { 0.0 } else { a / (a + b) }
+}
 
 pub struct World {
     hittables: Vec<Hittable>,
-    width: i32,
-    sun: Hittable
+    // an emissive area light that the camera does not see
+    sun: Option<Hittable>,
+    lights: Vec<Light>,
+    // what rays that leave the scene see, black if None
+    environment: Option<Environment>,
+    // the sky the environment was made from and its sun, see `set_sky`
+    sky: Option<(Sky, Light)>,
+    // how many times a path may bounce before it is cut off
+    max_depth: u32,
+    samples_per_pixel: u32,
+    // renders with the same seed come out exactly the same, None picks a new one every time
+    seed: Option<u64>,
+    progress: Progress,
+    // acceleration structure over every hittable with a bounding box, see `build_bvh`
+    bvh: Option<Bvh>,
+    // hittables without a bounding box (planes) which are tested against every ray
+    unbounded: Vec<usize>,
+    // emissive hittables that can be sampled as area lights, sorted
+    emitters: Vec<usize>
+}
+impl Default for World {
+    fn default() -> World {
+        World::new()
+    }
 }
 impl World {
-    pub fn new(width: i32) -> World {
+    pub fn new() -> World {
         let sun = Hittable {
             // default sun
-            shape: Shape::sphere(Vec3::new(3.0, 8.0, 2.0), 1.0), material: Material::Light
+            shape: Shape::sphere(Vec3::new(3.0, 8.0, 2.0), 1.0), material: Material::Emissive(Color::white() * SUN_RADIANCE)
         };
-        World { hittables: vec![], width, sun}
+        World { hittables: vec![], sun: Some(sun), lights: vec![], environment: None, sky: None, max_depth: 10, samples_per_pixel: 20, seed: None, progress: Progress::Silent, bvh: None, unbounded: vec![], emitters: vec![] }
     }
 
     pub fn add(&mut self, shape: Shape, material: Material) {
         self.hittables.push(
             Hittable::new(shape, material)
         );
+        // the old tree does not know about the new hittable
+        self.bvh = None;
     }
 
-    pub fn set_sun(&mut self, shape: Shape) {
-        self.sun = Hittable {
-            shape, material: Material::Light
+    /// The sun is an area light that glows with `radiance`, it lights the scene and shows up in reflections
+    /// but is not seen by the camera. The shape needs a bounding box, points on a plane can not be sampled.
+    pub fn set_sun(&mut self, shape: Shape, radiance: Vec3) -> Result<(), SunError> {
+        let sun = Hittable {
+            shape, material: Material::Emissive(radiance)
+        };
+        if sun.bounding_box().is_none() {
+            return Err(SunError::Unbounded);
         }
+        self.sun = Some(sun);
+        Ok(())
     }
 
-    pub fn render(self) {
-        let WIDTH: i32 = self.width;
-        let ASPECT_RATIO: f32 = 16.0/9.0;
-        let HEIGHT: i32 = (WIDTH as f32 / ASPECT_RATIO) as i32;
-        
+    /// Leaves only the lights added with `add_light`
+    pub fn remove_sun(&mut self) {
+        self.sun = None;
+    }
 
-        let camera = Vec3::new(0.0, 0.0, 10.0);
+    pub fn add_light(&mut self, light: Light) {
+        self.lights.push(light);
+    }
 
-        let u_range = 4.0;
-        let v_range = u_range / ASPECT_RATIO;
+    /// Rays that miss everything pick up light from the environment, which also lights the scene
+    pub fn set_environment(&mut self, environment: Environment) {
+        self.environment = Some(environment);
+        self.sky = None;
+    }
 
-        let samples_per_pixel = 20;
+    pub fn remove_environment(&mut self) {
+        self.environment = None;
+        self.sky = None;
+    }
 
-        let lower_left_corner = Vec3::new(
-            0.0 - u_range / 2.0, 
-            0.0 - v_range / 2.0, 
-            0.0
-        );
+    /// Uses the sky as the environment and lights the scene with its sun, which replaces the sun set with `set_sun`.
+    /// The sky's sun is a directional light and not part of the environment, so it gives light and shadows
+    /// but mirrors, glossy metal and glass do not show its disk. Use `set_sun` for a sun that shows up in them
+    pub fn set_sky(&mut self, sky: Sky) {
+        self.environment = Some(sky.to_environment());
+        self.sun = None;
+        let sun = sky.sun_light();
+        self.sky = Some((sky, sun));
+    }
 
-        // preamble
-        println!("P3\n{} {}\n255", WIDTH, HEIGHT);
-
-        for y in (0..HEIGHT).rev() {
-            for x in 0..WIDTH {
-                let rows_remaining = y;
-                dbg!(rows_remaining);
-                let mut color = Color::black();
-
-                for s in 0..samples_per_pixel {
-                    let u: f32 = (x as f32 + random_f32()) / (WIDTH - 1) as f32;
-                    let v: f32 = (y as f32 + random_f32()) / (HEIGHT - 1) as f32;
-    
-                    let uv = Vec3::new(
-                        lower_left_corner[0] + u * u_range,
-                        lower_left_corner[1] + v * v_range,
-                        -1.0
-                    );
-    
-                    let ray = Ray::new(
-                        camera.clone(), 
-                        uv - camera.clone()
-                    );
-                    
-                    color = color + self.ray_trace(ray);
-                    
-                }
+    pub fn set_max_depth(&mut self, max_depth: u32) {
+        self.max_depth = max_depth;
+    }
 
-                //gamma correction
-                let scale = 1.0 / (samples_per_pixel as f32);
-                color = Vec3::new(
-                    f32::sqrt(scale * color[0]),
-                    f32::sqrt(scale * color[1]),
-                    f32::sqrt(scale * color[2])
-                ) * 255.999;
-                println!("{}", color);
-                
-            }   
-        }
+    pub fn set_samples_per_pixel(&mut self, samples_per_pixel: u32) {
+        self.samples_per_pixel = samples_per_pixel;
     }
 
-    // fn ray_march_02(&self, ray: Ray, mut total_dist_traveled: f32) -> Vec3 {
-    //     // println!("total_dist_traveled = {}", total_dist_traveled);
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = Some(seed);
+    }
 
-    //     let nr_samples = 12;
-    //     let max_iterations = 15;
-    //     let max_tot_dist = 20.0;
-    //     let mut local_dist_traveled = 0.0;
+    /// How `render` reports how far along it is, silent by default
+    pub fn set_progress(&mut self, progress: Progress) {
+        self.progress = progress;
+    }
+
+    pub fn hittables(&self) -> &[Hittable] {
+        &self.hittables
+    }
 
-    //     let mut cur_pos = ray.get_origin();
+    pub fn sun(&self) -> Option<&Hittable> {
+        self.sun.as_ref()
+    }
 
-    //     for i in 0..max_iterations {
-    //         let (
-    //             distance_to_closest_hittalbe, 
-    //             material_closest_hittable
-    //         ) = self.distance_to_and_material_of_closest_hittable(&cur_pos);
+    pub fn lights(&self) -> &[Light] {
+        &self.lights
+    }
 
-    //         local_dist_traveled += distance_to_closest_hittalbe;
-    //         total_dist_traveled += distance_to_closest_hittalbe;
-            
+    pub fn environment(&self) -> Option<&Environment> {
+        self.environment.as_ref()
+    }
 
-    //         // println!("dist to closest hittable = {}", distance_to_closest_hittalbe);
-    //         // println!("tot dist traveled = {}", total_dist_traveled);
-    //         // println!();
+    pub fn sky(&self) -> Option<&Sky> {
+        self.sky.as_ref().map(|(sky, _)| sky)
+    }
 
-    //         if total_dist_traveled > max_tot_dist {break;}
+    pub fn max_depth(&self) -> u32 {
+        self.max_depth
+    }
 
-    //         if distance_to_closest_hittalbe < 0.01 {
-    //             let col = match material_closest_hittable {
+    pub fn samples_per_pixel(&self) -> u32 {
+        self.samples_per_pixel
+    }
 
-    //                 Material::Light => Color::white(),
+    pub fn seed(&self) -> Option<u64> {
+        self.seed
+    }
 
-    //                 Material::Lambertian(color) => {
-    //                     let mut col = Color::black();
-    //                     for i in 0..nr_samples {
-    //                         let normal_here = self.get_normal_at_surface_point(&cur_pos);
-    //                         let new_direction = normal_here + random_in_unit_sphere();
-    //                         let new_origin = cur_pos.clone() + normalize(&new_direction) * distance_to_closest_hittalbe;
-    //                         let new_ray = Ray::new(new_origin, new_direction);
+    /// Builds the bounding volume hierarchy that `ray_trace` and the shadow rays traverse.
+    /// Called by `render`, only needs to be called by hand when tracing rays outside of it.
+    pub fn build_bvh(&mut self) {
+        let mut bounded = vec![];
+        self.unbounded.clear();
+        self.emitters.clear();
+        for (i, hittable) in self.hittables.iter().enumerate() {
+            match hittable.bounding_box() {
+                Some(aabb) => {
+                    bounded.push((i, aabb));
+                    if hittable.material.emission().is_some() {
+                        self.emitters.push(i);
+                    }
+                },
+                None => self.unbounded.push(i)
+            }
+        }
+        self.bvh = Some(Bvh::build(&bounded));
+    }
 
-    //                         col = col + self.ray_march(new_ray, total_dist_traveled);
-    //                     }
-    //                     // col = col * 5.0 / nr_samples as f32;
-    //                     return col / 2.0;
+    pub fn render(self, camera: &Camera) -> Framebuffer {
+        self.render_with_stats(camera).0
+    }
 
-    //                     // return color;
-    //                 }
-    //             };
-    //             return col;
-    //         }
-            
+    /// Renders like `render` and also tells how long it took and how many rays were traced
+    pub fn render_with_stats(mut self, camera: &Camera) -> (Framebuffer, RenderStats) {
+        if self.bvh.is_none() {
+            self.build_bvh();
+        }
 
-    //         cur_pos = ray.scale(local_dist_traveled);
-    //     }
+        let width = camera.width;
+        let height = camera.height;
 
-    //     // println!("reached end of ray_march()");
+        let samples_per_pixel = self.samples_per_pixel;
 
-    //     return Color::black();
-    // }
+        // returns the color along with the depth and normal of what is seen through the pixel center
+        let render_pixel = |x: i32, y: i32| -> RenderedPixel {
+            let mut color = Color::black();
 
+            let center_ray = camera.get_ray((x as f32 + 0.5) / width as f32, (y as f32 + 0.5) / height as f32);
+            let (depth, normal) = match self.closest_hit(&center_ray) {
+                Some((i, hit)) => (hit.dist, self.hittables[i].surface(&center_ray, &hit).normal),
+                None => (f32::INFINITY, Color::black())
+            };
 
-    fn reflection(&self, p: &Vec3) -> Vec3 {
-        let dir_to_sun = match &self.sun.shape {
-            Shape::Plane(plane) => {
-                panic!()
-            },
-            Shape::Sphere(sphere) => {
-                normalize(&(
-                    sphere.c.clone() - p.clone()
-                ))
+            for _ in 0..samples_per_pixel {
+                let u: f32 = (x as f32 + random_f32()) / width as f32;
+                let v: f32 = (y as f32 + random_f32()) / height as f32;
 
+                let ray = camera.get_ray(u, v);
+                
+                color = color + self.ray_trace(ray, self.max_depth, None);
+                
             }
+            (color / samples_per_pixel as f32, depth, normal)
         };
 
-        let normal = self.get_normal_at_surface_point(p);
-        
-        let dot_prod = normal.dot(&dir_to_sun);
-        let mut col = Color::white();
+        let reporter = ProgressReporter::new(self.progress, (width * height) as usize);
+        let rays = AtomicU64::new(0);
+
+        // every tile is rendered on its own by whichever thread gets to it first,
+        // collect() keeps the tiles in order
+        let rendered_tiles: Vec<(Tile, Vec<RenderedPixel>)> = tiles(width, height)
+            .into_par_iter()
+            .enumerate()
+            .map(|(i, tile)| {
+                // each tile gets its own seed, so it does not matter which thread renders it
+                if let Some(seed) = self.seed {
+                    seed_rng(seed ^ (i as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15));
+                }
+                // leaves out rays this thread traced before the render started
+                take_ray_count();
+                let mut pixels = Vec::with_capacity((tile.width * tile.height) as usize);
+                for y in tile.y..tile.y + tile.height {
+                    for x in tile.x..tile.x + tile.width {
+                        pixels.push(render_pixel(x, y));
+                    }
+                }
+                rays.fetch_add(take_ray_count(), Ordering::Relaxed);
+                reporter.advance(pixels.len());
+                (tile, pixels)
+            })
+            .collect();
+        reporter.finish();
+        let stats = RenderStats {
+            wall_time: reporter.elapsed(),
+            rays: rays.into_inner(),
+            samples_per_pixel,
+            width,
+            height
+        };
 
-        if dot_prod < 0.0 {
-            return Color::black();
-        } else {
-            col = col * dot_prod;
+        // y goes up in the image while framebuffer rows go down
+        let mut framebuffer = Framebuffer::new(width as usize, height as usize);
+        for (tile, pixels) in rendered_tiles {
+            for (i, (color, depth, normal)) in pixels.into_iter().enumerate() {
+                let x = (tile.x + i as i32 % tile.width) as usize;
+                let y = (height - 1 - (tile.y + i as i32 / tile.width)) as usize;
+                framebuffer.set(x, y, color);
+                framebuffer.set_depth(x, y, depth);
+                framebuffer.set_normal(x, y, normal);
+            }
         }
+        (framebuffer, stats)
+    }
 
-        let ray_to_sun = Ray::new(
-            p.clone(),
-            dir_to_sun.clone()
-        );
-
-        // check if in shadow
-        for i in 0..self.hittables.len() {
-            match self.hittables[i].is_hit(&ray_to_sun) {
-                Ok(_) => {
-                    // this point is blocked from light by another hittable
-                    return Color::black();
-                },
-                Err(_) => () // continue looking for blocking hittables
+    /// Light arriving at `surface` straight from the sun, the lights and the emissive hittables that `bsdf`
+    /// sends off towards `wo`. `hittable` is the one `surface` is on, which does not light itself
+    fn direct_light(&self, surface: &SurfacePoint, bsdf: &Bsdf, frame: &Frame, wo: &Vec3, hittable: usize) -> Vec3 {
+        let p = &surface.point;
+        let mut total = Color::black();
+        if let Some(sun) = &self.sun {
+            total = total + self.area_light(surface, bsdf, frame, wo, sun);
+        }
+        for light in self.lights.iter().chain(self.sky.iter().map(|(_, sun)| sun)) {
+            if let Some(sample) = light.sample(p) {
+                total = total + self.unoccluded(surface, bsdf, frame, wo, &sample);
+            }
+        }
+        if let Some(environment) = &self.environment {
+            if let Some(sample) = environment.sample() {
+                // weighted against bounce rays escaping the scene, like the area lights
+                let weight = power_heuristic(sample.pdf, bsdf.pdf(frame, wo, &sample.direction));
+                let sample = LightSample {
+                    direction: sample.direction,
+                    distance: f32::INFINITY,
+                    irradiance: sample.radiance * (weight / sample.pdf)
+                };
+                total = total + self.unoccluded(surface, bsdf, frame, wo, &sample);
             }
         }
-        // no hittable found between p and light
-        return col;
+        for &i in &self.emitters {
+            // a glowing principled surface would pick points right next to itself
+            if i != hittable {
+                total = total + self.area_light(surface, bsdf, frame, wo, &self.hittables[i]);
+            }
+        }
+        total
+    }
 
+    // light from a random point on an emissive hittable, so parts of it can be hidden which gives soft shadows
+    fn area_light(&self, surface: &SurfacePoint, bsdf: &Bsdf, frame: &Frame, wo: &Vec3, emitter: &Hittable) -> Vec3 {
+        let p = &surface.point;
+        let (emission, sample) = match (emitter.material.emission(), emitter.sample_from(p)) {
+            (Some(emission), Some(sample)) => (emission, sample),
+            _ => return Color::black()
+        };
+        let to_light = sample.point - p.clone();
+        let distance = length(to_light.clone());
+        let direction = to_light / distance;
+        // the bounce ray could have found this light too, see `ray_trace`
+        let weight = power_heuristic(sample.pdf, bsdf.pdf(frame, wo, &direction));
+        let sample = LightSample {
+            direction,
+            // stops short so the shadow ray does not count the light itself as in the way
+            distance: distance * (1.0 - SHADOW_EPSILON),
+            irradiance: emission * (weight / sample.pdf)
+        };
+        self.unoccluded(surface, bsdf, frame, wo, &sample)
+    }
+
+    // the bounce if the light `i` was also sampled where it left from. unbounded lights are never sampled
+    // and surfaces do not sample themselves, only bounce rays find those
+    fn sampled_from(&self, i: usize, bounce: Option<Bounce>) -> Option<Bounce> {
+        bounce.filter(|bounce| bounce.hittable != i && self.emitters.binary_search(&i).is_ok())
     }
 
+    // light from an emissive hittable that `ray` ran into, weighted against having sampled it directly
+    fn emitted_towards(hittable: &Hittable, ray: &Ray, hit: &Hit, bounce: Option<Bounce>) -> Vec3 {
+        let emission = hittable.material.emission().unwrap_or(Color::black());
+        match bounce {
+            // both the light sample and this bounce could have found the light, the power heuristic
+            // trusts whichever was more likely to, so neither small lights nor sharp reflections are noisy
+            Some(bounce) => {
+                let point = ray.scale(hit.dist);
+                let normal = hittable.get_normal(&point, hit);
+                let light_pdf = hittable.pdf_from(&bounce.origin, &point, &normal);
+                emission * power_heuristic(bounce.pdf, light_pdf)
+            },
+            None => emission
+        }
+    }
 
-    fn ray_trace(&self, ray: Ray) -> Vec3 {
-        let max_distance = 30.0;
+    // light from the sample that the bsdf sends towards `wo`, or nothing if something is in the way
+    fn unoccluded(&self, surface: &SurfacePoint, bsdf: &Bsdf, frame: &Frame, wo: &Vec3, sample: &LightSample) -> Vec3 {
+        let scattered = bsdf.eval(frame, wo, &sample.direction);
+        if scattered[0] <= 0.0 && scattered[1] <= 0.0 && scattered[2] <= 0.0 {
+            return Color::black();
+        }
+        let origin = match leaving_point(surface, &sample.direction) {
+            Some(origin) => origin,
+            None => return Color::black()
+        };
+        let shadow_ray = Ray::new(origin, sample.direction.clone());
+        if self.is_occluded(&shadow_ray, sample.distance) {
+            return Color::black();
+        }
+        sample.irradiance.clone() * scattered
+    }
 
+    /// Estimates the light coming back along `ray` by following one random path through the scene.
+    /// `depth` is how many more bounces the path may take. `bounce` is set when `ray` left a surface
+    /// that also sampled the area lights directly, hitting one of them is then weighted against that.
+    fn ray_trace(&self, ray: Ray, depth: u32, bounce: Option<Bounce>) -> Vec3 {
         if self.hittables.is_empty() {
             panic!("no hittables in world!");
         }
 
-        let mut shortest_distance: Option<f32> = None;
-
-        let mut index_closest_hittalbe = 0;
-
-        for i in 0..self.hittables.len() {
-            match self.hittables[i].is_hit(&ray) {
-                Ok(new_distance) => {
-                    match shortest_distance {
-                        Some(closest_so_far) => {
-                            if new_distance < closest_so_far {
-                                shortest_distance = Some(new_distance);
-                                index_closest_hittalbe = i;
-                            }
-                        },
-                        None => {
-                            shortest_distance = Some(new_distance);
-                            index_closest_hittalbe = i;
-
-                        }
-                    }
-                },
-                Err(_) => ()
-            }
+        // bounced around too many times, no more light is gathered
+        if depth == 0 {
+            return Color::black();
         }
 
-        match shortest_distance {
-            Some(d) => {
-                if d > max_distance {
-                    return Color::black();
+        let closest = self.closest_hit(&ray);
+        // the sun is not one of the hittables, everything but the camera can run into it
+        if let (Some(sun), true) = (&self.sun, depth < self.max_depth) {
+            if let Some(sun_hit) = sun.is_hit(&ray) {
+                if closest.as_ref().is_none_or(|(_, hit)| sun_hit.dist < hit.dist) {
+                    return World::emitted_towards(sun, &ray, &sun_hit, bounce);
+                }
+            }
+        }
+        let (index_closest_hittable, hit) = match closest {
+            Some(closest) => closest,
+            None => return self.escaped(&ray, bounce)
+        };
+        let d = hit.dist;
+        let hittable = &self.hittables[index_closest_hittable];
+        let mut surface = hittable.surface(&ray, &hit);
+        // normal and bump maps only tilt the shading normal, the material underneath does the rest
+        if let Material::Bumped { bump, .. } = &hittable.material {
+            bump.apply(&mut surface);
+        }
+        let material = hittable.material.base();
+        // where rays bouncing back out of the surface start
+        let above_surface = surface.point.clone() + surface.geometric_normal.clone() * SURFACE_OFFSET;
+        // towards where the light goes
+        let wo = ray.get_direction() * -1.0;
+
+        // light the path picks up at this hit, how much of what comes from further along gets through,
+        // and the density the bounce was picked with if the lights were also sampled here
+        let (emitted, attenuation, next_ray, bounce_pdf) = match (material, material.bsdf(&surface)) {
+            (_, Some(bsdf)) => {
+                // the sun and lights can not be hit by chance and area lights rarely are, so they are sampled directly
+                let frame = Frame::new(&surface);
+                let direct = self.direct_light(&surface, &bsdf, &frame, &wo, index_closest_hittable);
+                // principled materials can also glow. a constant glow is one of the area lights and weighted
+                // like them, a textured one is only picked up by running into it
+                let emitted = match material {
+                    Material::Principled(_) if hittable.material.emission().is_some() => {
+                        direct + World::emitted_towards(hittable, &ray, &hit, self.sampled_from(index_closest_hittable, bounce))
+                    },
+                    Material::Principled(principled) => direct + principled.emission.value(&surface),
+                    _ => direct
+                };
+                let sample = bsdf.sample(&frame, &wo);
+                match sample.and_then(|sample| Some((leaving_point(&surface, &sample.direction)?, sample))) {
+                    Some((origin, sample)) => (emitted, sample.weight, Some(Ray::new(origin, sample.direction)), Some(sample.pdf)),
+                    None => (emitted, Color::black(), None, None)
+                }
+            },
+            (Material::Emissive(_), None) => {
+                let bounce = self.sampled_from(index_closest_hittable, bounce);
+                (World::emitted_towards(hittable, &ray, &hit, bounce), Color::black(), None, None)
+            },
+            (Material::Metal { albedo, fuzz }, None) => {
+                let reflected = reflect(&ray.get_direction(), &surface.normal)
+                    + random_in_unit_sphere() * *fuzz;
+                // fuzz can push the reflection below the surface, it is absorbed then
+                if reflected.dot(&surface.normal) <= 0.0 || reflected.dot(&surface.geometric_normal) <= 0.0 {
+                    (Color::black(), Color::black(), None, None)
+                } else {
+                    (Color::black(), albedo.value(&surface), Some(Ray::new(above_surface, reflected)), None)
+                }
+            },
+            (Material::Conductor { eta, k, .. }, None) => {
+                // polished, a perfect mirror tinted by how much the metal reflects at this angle
+                let reflected = reflect(&ray.get_direction(), &surface.normal);
+                if reflected.dot(&surface.geometric_normal) <= 0.0 {
+                    (Color::black(), Color::black(), None, None)
                 } else {
-                    let hit_point_col = match &self.hittables[index_closest_hittalbe].material {
-                        Material::Light => panic!(),
-                        Material::Lambertian(col) => col.clone()
-                    };
-                    let hit_point = ray.scale(d*0.99);
-                    let col = self.reflection(&hit_point) * hit_point_col;
-                    return col * (1.0 - d / max_distance);
+                    let cos_theta = f32::max(0.0, wo.dot(&surface.normal));
+                    (Color::black(), fresnel_conductor(cos_theta, eta, k), Some(Ray::new(above_surface, reflected)), None)
                 }
             },
-            None => return Color::black(),
+            (Material::Dielectric { ior, .. }, None) => {
+                let eta_ratio = if surface.front_face { 1.0 / ior } else { *ior };
+                let dir = ray.get_direction();
+                let cos_theta = f32::min(-dir.dot(&surface.normal), 1.0);
+                let sin_theta = f32::sqrt(1.0 - cos_theta * cos_theta);
+
+                let cannot_refract = eta_ratio * sin_theta > 1.0;
+                let (direction, offset) = if cannot_refract || reflectance(cos_theta, eta_ratio) > random_f32() {
+                    (reflect(&dir, &surface.normal), SURFACE_OFFSET)
+                } else {
+                    // the refracted ray continues on the other side of the surface
+                    (refract(&dir, &surface.normal, eta_ratio), -SURFACE_OFFSET)
+                };
+                let origin = surface.point.clone() + surface.geometric_normal.clone() * offset;
+                (Color::black(), Color::white(), Some(Ray::new(origin, direction)), None)
+            },
+            (Material::Lambertian(_) | Material::Principled(_) | Material::Bumped { .. }, None) => {
+                unreachable!("lambertian and principled surfaces always have a bsdf")
+            }
+        };
+
+        let attenuation = match material {
+            // hitting the back face of glass means the ray travelled d inside it
+            Material::Dielectric { absorption: Some(absorption), .. } if !surface.front_face => attenuation * Vec3::new(
+                f32::exp(-absorption[0] * d),
+                f32::exp(-absorption[1] * d),
+                f32::exp(-absorption[2] * d)
+            ),
+            _ => attenuation
+        };
+
+        let next_ray = match next_ray {
+            Some(next_ray) => next_ray,
+            None => return emitted
+        };
+
+        // russian roulette, after a few bounces paths that carry little light are ended early.
+        // the ones that survive are made brighter by as much so the average stays the same
+        let mut attenuation = attenuation;
+        let bounces = self.max_depth - depth;
+        if bounces >= MIN_BOUNCES {
+            let survival = f32::clamp(f32::max(attenuation[0], f32::max(attenuation[1], attenuation[2])), 0.05, 1.0);
+            if random_f32() >= survival {
+                return emitted;
+            }
+            attenuation = attenuation / survival;
         }
 
+        // after perfect mirrors and glass, which do not sample the lights, whatever is hit counts fully
+        let bounce = bounce_pdf.map(|pdf| Bounce { origin: next_ray.get_origin(), pdf, hittable: index_closest_hittable });
+        emitted + self.ray_trace(next_ray, depth - 1, bounce) * attenuation
+    }
+
+    // light from the environment for a ray that left the scene
+    fn escaped(&self, ray: &Ray, bounce: Option<Bounce>) -> Vec3 {
+        let environment = match &self.environment {
+            Some(environment) => environment,
+            None => return Color::black()
+        };
+        let direction = ray.get_direction();
+        let radiance = environment.radiance(&direction);
+        match bounce {
+            Some(bounce) => radiance * power_heuristic(bounce.pdf, environment.pdf(&direction)),
+            None => radiance
+        }
     }
 
-    pub fn get_normal_at_surface_point(&self, p: &Vec3) -> Vec3 {
-        let index_to_closest_hittalbe = self.index_of_closest_hittable(p);
-        let closest_hittable = &self.hittables[index_to_closest_hittalbe];
-        return closest_hittable.get_normal(p);
+    /// How far along the ray the first hittable is, if any
+    pub fn first_hit_distance(&self, ray: &Ray) -> Option<f32> {
+        self.closest_hit(ray).map(|(_, hit)| hit.dist)
     }
 
-    // pub fn get_normal_at_surface_point_02(&self, p: &Vec3) -> Vec3 {
+    /// Index of and hit on the first hittable along the ray.
+    /// Goes through the bvh if it has been built and tests every hittable otherwise.
+    fn closest_hit(&self, ray: &Ray) -> Option<(usize, Hit)> {
+        count_ray();
+        let bvh = match &self.bvh {
+            Some(bvh) => bvh,
+            None => return self.closest_hit_linear(ray)
+        };
 
-    //     let p = p.clone();
+        let mut closest = bvh.closest(ray, f32::INFINITY, |i, t_max| {
+            self.hittables[i].is_hit(ray).filter(|hit| hit.dist < t_max)
+        });
+        for &i in &self.unbounded {
+            if let Some(hit) = self.hittables[i].is_hit(ray) {
+                if closest.as_ref().is_none_or(|(_, closest)| hit.dist < closest.dist) {
+                    closest = Some((i, hit));
+                }
+            }
+        }
+        closest
+    }
 
-    //     let small_step_x: Vec3 = Vec3::new(0.001, 0.0, 0.0);
-    //     let small_step_y: Vec3 = Vec3::new(0.0, 0.001, 0.0);
-    //     let small_step_z: Vec3 = Vec3::new(0.0, 0.0, 0.001);
+    fn closest_hit_linear(&self, ray: &Ray) -> Option<(usize, Hit)> {
+        let mut closest: Option<(usize, Hit)> = None;
+        for (i, hittable) in self.hittables.iter().enumerate() {
+            if let Some(hit) = hittable.is_hit(ray) {
+                if closest.as_ref().is_none_or(|(_, closest)| hit.dist < closest.dist) {
+                    closest = Some((i, hit));
+                }
+            }
+        }
+        closest
+    }
 
-    //     let gradient_x: f32 = self.distance_to_closest_hittable(&(p.clone() + small_step_x.clone())) - self.distance_to_closest_hittable(&(p.clone()  - small_step_x));
-    //     let gradient_y: f32 = self.distance_to_closest_hittable(&(p.clone()  + small_step_y.clone())) - self.distance_to_closest_hittable(&(p.clone()  - small_step_y));
-    //     let gradient_z: f32 = self.distance_to_closest_hittable(&(p.clone()  + small_step_z.clone())) - self.distance_to_closest_hittable(&(p - small_step_z));
-    //     let normal = Vec3::new(gradient_x, gradient_y, gradient_z);
-    //     return normalize(&normal);
-    // }
+    /// True if any hittable is hit before `max_distance`
+    fn is_occluded(&self, ray: &Ray, max_distance: f32) -> bool {
+        count_ray();
+        let blocks = |i: usize, t_max: f32| {
+            matches!(self.hittables[i].is_hit(ray), Some(hit) if hit.dist < t_max)
+        };
+        match &self.bvh {
+            Some(bvh) => {
+                bvh.any(ray, max_distance, blocks)
+                    || self.unbounded.iter().any(|&i| blocks(i, max_distance))
+            },
+            None => (0..self.hittables.len()).any(|i| blocks(i, max_distance))
+        }
+    }
+}
 
-    fn index_of_closest_hittable(&self, p: &Vec3) -> usize {
-        let mut index_closest: usize = 0;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::texture::Texture;
+    use crate::vec3::random_cosine_direction;
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    #[test]
+    fn bvh_finds_same_closest_hit_as_brute_force() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut world = World::new();
+
+        for _ in 0..2000 {
+            let c = Vec3::new(
+                rng.gen_range(-20.0..20.0),
+                rng.gen_range(-20.0..20.0),
+                rng.gen_range(-20.0..20.0)
+            );
+            world.add(Shape::sphere(c, rng.gen_range(0.05..0.8)), Material::lambertian(Color::white()));
+        }
+        world.add(Shape::plane(0.0, 1.0, 0.0, -25.0), Material::lambertian(Color::white()));
+        world.build_bvh();
+
+        let mut hits = 0;
+        for _ in 0..5000 {
+            let origin = Vec3::new(
+                rng.gen_range(-30.0..30.0),
+                rng.gen_range(-30.0..30.0),
+                rng.gen_range(-30.0..30.0)
+            );
+            let direction = Vec3::new(
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0),
+                rng.gen_range(-1.0..1.0)
+            );
+            let ray = Ray::new(origin, direction);
+
+            let expected = world.closest_hit_linear(&ray);
+            assert_eq!(world.closest_hit(&ray), expected);
+            if expected.is_some() {
+                hits += 1;
+            }
 
-        if self.hittables.is_empty() {panic!("No hittables in world!")}
-        else if self.hittables.len() == 1 {return index_closest;}
-        else {
-            for i in 1..self.hittables.len() {
-                if self.hittables[i].shortest_dist(p) < self.hittables[index_closest].shortest_dist(p) {
-                    index_closest = i;
+            let t_max = rng.gen_range(0.0..40.0);
+            let brute_force_occluded = (0..world.hittables.len())
+                .any(|i| matches!(world.hittables[i].is_hit(&ray), Some(hit) if hit.dist < t_max));
+            assert_eq!(world.is_occluded(&ray, t_max), brute_force_occluded);
+        }
+        // make sure the rays actually hit something
+        assert!(hits > 1000);
+    }
+
+    // light a bounce ray runs into, from the closest glowing hittable or the sun in front of it
+    fn emitted_along(world: &World, ray: &Ray) -> Vec3 {
+        let closest = world.closest_hit(ray);
+        if let Some(sun) = world.sun() {
+            if let Some(hit) = sun.is_hit(ray) {
+                if closest.as_ref().is_none_or(|(_, closest)| hit.dist < closest.dist) {
+                    return sun.material.emission().unwrap();
                 }
             }
         }
-        return index_closest;
-    }
-
-    // fn distance_to_and_material_of_closest_hittable(&self, p: &Vec3) -> (f32, Material) {
-    //     let mut index_closest: usize = 0;
-
-    //     if self.hittables.is_empty() {panic!("No hittables in world!")}
-    //     else if self.hittables.len() == 1 {
-    //         return (
-    //             self.hittables[0].shortest_dist(p),
-    //             self.hittables[0].material.clone()
-    //         );
-    //     }
-    //     else {
-    //         for i in 1..self.hittables.len() {
-    //             if self.hittables[i].shortest_dist(p) < self.hittables[index_closest].shortest_dist(p) {
-    //                 index_closest = i;
-    //             }
-    //         }
-    //     }
-    //     let distance_to_closest_hittable = self.hittables[index_closest].shortest_dist(p);
-    //     let material_of_closest_hittalbe = self.hittables[index_closest].material.clone();
-    //     return (
-    //         distance_to_closest_hittable,
-    //         material_of_closest_hittalbe
-    //     )
-    // }
-
-    // fn distance_to_closest_hittable(&self, p: &Vec3) -> f32 {
-    //     return self.distance_to_and_material_of_closest_hittable(p).0;
-    // }
-
-
-}
\ No newline at end of file
+        match closest {
+            Some((i, hit)) => match &world.hittables[i].material {
+                Material::Emissive(color) => color.clone(),
+                Material::Principled(principled) => principled.emission.value(&world.hittables[i].surface(ray, &hit)),
+                _ => Color::black()
+            },
+            None => Color::black()
+        }
+    }
+
+    /// Light sampling with MIS has to see as much light as plain cosine bounces from the floor
+    /// at y = 0, which has to have a lambertian `albedo`. The world needs a max depth of 2
+    fn assert_mis_matches_brute_force(world: &World, albedo: &Vec3, xs: &[f32]) {
+        const SAMPLES: usize = 200_000;
+        for &x in xs {
+            let p = Vec3::new(x, 0.0, 0.5);
+            let camera_ray = Ray::new(p.clone() + Vec3::new(0.0, 0.3, 0.0), Vec3::new(0.0, -1.0, 0.0));
+
+            let mut mis = Color::black();
+            for _ in 0..SAMPLES {
+                mis = mis + world.ray_trace(camera_ray.clone(), 2, None);
+            }
+            let mis = mis / SAMPLES as f32;
+
+            // only bounce rays, whatever light they happen to hit
+            let normal = Vec3::new(0.0, 1.0, 0.0);
+            let mut brute_force = Color::black();
+            for _ in 0..SAMPLES {
+                let ray = Ray::new(p.clone() + normal.clone() * SURFACE_OFFSET, random_cosine_direction(&normal));
+                brute_force = brute_force + emitted_along(world, &ray) * albedo.clone();
+            }
+            let brute_force = brute_force / SAMPLES as f32;
+
+            for c in 0..3 {
+                let error = f32::abs(mis[c] - brute_force[c]) / brute_force[c];
+                assert!(error < 0.03, "at x = {}: mis {:?} but brute force {:?}", x, mis, brute_force);
+            }
+        }
+    }
+
+    #[test]
+    fn mis_matches_brute_force() {
+        seed_rng(11);
+        let albedo = Vec3::new(0.8, 0.6, 0.4);
+        let mut world = World::new();
+        world.remove_sun();
+        // one bounce off the floor into the lights and no russian roulette
+        world.set_max_depth(2);
+        world.add(Shape::plane(0.0, 1.0, 0.0, 0.0), Material::lambertian(albedo.clone()));
+        // a small bright light, which light sampling is good at
+        world.add(Shape::sphere(Vec3::new(1.0, 1.0, 0.0), 0.1), Material::Emissive(Vec3::new(50.0, 50.0, 50.0)));
+        // and a big dim one close to the floor, which the bounce rays find easily
+        world.add(
+            Shape::quad(Vec3::new(-3.0, 0.5, -2.0), Vec3::new(2.0, 0.0, 0.0), Vec3::new(0.0, 0.0, 4.0)),
+            Material::Emissive(Vec3::new(1.0, 2.0, 3.0))
+        );
+        world.build_bvh();
+        assert_mis_matches_brute_force(&world, &albedo, &[-0.5, 0.3, 1.5]);
+    }
+
+    #[test]
+    fn sun_is_an_area_light() {
+        seed_rng(12);
+        let albedo = Vec3::new(0.5, 0.5, 0.5);
+        let mut world = World::new();
+        world.set_sun(Shape::sphere(Vec3::new(2.0, 3.0, 0.0), 1.5), Vec3::new(4.0, 3.0, 2.0)).unwrap();
+        // a plane can not be a sun, and the old sun stays
+        assert!(world.set_sun(Shape::plane(0.0, 1.0, 0.0, 10.0), Vec3::new(1.0, 1.0, 1.0)).is_err());
+        world.set_max_depth(2);
+        world.add(Shape::plane(0.0, 1.0, 0.0, 0.0), Material::lambertian(albedo.clone()));
+        // hides part of the sun from some of the points
+        world.add(Shape::sphere(Vec3::new(1.0, 1.0, 0.0), 0.4), Material::lambertian(Color::black()));
+        world.build_bvh();
+        assert!(world.sun().is_some());
+
+        // the camera does not see the sun
+        let camera_ray = Ray::new(Vec3::new(2.0, 0.5, 0.0), Vec3::new(0.0, 1.0, 0.0));
+        assert_eq!(world.ray_trace(camera_ray, 2, None), Color::black());
+
+        assert_mis_matches_brute_force(&world, &albedo, &[-1.0, 0.5, 2.0]);
+    }
+
+    #[test]
+    fn glowing_principled_materials_are_lights() {
+        seed_rng(13);
+        let albedo = Vec3::new(0.5, 0.5, 0.5);
+        let mut world = World::new();
+        world.remove_sun();
+        world.set_max_depth(2);
+        world.add(Shape::plane(0.0, 1.0, 0.0, 0.0), Material::lambertian(albedo.clone()));
+        let mut glowing = Principled::new(Color::black());
+        glowing.emission = Vec3::new(6.0, 4.0, 2.0).into();
+        world.add(Shape::sphere(Vec3::new(0.5, 1.0, 0.0), 0.3), Material::principled(glowing));
+        // a textured glow is not sampled, only found
+        let mut textured = Principled::new(Color::black());
+        textured.emission = Texture::checker_2d(Texture::from(1.0), Texture::from(0.0), 4.0);
+        world.add(Shape::sphere(Vec3::new(-2.0, 1.0, 0.0), 0.3), Material::principled(textured));
+        world.build_bvh();
+        assert_eq!(world.emitters, vec![1]);
+
+        assert_mis_matches_brute_force(&world, &albedo, &[0.0, 1.5]);
+    }
+}